@@ -0,0 +1,51 @@
+/* C interface to kvs, built with `cargo build -p kvs-ffi`. Mirrors ffi/src/lib.rs. */
+
+#ifndef KVS_H
+#define KVS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The call succeeded */
+#define KVS_OK 0
+/* The key is not in the store */
+#define KVS_NOT_FOUND 1
+/* The call failed; kvs_last_error says why */
+#define KVS_ERROR -1
+
+typedef struct KvStore KvStore;
+
+/* Opens the store at a path, returning NULL on failure. Close it with kvs_close. */
+KvStore *kvs_open(const char *path);
+
+/* Closes a store opened with kvs_open, syncing its log; NULL is ignored */
+void kvs_close(KvStore *store);
+
+/* Reads the value of a key. On KVS_OK, *value and *value_len hold a copy of the value that must
+ * be released with kvs_free_value. On KVS_NOT_FOUND they are set to NULL and zero. */
+int kvs_get(KvStore *store, const uint8_t *key, size_t key_len, uint8_t **value,
+            size_t *value_len);
+
+/* Releases a value returned by kvs_get; NULL is ignored */
+void kvs_free_value(uint8_t *value, size_t value_len);
+
+/* Sets the value of a key */
+int kvs_set(KvStore *store, const uint8_t *key, size_t key_len, const uint8_t *value,
+            size_t value_len);
+
+/* Removes a key, returning KVS_NOT_FOUND if it is not in the store */
+int kvs_remove(KvStore *store, const uint8_t *key, size_t key_len);
+
+/* Returns the message of the last error on this thread, or NULL if there was none. The message
+ * stays valid until the next failing call on the same thread. */
+const char *kvs_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KVS_H */
@@ -0,0 +1,152 @@
+//! C interface to open, read, write and close a store
+//!
+//! This crate builds the `kvs_ffi` shared library. The functions are declared for C in
+//! `include/kvs.h`. Keys and values are byte strings given as a pointer and a length, so they
+//! need not be valid UTF-8 or NUL-terminated. Functions that can fail return `KVS_ERROR` and
+//! leave a message to be read with `kvs_last_error`.
+#![deny(missing_docs)]
+#![allow(clippy::missing_safety_doc)]
+
+use kvs::{KvError, KvStore};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+/// The call succeeded
+pub const KVS_OK: c_int = 0;
+/// The key is not in the store
+pub const KVS_NOT_FOUND: c_int = 1;
+/// The call failed; `kvs_last_error` says why
+pub const KVS_ERROR: c_int = -1;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn fail(err: KvError) -> c_int {
+    set_last_error(err.to_string());
+    KVS_ERROR
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        &[]
+    } else {
+        slice::from_raw_parts(data, len)
+    }
+}
+
+/// Opens the store at a NUL-terminated path, returning `NULL` on failure
+///
+/// The store must be closed with `kvs_close`.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_open(path: *const c_char) -> *mut KvStore {
+    if path.is_null() {
+        set_last_error("path is NULL".to_owned());
+        return ptr::null_mut();
+    }
+    let path = match CStr::from_ptr(path).to_str() {
+        Ok(path) => path,
+        Err(err) => {
+            set_last_error(format!("path is not valid UTF-8: {}", err));
+            return ptr::null_mut();
+        }
+    };
+    match KvStore::open(Path::new(path)) {
+        Ok(store) => Box::into_raw(Box::new(store)),
+        Err(err) => {
+            fail(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Closes a store opened with `kvs_open`, syncing its log; `NULL` is ignored
+#[no_mangle]
+pub unsafe extern "C" fn kvs_close(store: *mut KvStore) {
+    if !store.is_null() {
+        drop(Box::from_raw(store));
+    }
+}
+
+/// Reads the value of a key
+///
+/// On `KVS_OK`, `*value` and `*value_len` hold a copy of the value that must be released with
+/// `kvs_free_value`. On `KVS_NOT_FOUND` they are set to `NULL` and zero.
+#[no_mangle]
+pub unsafe extern "C" fn kvs_get(
+    store: *mut KvStore,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *mut u8,
+    value_len: *mut usize,
+) -> c_int {
+    *value = ptr::null_mut();
+    *value_len = 0;
+    match (*store).get_bytes(bytes(key, key_len)) {
+        Ok(Some(found)) => {
+            let found = found.into_boxed_slice();
+            *value_len = found.len();
+            *value = Box::into_raw(found) as *mut u8;
+            KVS_OK
+        }
+        Ok(None) => KVS_NOT_FOUND,
+        Err(err) => fail(err),
+    }
+}
+
+/// Releases a value returned by `kvs_get`; `NULL` is ignored
+#[no_mangle]
+pub unsafe extern "C" fn kvs_free_value(value: *mut u8, value_len: usize) {
+    if !value.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            value, value_len,
+        )));
+    }
+}
+
+/// Sets the value of a key
+#[no_mangle]
+pub unsafe extern "C" fn kvs_set(
+    store: *mut KvStore,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> c_int {
+    let key = bytes(key, key_len).to_vec();
+    let value = bytes(value, value_len).to_vec();
+    match (*store).set_bytes(key, value) {
+        Ok(()) => KVS_OK,
+        Err(err) => fail(err),
+    }
+}
+
+/// Removes a key, returning `KVS_NOT_FOUND` if it is not in the store
+#[no_mangle]
+pub unsafe extern "C" fn kvs_remove(store: *mut KvStore, key: *const u8, key_len: usize) -> c_int {
+    match (*store).remove_bytes(bytes(key, key_len)) {
+        Ok(()) => KVS_OK,
+        Err(KvError::KeyNotFound) => KVS_NOT_FOUND,
+        Err(err) => fail(err),
+    }
+}
+
+/// Returns the message of the last error on this thread, or `NULL` if there was none
+///
+/// The message stays valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn kvs_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
@@ -0,0 +1,40 @@
+use kvs_ffi::*;
+use std::ffi::{CStr, CString};
+use std::ptr;
+use tempfile::TempDir;
+
+// The C interface should set, get, remove and close a store, and report errors
+#[test]
+fn ffi_roundtrip() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+    unsafe {
+        let store = kvs_open(path.as_ptr());
+        assert!(!store.is_null());
+        assert_eq!(
+            kvs_set(store, b"key".as_ptr(), 3, b"value".as_ptr(), 5),
+            KVS_OK
+        );
+
+        let mut value = ptr::null_mut();
+        let mut value_len = 0;
+        assert_eq!(
+            kvs_get(store, b"key".as_ptr(), 3, &mut value, &mut value_len),
+            KVS_OK
+        );
+        assert_eq!(std::slice::from_raw_parts(value, value_len), b"value");
+        kvs_free_value(value, value_len);
+
+        assert_eq!(kvs_remove(store, b"key".as_ptr(), 3), KVS_OK);
+        assert_eq!(kvs_remove(store, b"key".as_ptr(), 3), KVS_NOT_FOUND);
+        assert_eq!(
+            kvs_get(store, b"key".as_ptr(), 3, &mut value, &mut value_len),
+            KVS_NOT_FOUND
+        );
+        assert!(value.is_null());
+        kvs_close(store);
+
+        assert!(kvs_open(ptr::null()).is_null());
+        assert!(!CStr::from_ptr(kvs_last_error()).to_bytes().is_empty());
+    }
+}
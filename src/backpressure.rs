@@ -0,0 +1,49 @@
+use crate::clock::sleep;
+use crate::{KvError, KvStore, Result};
+use std::time::Duration;
+
+/// Longest a write is delayed for before stale bytes reach the hard limit
+const MAX_STALL: Duration = Duration::from_millis(100);
+
+impl KvStore {
+    /// Delays or refuses a write while the log holds more stale bytes than the write stall
+    /// limits allow
+    ///
+    /// Between the soft and the hard limit writes are delayed by up to `MAX_STALL`, growing with
+    /// the stale bytes. At the hard limit the log is compacted if anything was written since the
+    /// last compaction, and the write is refused if that does not bring it back under.
+    pub(crate) fn stall(&mut self) -> Result<()> {
+        let (soft, hard) = match self.options.write_stall {
+            Some(limits) => limits,
+            None => return Ok(()),
+        };
+        // Stale bytes grow by at most the bytes appended since they were last counted, which
+        // saves walking the index on every write while well under the soft limit
+        let size = self.own_size()?;
+        let (counted_at, counted) = self.stale_bytes;
+        if counted + size.saturating_sub(counted_at) < soft {
+            return Ok(());
+        }
+        let mut stale = self.count_stale_bytes()?;
+        if stale >= hard && self.compaction_counter > 0 {
+            self.compact()?;
+            stale = self.count_stale_bytes()?;
+        }
+        if stale >= hard {
+            return Err(KvError::CompactionBackpressure);
+        }
+        if stale > soft {
+            let delay = MAX_STALL.mul_f64((stale - soft) as f64 / (hard - soft) as f64);
+            self.stalls += 1;
+            sleep(delay);
+        }
+        Ok(())
+    }
+
+    fn count_stale_bytes(&mut self) -> Result<u64> {
+        let size = self.own_size()?;
+        let stale = size.saturating_sub(self.live_size());
+        self.stale_bytes = (size, stale);
+        Ok(stale)
+    }
+}
@@ -0,0 +1,390 @@
+use crate::log::Log;
+use crate::{fnv1a, KvError, KvStore, LogEntry, Result, FNV_OFFSET};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+
+/// Name of the log file inside a backup directory
+const BACKUP_LOG: &str = "data.log";
+
+/// Name of the value log inside a backup directory
+const BACKUP_VALUES: &str = "data.vlog";
+
+/// Name of the manifest describing what a backup directory holds
+const BACKUP_MANIFEST: &str = "MANIFEST";
+
+/// Number of bytes at each end of the backed up log that are compared to detect compaction
+const FINGERPRINT_LEN: u64 = 64;
+
+/// Records how much of the log a backup holds, so later backups can copy only the rest, and
+/// the checksum of every file in the backup
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct BackupManifest {
+    len: u64,
+    head: Vec<u8>,
+    tail: Vec<u8>,
+    #[serde(default)]
+    files: BTreeMap<String, Checksum>,
+}
+
+/// Length and FNV-1a hash of a file, taken from the bytes copied into it
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+struct Checksum {
+    len: u64,
+    hash: u64,
+}
+
+impl Checksum {
+    const EMPTY: Checksum = Checksum {
+        len: 0,
+        hash: FNV_OFFSET,
+    };
+
+    fn of_file(path: &Path) -> Result<Checksum> {
+        let mut writer = ChecksumWriter::new(io::sink(), Checksum::EMPTY);
+        io::copy(&mut File::open(path)?, &mut writer)?;
+        Ok(writer.checksum)
+    }
+}
+
+/// Writer that keeps the checksum of everything written through it
+struct ChecksumWriter<W> {
+    inner: W,
+    checksum: Checksum,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    /// Continues from the checksum of what the destination already holds
+    fn new(inner: W, checksum: Checksum) -> ChecksumWriter<W> {
+        ChecksumWriter { inner, checksum }
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.checksum.len += written as u64;
+        self.checksum.hash = fnv1a(self.checksum.hash, &buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl BackupManifest {
+    fn new<R: Read + Seek>(
+        log: &mut R,
+        len: u64,
+        files: BTreeMap<String, Checksum>,
+    ) -> Result<BackupManifest> {
+        Ok(BackupManifest {
+            len,
+            head: read_at(log, 0, len.min(FINGERPRINT_LEN))?,
+            tail: read_at(
+                log,
+                len.saturating_sub(FINGERPRINT_LEN),
+                len.min(FINGERPRINT_LEN),
+            )?,
+            files,
+        })
+    }
+
+    /// Returns whether a later backup of the same log could start from this one
+    fn same_log(&self, other: &BackupManifest) -> bool {
+        (self.len, &self.head, &self.tail) == (other.len, &other.head, &other.tail)
+    }
+
+    fn read(dir: &Path) -> Result<Option<BackupManifest>> {
+        match fs::read(dir.join(BACKUP_MANIFEST)) {
+            Ok(buf) => Ok(serde_json::from_slice(&buf).ok()),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(BACKUP_MANIFEST).with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp_path, dir.join(BACKUP_MANIFEST))?;
+        Ok(())
+    }
+}
+
+/// A point in the history of a store
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestorePoint {
+    /// Just after the write with this sequence number
+    Seq(u64),
+    /// Just after the last write at or before this time, in milliseconds since the Unix epoch
+    Time(u64),
+}
+
+impl RestorePoint {
+    /// Returns whether a log record was written at or before this point
+    pub(crate) fn includes(self, entry: &LogEntry) -> bool {
+        let (seq, time) = match *entry {
+            LogEntry::Set { seq, time, .. }
+            | LogEntry::Remove { seq, time, .. }
+            | LogEntry::Commit { seq, time, .. }
+            | LogEntry::RemovePrefix { seq, time, .. }
+            | LogEntry::Merge { seq, time, .. }
+            | LogEntry::Append { seq, time, .. }
+            | LogEntry::Rename { seq, time, .. } => (seq, time),
+            _ => return true,
+        };
+        match self {
+            RestorePoint::Seq(until) => seq <= until,
+            RestorePoint::Time(until) => time <= until,
+        }
+    }
+}
+
+fn read_at<R: Read + Seek>(file: &mut R, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(len as usize);
+    file.seek(SeekFrom::Start(offset))?;
+    file.take(len).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Copies the first `len` bytes of a log to `path`, replacing any existing file atomically,
+/// and returns the checksum of the copy
+fn copy_log(log: &Log, len: u64, path: &Path) -> Result<Checksum> {
+    let mut src = log.reader();
+    src.seek(SeekFrom::Start(0))?;
+    let tmp_path = path.with_extension("tmp");
+    let checksum = {
+        let mut tmp = ChecksumWriter::new(File::create(&tmp_path)?, Checksum::EMPTY);
+        io::copy(&mut src.take(len), &mut tmp)?;
+        tmp.inner.sync_all()?;
+        tmp.checksum
+    };
+    fs::rename(&tmp_path, path)?;
+    Ok(checksum)
+}
+
+/// Appends the bytes of a log between two offsets to the file at `path`, whose contents had
+/// the checksum `previous`, and returns the checksum of the whole file
+fn append_log(log: &Log, from: u64, to: u64, path: &Path, previous: Checksum) -> Result<Checksum> {
+    let mut src = log.reader();
+    src.seek(SeekFrom::Start(from))?;
+    let dest = OpenOptions::new().append(true).create(true).open(path)?;
+    let mut dest = ChecksumWriter::new(dest, previous);
+    io::copy(&mut src.take(to - from), &mut dest)?;
+    dest.inner.sync_all()?;
+    Ok(dest.checksum)
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+impl KvStore {
+    /// Writes a consistent copy of the log to `dest`, which can be opened as a store directly
+    ///
+    /// Records are only ever appended, so copying the log up to its current length captures
+    /// every completed write even while the store stays open. Buckets are backed up separately.
+    pub fn backup(&self, dest: &Path) -> Result<()> {
+        fs::create_dir_all(dest)?;
+        let len = self.log.len()?;
+        let mut files = BTreeMap::new();
+        // The value log is copied first so the backed up log never refers to missing values
+        match &self.values {
+            Some(values) => {
+                let checksum = copy_log(values, values.len()?, &dest.join(BACKUP_VALUES))?;
+                files.insert(BACKUP_VALUES.to_string(), checksum);
+            }
+            None => remove_if_exists(&dest.join(BACKUP_VALUES))?,
+        }
+        let checksum = copy_log(&self.log, len, &dest.join(BACKUP_LOG))?;
+        files.insert(BACKUP_LOG.to_string(), checksum);
+        BackupManifest::new(&mut self.log.reader(), len, files)?.write(dest)
+    }
+
+    /// Checks every file of a backup against the checksums recorded in its manifest
+    ///
+    /// Fails with `KvError::CorruptBackup` naming the first file that is missing, has been
+    /// changed since the backup was written, or has no checksum because the backup predates
+    /// them.
+    pub fn verify_backup(dir: &Path) -> Result<()> {
+        let manifest = BackupManifest::read(dir)?
+            .ok_or_else(|| KvError::CorruptBackup(BACKUP_MANIFEST.to_string()))?;
+        if !manifest.files.contains_key(BACKUP_LOG) {
+            return Err(KvError::CorruptBackup(BACKUP_LOG.to_string()));
+        }
+        if dir.join(BACKUP_VALUES).exists() && !manifest.files.contains_key(BACKUP_VALUES) {
+            return Err(KvError::CorruptBackup(BACKUP_VALUES.to_string()));
+        }
+        for (name, expected) in &manifest.files {
+            let path = dir.join(name);
+            if !path.exists() || Checksum::of_file(&path)? != *expected {
+                return Err(KvError::CorruptBackup(name.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Brings a backup in `dest` up to date, copying only what was appended since it was taken
+    ///
+    /// Falls back to a full backup when there is no usable earlier backup or the log has been
+    /// compacted since. Returns the number of bytes copied.
+    pub fn backup_incremental(&self, dest: &Path) -> Result<u64> {
+        let len = self.log.len()?;
+        let values_len = match &self.values {
+            Some(values) => values.len()?,
+            None => 0,
+        };
+        let mut src = self.log.reader();
+        let backup_log = dest.join(BACKUP_LOG);
+        let previous = match BackupManifest::read(dest)? {
+            Some(manifest) if manifest.len <= len => manifest,
+            _ => return self.backup(dest).map(|_| len + values_len),
+        };
+        let unchanged = previous.same_log(&BackupManifest::new(
+            &mut src,
+            previous.len,
+            BTreeMap::new(),
+        )?);
+        let complete = match fs::metadata(&backup_log) {
+            Ok(meta) => meta.len() == previous.len,
+            Err(_) => false,
+        };
+        // The value log only shrinks when the log is compacted, which was ruled out above
+        let previous_values = match fs::metadata(dest.join(BACKUP_VALUES)) {
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        };
+        // Checksums are carried forward, so a backup without them is taken afresh
+        let log_checksum = previous.files.get(BACKUP_LOG).copied();
+        let values_checksum = match previous_values {
+            0 => Some(Checksum::EMPTY),
+            _ => previous.files.get(BACKUP_VALUES).copied(),
+        };
+        let (log_checksum, values_checksum) = match (log_checksum, values_checksum) {
+            (Some(log), Some(values)) if unchanged && complete && previous_values <= values_len => {
+                (log, values)
+            }
+            _ => return self.backup(dest).map(|_| len + values_len),
+        };
+
+        let mut files = BTreeMap::new();
+        if let Some(values) = &self.values {
+            let checksum = append_log(
+                values,
+                previous_values,
+                values_len,
+                &dest.join(BACKUP_VALUES),
+                values_checksum,
+            )?;
+            files.insert(BACKUP_VALUES.to_string(), checksum);
+        }
+        let checksum = append_log(&self.log, previous.len, len, &backup_log, log_checksum)?;
+        files.insert(BACKUP_LOG.to_string(), checksum);
+        BackupManifest::new(&mut src, len, files)?.write(dest)?;
+        Ok(len - previous.len + values_len - previous_values)
+    }
+
+    /// Rewinds the store to its state at a point in its history, discarding later writes
+    ///
+    /// Compaction keeps only the latest version of each key, so only writes made since the
+    /// last compaction can be rolled back reliably. What is registered on the store is kept, as
+    /// with `restore`.
+    pub fn restore_to(&mut self, point: RestorePoint) -> Result<()> {
+        // Replay through a read-only handle, as this store still holds the lock on the log
+        let log = self.log.read_handle(&self.path)?;
+        let values = self.reopen_values()?;
+        let mut restored = KvStore::load(
+            self.path.clone(),
+            log,
+            values,
+            Some(point),
+            self.options.clone(),
+        )?;
+        restored.seq = self.seq;
+        restored.merge_operator = self.merge_operator.clone();
+        // The rewritten log replaces the one this store has open
+        self.close_files();
+        let format = restored.options.format;
+        if let Err(err) = restored.rewrite_log(format) {
+            self.reopen_files()?;
+            return Err(err);
+        }
+        self.replace_with(restored)
+    }
+
+    /// Replaces the contents of the store with a backup written by `backup`
+    ///
+    /// Watchers, change sinks, event hooks, the merge operator and open buckets stay registered,
+    /// and secondary indexes are rebuilt from the restored contents. The keys the restore
+    /// changed are not reported to watchers, sinks or hooks.
+    pub fn restore(&mut self, src: &Path) -> Result<()> {
+        self.check_writable()?;
+        let has_values = src.join(BACKUP_VALUES).exists();
+        let restored = if self.log.is_memory() {
+            let log = Log::with_contents(fs::read(src.join(BACKUP_LOG))?);
+            let values = if has_values {
+                Some(Log::with_contents(fs::read(src.join(BACKUP_VALUES))?))
+            } else {
+                None
+            };
+            KvStore::load(self.path.clone(), log, values, None, self.options.clone())?
+        } else {
+            let tmp_values_path = self.path.with_extension("vlog.restore");
+            if has_values {
+                self.options
+                    .import_file(&src.join(BACKUP_VALUES), &tmp_values_path)?;
+            }
+            let tmp_path = self.path.with_extension("restore");
+            self.options.import_file(&src.join(BACKUP_LOG), &tmp_path)?;
+
+            // The files are only replaced once the copies are complete, and must be closed
+            // first so that the renames also work on Windows
+            self.close_files();
+            match self.replace_files(&tmp_path, has_values.then_some(tmp_values_path.as_path())) {
+                Ok(restored) => restored,
+                Err(err) => {
+                    self.reopen_files()?;
+                    return Err(err);
+                }
+            }
+        };
+        self.replace_with(restored)
+    }
+
+    /// Moves restored copies of the log and value log over those of the store and opens them
+    fn replace_files(&self, log: &Path, values: Option<&Path>) -> Result<KvStore> {
+        let values_path = self.path.with_extension("vlog");
+        match values {
+            Some(values) => self.options.rename_file(values, &values_path)?,
+            None if self.options.file_exists(&values_path) => {
+                self.options.remove_file(&values_path)?
+            }
+            None => {}
+        }
+        self.options.rename_file(log, &self.path)?;
+        self.options.open(&self.path)
+    }
+
+    /// Takes the place of this store with a restored one, handing over what was registered
+    /// on it
+    fn replace_with(&mut self, mut restored: KvStore) -> Result<()> {
+        restored.merge_operator = self.merge_operator.take();
+        restored.watchers = mem::take(&mut self.watchers);
+        restored.sinks = mem::take(&mut self.sinks);
+        restored.hooks = mem::take(&mut self.hooks);
+        restored.buckets = mem::take(&mut self.buckets);
+        restored.secondary = mem::take(&mut self.secondary);
+        restored.rebuild_indexes()?;
+        restored.temp_dir = self.temp_dir.take();
+        *self = restored;
+        Ok(())
+    }
+}
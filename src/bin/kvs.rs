@@ -1,39 +1,1179 @@
 extern crate structopt;
 
-use kvs::KvStore;
-use std::path::Path;
+use kvs::{JournalOp, KvError, KvStore, LogFormat, RestorePoint};
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use serde_json::json;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use structopt::StructOpt;
 
+#[derive(StructOpt)]
+struct Opts {
+    #[structopt(
+        long = "db",
+        env = "KVS_DIR",
+        raw(global = "true"),
+        default_value = "data.log",
+        parse(from_os_str),
+        help = "Directory holding the store, or the path of its log file"
+    )]
+    db: PathBuf,
+    #[structopt(
+        long = "output",
+        raw(global = "true"),
+        default_value = "plain",
+        raw(possible_values = "&[\"plain\", \"json\", \"quiet\"]"),
+        help = "Print results as plain text or JSON, or exit with status 2 instead of \
+                printing that a key was not found"
+    )]
+    output: String,
+    #[structopt(subcommand)]
+    app: KvsApp,
+}
+
 #[derive(StructOpt)]
 enum KvsApp {
     #[structopt(name = "set")]
-    Set { key: String, value: String },
+    Set {
+        key: String,
+        value: String,
+        #[structopt(
+            long = "ttl",
+            parse(try_from_str = "humantime::parse_duration"),
+            help = "Expire the key after this long, e.g. 30s or 1h 15m"
+        )]
+        ttl: Option<Duration>,
+    },
+    #[structopt(name = "getset")]
+    GetSet { key: String, value: String },
+    #[structopt(name = "getdel")]
+    GetDel { key: String },
+    #[structopt(name = "exists")]
+    Exists { key: String },
+    #[structopt(name = "strlen")]
+    Strlen { key: String },
+    #[structopt(name = "ttl")]
+    Ttl { key: String },
     #[structopt(name = "get")]
-    Get { key: String },
+    Get {
+        key: String,
+        #[structopt(
+            long = "meta",
+            help = "Also print when the key was created and modified"
+        )]
+        meta: bool,
+        #[structopt(flatten)]
+        output: OutputOpts,
+    },
     #[structopt(name = "rm")]
     Remove { key: String },
+    #[structopt(name = "clear")]
+    Clear {
+        #[structopt(long = "yes", help = "Confirm that every key should be deleted")]
+        yes: bool,
+    },
+    #[structopt(name = "rewind")]
+    Rewind {
+        #[structopt(
+            long = "seq",
+            conflicts_with = "time",
+            required_unless = "time",
+            help = "Keep writes up to and including this sequence number"
+        )]
+        seq: Option<u64>,
+        #[structopt(
+            long = "time",
+            help = "Keep writes made at or before this RFC 3339 time"
+        )]
+        time: Option<String>,
+    },
+    #[structopt(name = "export")]
+    Export {
+        #[structopt(
+            long = "format",
+            default_value = "jsonl",
+            raw(possible_values = r#"&["jsonl", "sqlite"]"#)
+        )]
+        format: String,
+        #[structopt(help = "File to write instead of standard output")]
+        file: Option<String>,
+        #[structopt(flatten)]
+        output: OutputOpts,
+    },
+    #[structopt(name = "import")]
+    Import {
+        #[structopt(
+            long = "format",
+            default_value = "jsonl",
+            raw(possible_values = r#"&["jsonl", "csv", "rdb"]"#)
+        )]
+        format: String,
+        #[structopt(
+            long = "key-column",
+            default_value = "key",
+            help = "CSV column holding the keys, by header name or zero-based position"
+        )]
+        key_column: String,
+        #[structopt(
+            long = "value-column",
+            default_value = "value",
+            help = "CSV column holding the values, by header name or zero-based position"
+        )]
+        value_column: String,
+        #[structopt(help = "File to read instead of standard input")]
+        file: Option<String>,
+    },
+    #[structopt(name = "compact")]
+    Compact,
+    #[structopt(name = "migrate")]
+    Migrate {
+        #[structopt(
+            long = "format",
+            raw(possible_values = "&[\"msgpack\", \"jsonl\"]"),
+            help = "Encode records in this format instead of the current one"
+        )]
+        format: Option<String>,
+    },
+    #[structopt(name = "stats")]
+    Stats,
+    #[structopt(name = "doctor")]
+    Doctor,
+    #[structopt(name = "backup")]
+    Backup {
+        dir: String,
+        #[structopt(
+            long = "incremental",
+            help = "Only copy what was written since the last backup to the same directory"
+        )]
+        incremental: bool,
+        #[structopt(
+            long = "verify",
+            help = "Check the backup against its checksums once it is written"
+        )]
+        verify: bool,
+    },
+    #[structopt(name = "restore")]
+    Restore {
+        dir: String,
+        #[structopt(
+            long = "verify",
+            help = "Check the backup against its checksums before restoring it"
+        )]
+        verify: bool,
+    },
+    #[structopt(name = "grep")]
+    Grep {
+        pattern: String,
+        #[structopt(long = "keys", help = "Only match keys, not values")]
+        keys: bool,
+        #[structopt(flatten)]
+        output: OutputOpts,
+    },
+    #[structopt(name = "scan")]
+    Scan {
+        #[structopt(default_value = "", help = "Only list keys starting with this prefix")]
+        prefix: String,
+        #[structopt(long = "limit", help = "Stop after this many keys")]
+        limit: Option<usize>,
+        #[structopt(long = "values", help = "Print the value of each key after a tab")]
+        values: bool,
+        #[structopt(
+            long = "meta",
+            help = "Also print when each key was created and modified"
+        )]
+        meta: bool,
+        #[structopt(
+            long = "modified-before",
+            help = "Only list keys last modified before this RFC 3339 time"
+        )]
+        modified_before: Option<String>,
+        #[structopt(flatten)]
+        output: OutputOpts,
+    },
+    #[structopt(name = "inspect")]
+    Inspect { key: String },
+    #[structopt(name = "log-dump")]
+    LogDump,
+    #[structopt(name = "watch")]
+    Watch {
+        #[structopt(
+            default_value = "",
+            help = "Only print changes to keys with this prefix"
+        )]
+        prefix: String,
+        #[structopt(
+            long = "since",
+            help = "Print changes after this sequence number instead of only new ones"
+        )]
+        since: Option<u64>,
+        #[structopt(long = "count", help = "Exit after printing this many changes")]
+        count: Option<usize>,
+    },
+    #[structopt(name = "bench")]
+    Bench {
+        #[structopt(long = "writes", default_value = "10000")]
+        writes: usize,
+        #[structopt(long = "reads", default_value = "10000")]
+        reads: usize,
+        #[structopt(long = "value-size", default_value = "100")]
+        value_size: usize,
+        #[structopt(long = "threads", default_value = "1")]
+        threads: usize,
+    },
+    #[structopt(name = "shell")]
+    Shell,
+    #[structopt(name = "batch", raw(alias = "\"-\""))]
+    Batch,
+    #[structopt(name = "journal")]
+    Journal {
+        #[structopt(long = "since")]
+        since: Option<u64>,
+        #[structopt(long = "until")]
+        until: Option<u64>,
+    },
+}
+
+#[derive(StructOpt)]
+struct OutputOpts {
+    #[structopt(
+        long = "escape",
+        help = "Escape non-printable characters in keys and values"
+    )]
+    escape: bool,
+    #[structopt(
+        long = "base64",
+        conflicts_with = "escape",
+        help = "Print keys and values encoded as base64"
+    )]
+    base64: bool,
+    #[structopt(
+        long = "strict",
+        help = "Fail instead of printing keys or values containing non-printable characters"
+    )]
+    strict: bool,
+}
+
+impl OutputOpts {
+    fn is_plain(&self) -> bool {
+        !self.escape && !self.base64 && !self.strict
+    }
+
+    fn render(&self, value: &[u8]) -> Result<String, Box<dyn Error>> {
+        if self.base64 {
+            return Ok(base64::encode(value));
+        }
+        if self.strict && std::str::from_utf8(value).is_err() {
+            return Err(err_msg("Value is not valid UTF-8; use --base64"));
+        }
+        if self.escape {
+            // Bytes that are not part of valid UTF-8 are escaped one by one, so none are lost
+            let mut escaped = String::new();
+            for chunk in value.utf8_chunks() {
+                escaped.extend(chunk.valid().escape_debug());
+                for byte in chunk.invalid() {
+                    escaped.push_str(&format!("\\x{:02x}", byte));
+                }
+            }
+            return Ok(escaped);
+        }
+        let text = String::from_utf8_lossy(value);
+        if self.strict && text.chars().any(char::is_control) {
+            Err(err_msg(
+                "Value contains non-printable characters; use --escape or --base64",
+            ))
+        } else {
+            Ok(text.to_string())
+        }
+    }
 }
 
-fn run_app() -> kvs::Result<()> {
-    let app = KvsApp::from_args();
-    let mut kvs = KvStore::open(Path::new("data.log"))?;
+/// Writes every live pair as a line of JSON with its key and value rendered for printing
+///
+/// Lines written with `--base64` can be imported again, as they are marked the same way as
+/// binary pairs in a plain export.
+fn export_rendered(
+    kvs: &KvStore,
+    mut writer: impl Write,
+    output: &OutputOpts,
+) -> Result<(), Box<dyn Error>> {
+    for entry in kvs.export() {
+        let entry = entry?;
+        let mut record = json!({
+            "key": output.render(&entry.key)?,
+            "value": output.render(&entry.value)?,
+        });
+        if let Some(expires_at) = entry.expires_at {
+            record["expires_at"] = json!(expires_at);
+        }
+        if output.base64 {
+            record["base64"] = json!(true);
+        }
+        writeln!(writer, "{}", record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn run_app(opts: Opts) -> Result<(), Box<dyn Error>> {
+    let Opts {
+        db,
+        output: mode,
+        app,
+    } = opts;
+    if let KvsApp::Watch {
+        prefix,
+        since,
+        count,
+    } = app
+    {
+        return watch(&db, &mode, &prefix, since, count);
+    }
+    if let KvsApp::Bench {
+        writes,
+        reads,
+        value_size,
+        threads,
+    } = app
+    {
+        return bench(writes, reads, value_size, threads.max(1));
+    }
+    let mut kvs = KvStore::open(&db)?;
 
     match app {
-        KvsApp::Set { key, value } => kvs.set(key, value),
-        KvsApp::Get { key } => kvs
-            .get(key)
-            .map(|v| println!("{}", v.unwrap_or_else(|| "Key not found".to_string()))),
-        KvsApp::Remove { key } => kvs.remove(key),
+        KvsApp::Set {
+            key,
+            value,
+            ttl: None,
+        } => kvs.set(key, value)?,
+        KvsApp::Set {
+            key,
+            value,
+            ttl: Some(ttl),
+        } => kvs.set_with_ttl(key, value, ttl)?,
+        KvsApp::GetSet { key, value } => {
+            let previous = kvs.replace(key.clone(), value)?;
+            print_previous(&mode, &key, previous)?;
+        }
+        KvsApp::GetDel { key } => {
+            let previous = kvs.take(key.clone())?;
+            print_previous(&mode, &key, previous)?;
+        }
+        KvsApp::Exists { key } => {
+            let exists = kvs.contains_key(&key);
+            match mode.as_str() {
+                "json" => println!("{}", json!({"key": key, "exists": exists})),
+                "quiet" => {}
+                _ => println!("{}", exists),
+            }
+            if !exists {
+                return Err(Missing.into());
+            }
+        }
+        KvsApp::Strlen { key } => match (mode.as_str(), kvs.get_bytes(key.as_bytes())?) {
+            ("json", value) => println!(
+                "{}",
+                json!({"key": key, "len": value.map(|value| value.len())})
+            ),
+            ("quiet", None) => return Err(KvError::KeyNotFound.into()),
+            (_, None) => println!("Key not found"),
+            (_, Some(value)) => println!("{}", value.len()),
+        },
+        KvsApp::Ttl { key } => {
+            // Remaining lifetimes are printed to the second
+            let ttl = kvs.ttl(&key)?.map(|ttl| Duration::from_secs(ttl.as_secs()));
+            match (mode.as_str(), ttl) {
+                ("json", ttl) => println!(
+                    "{}",
+                    json!({"key": key, "ttl": ttl.map(|ttl| ttl.as_secs())})
+                ),
+                (_, Some(ttl)) => println!("{}", humantime::format_duration(ttl)),
+                (_, None) => println!("No expiry"),
+            }
+        }
+        KvsApp::Get { key, meta, output } => {
+            let found = match kvs.get_bytes(key.as_bytes())? {
+                Some(value) if meta => Some((value, kvs.metadata(&key).map(|entry| entry.meta))),
+                Some(value) => Some((value, None)),
+                None => None,
+            };
+            match (mode.as_str(), found) {
+                ("json", found) => {
+                    let entry = match found {
+                        Some((value, meta)) => {
+                            let mut entry = json!({"key": key, "value": output.render(&value)?});
+                            if let Some(meta) = meta {
+                                entry["created"] = json!(format_time(meta.created));
+                                entry["modified"] = json!(format_time(meta.modified));
+                                entry["expires"] = json!(meta.expires_at.map(format_time));
+                            }
+                            entry
+                        }
+                        None => json!({"key": key, "value": null}),
+                    };
+                    println!("{}", entry);
+                }
+                ("quiet", None) => return Err(KvError::KeyNotFound.into()),
+                (_, None) => println!("Key not found"),
+                (_, Some((value, meta))) => {
+                    println!("{}", output.render(&value)?);
+                    if let Some(meta) = meta {
+                        println!("created: {}", format_time(meta.created));
+                        println!("modified: {}", format_time(meta.modified));
+                        if let Some(expires_at) = meta.expires_at {
+                            println!("expires: {}", format_time(expires_at));
+                        }
+                    }
+                }
+            }
+        }
+        KvsApp::Remove { key } => kvs.remove(key)?,
+        KvsApp::Clear { yes: false } => {
+            return Err(err_msg("Refusing to delete every key without --yes"));
+        }
+        KvsApp::Clear { yes: true } => kvs.clear()?,
+        KvsApp::Backup {
+            dir,
+            incremental,
+            verify,
+        } => {
+            if incremental {
+                kvs.backup_incremental(Path::new(&dir))?;
+            } else {
+                kvs.backup(Path::new(&dir))?;
+            }
+            if verify {
+                KvStore::verify_backup(Path::new(&dir))?;
+            }
+        }
+        KvsApp::Restore { dir, verify } => {
+            if verify {
+                KvStore::verify_backup(Path::new(&dir))?;
+            }
+            kvs.restore(Path::new(&dir))?
+        }
+        KvsApp::Rewind { seq, time } => {
+            let point = match (seq, time) {
+                (Some(seq), _) => RestorePoint::Seq(seq),
+                (None, Some(time)) => RestorePoint::Time(parse_time(&time)?),
+                (None, None) => return Err(err_msg("Either --seq or --time is required")),
+            };
+            kvs.restore_to(point)?;
+        }
+        KvsApp::Export {
+            format,
+            file,
+            output,
+        } => {
+            let stdout = io::stdout();
+            match (format.as_str(), file) {
+                ("jsonl", file) => {
+                    let writer: Box<dyn Write> = match file {
+                        Some(file) => Box::new(io::BufWriter::new(fs::File::create(file)?)),
+                        None => Box::new(stdout.lock()),
+                    };
+                    if output.is_plain() {
+                        kvs.export_jsonl(writer)?;
+                    } else {
+                        export_rendered(&kvs, writer, &output)?;
+                    }
+                }
+                (_, _) if !output.is_plain() => {
+                    return Err(err_msg("Output flags only apply to JSON Lines exports"))
+                }
+                #[cfg(feature = "sqlite")]
+                ("sqlite", Some(file)) => {
+                    kvs.export_sqlite(Path::new(&file))?;
+                }
+                #[cfg(not(feature = "sqlite"))]
+                ("sqlite", Some(_)) => {
+                    return Err(err_msg(
+                        "SQLite exports need kvs to be built with the sqlite feature",
+                    ))
+                }
+                ("sqlite", None) => return Err(err_msg("SQLite exports need an output file")),
+                _ => return Err(err_msg(format!("Unsupported format {}", format))),
+            };
+        }
+        KvsApp::Import {
+            format,
+            key_column,
+            value_column,
+            file,
+        } => {
+            let stdin = io::stdin();
+            let input: Box<dyn io::BufRead> = match file {
+                Some(file) => Box::new(io::BufReader::new(fs::File::open(file)?)),
+                None => Box::new(stdin.lock()),
+            };
+            match format.as_str() {
+                "jsonl" => kvs.import_jsonl(input)?,
+                "csv" => kvs.import_csv(input, &key_column, &value_column)?,
+                "rdb" => kvs.import_rdb(input)?,
+                _ => return Err(err_msg(format!("Unsupported format {}", format))),
+            };
+        }
+        KvsApp::Compact => kvs.compact()?,
+        KvsApp::Migrate { format } => {
+            let from = (kvs.log_version()?, kvs.log_format());
+            let format = match format.as_deref() {
+                Some("jsonl") => LogFormat::JsonLines,
+                Some(_) => LogFormat::MessagePack,
+                None => kvs.log_format(),
+            };
+            kvs.migrate(format)?;
+            println!(
+                "migrated from version {} ({}) to version {} ({})",
+                from.0,
+                format_name(from.1),
+                kvs.log_version()?,
+                format_name(kvs.log_format())
+            );
+        }
+        KvsApp::Stats => {
+            let stats = kvs.stats()?;
+            println!("keys: {}", stats.keys);
+            println!("tombstones: {}", stats.tombstones);
+            println!("live bytes: {}", stats.live_bytes);
+            println!("dead bytes: {}", stats.dead_bytes);
+            println!("log size: {}", stats.log_size);
+            println!("cache hit rate: {:.2}", stats.cache_hit_rate());
+            println!("compactions: {}", stats.compactions);
+            println!("write stalls: {}", stats.write_stalls);
+            println!("evictions: {}", stats.evictions);
+            for (name, histogram) in &[("key", &stats.key_sizes), ("value", &stats.value_sizes)] {
+                println!("{} sizes:", name);
+                for (bound, count) in histogram.buckets() {
+                    println!("  < {}: {}", bound, count);
+                }
+            }
+        }
+        KvsApp::Doctor => {
+            println!("ok: log opened with {} keys", kvs.len());
+            println!("ok: index uses about {} bytes", kvs.estimated_index_size());
+            self_test(log_path(&db).parent().unwrap_or_else(|| Path::new("")))?;
+            println!("ok: write/read/compact round trip succeeded");
+            for advice in diagnose(&kvs, &log_path(&db))? {
+                println!("{}", advice);
+            }
+        }
+        KvsApp::Grep {
+            pattern,
+            keys,
+            output,
+        } => {
+            let pattern = pattern.as_bytes();
+            let matches = |bytes: &[u8]| {
+                pattern.is_empty() || bytes.windows(pattern.len()).any(|w| w == pattern)
+            };
+            let mut found = false;
+            for entry in kvs.scan_filter(|key, value| matches(key) || (!keys && matches(value))) {
+                let entry = entry?;
+                let key = output.render(&entry.key)?;
+                let value = output.render(&entry.value)?;
+                found = true;
+                match mode.as_str() {
+                    "json" => println!("{}", json!({"key": key, "value": value})),
+                    "quiet" => break,
+                    _ => println!("{}\t{}", key, value),
+                }
+            }
+            if mode == "quiet" && !found {
+                return Err(KvError::KeyNotFound.into());
+            }
+        }
+        KvsApp::Scan {
+            prefix,
+            limit,
+            values,
+            meta,
+            modified_before,
+            output,
+        } => {
+            let until = modified_before.as_deref().map(parse_time).transpose()?;
+            let keys: Vec<Vec<u8>> = kvs
+                .keys_bytes()
+                .filter(|key| key.starts_with(prefix.as_bytes()))
+                .filter(|key| match until {
+                    Some(until) => kvs
+                        .metadata(&String::from_utf8_lossy(key))
+                        .is_some_and(|location| location.meta.modified < until),
+                    None => true,
+                })
+                .take(limit.unwrap_or(usize::MAX))
+                .map(<[u8]>::to_vec)
+                .collect();
+            for key in keys {
+                let value = match (values, kvs.get_bytes(&key)?) {
+                    (true, Some(value)) => Some(output.render(&value)?),
+                    (true, None) => continue,
+                    (false, _) => None,
+                };
+                let times = match (meta, kvs.metadata(&String::from_utf8_lossy(&key))) {
+                    (true, Some(location)) => Some((
+                        format_time(location.meta.created),
+                        format_time(location.meta.modified),
+                    )),
+                    _ => None,
+                };
+                let key = output.render(&key)?;
+                match (mode.as_str(), value, times) {
+                    ("json", value, Some((created, modified))) => println!(
+                        "{}",
+                        json!({"key": key, "value": value, "created": created, "modified": modified})
+                    ),
+                    ("json", value, None) => println!("{}", json!({"key": key, "value": value})),
+                    (_, value, times) => {
+                        let mut line = key;
+                        if let Some(value) = value {
+                            line = format!("{}\t{}", line, value);
+                        }
+                        if let Some((created, modified)) = times {
+                            line = format!("{}\t{}\t{}", line, created, modified);
+                        }
+                        println!("{}", line);
+                    }
+                }
+            }
+        }
+        KvsApp::Inspect { key } => match (mode.as_str(), kvs.metadata(&key)) {
+            ("json", location) => println!(
+                "{}",
+                location.map_or(json!({ "key": key, "location": null }), |location| {
+                    json!({
+                        "key": key,
+                        "path": location.path,
+                        "offset": location.offset,
+                        "size": location.len,
+                        "value log bytes": location.separated,
+                        "blob": location.blob,
+                        "cached": location.cached,
+                        "seq": location.meta.seq,
+                    })
+                })
+            ),
+            ("quiet", None) => return Err(KvError::KeyNotFound.into()),
+            (_, None) => println!("Key not found"),
+            (_, Some(location)) => {
+                println!("path: {}", location.path.display());
+                println!("offset: {}", location.offset);
+                println!("size: {}", location.len);
+                println!("value log bytes: {}", location.separated);
+                if let Some(blob) = location.blob {
+                    println!("blob: {}", blob);
+                }
+                println!("cached: {}", location.cached);
+                println!("seq: {}", location.meta.seq);
+            }
+        },
+        KvsApp::LogDump => {
+            let dash = |field: Option<String>| field.unwrap_or_else(|| "-".to_string());
+            for record in kvs.dump_log()? {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    record.offset,
+                    record.len,
+                    record.kind.unwrap_or("UNDECODABLE"),
+                    dash(record.seq.map(|seq| seq.to_string())),
+                    dash(record.key),
+                    dash(record.value_size.map(|size| size.to_string()))
+                );
+            }
+        }
+        KvsApp::Watch { .. } | KvsApp::Bench { .. } => unreachable!(),
+        KvsApp::Shell => shell(&mut kvs)?,
+        KvsApp::Batch => batch(&mut kvs)?,
+        KvsApp::Journal { since, until } => {
+            let since = since.map_or(Bound::Unbounded, Bound::Included);
+            let until = until.map_or(Bound::Unbounded, Bound::Included);
+            for entry in kvs.journal((since, until))? {
+                let size = entry
+                    .value_size
+                    .map_or_else(|| "-".to_string(), |s| s.to_string());
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    entry.seq,
+                    format_time(entry.time),
+                    entry.op,
+                    match entry.new_key {
+                        Some(new_key) => format!("{} -> {}", entry.key, new_key),
+                        None => entry.key,
+                    },
+                    size
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints changes to keys with a prefix as other processes write them
+///
+/// The store is reopened read-only whenever its log changes and the journal is read from the
+/// last sequence number seen, so no lock is held on the store while watching. Values are read
+/// when a change is seen, so a key written several times between polls shows its latest value.
+fn watch(
+    db: &Path,
+    mode: &str,
+    prefix: &str,
+    since: Option<u64>,
+    count: Option<usize>,
+) -> Result<(), Box<dyn Error>> {
+    let open = || KvStore::options().read_only(true).open(db);
+    let log_path = log_path(db);
+    let mut last_seq = match since {
+        Some(seq) => seq,
+        None => open()?.latest_seq(),
+    };
+    let mut last_modified = None;
+    let mut remaining = count.unwrap_or(usize::MAX);
+    while remaining > 0 {
+        let metadata = fs::metadata(&log_path)?;
+        let modified = Some((metadata.len(), metadata.modified()?));
+        if modified == last_modified {
+            std::thread::sleep(Duration::from_millis(100));
+            continue;
+        }
+        last_modified = modified;
+        let mut store = open()?;
+        for entry in store.journal(last_seq + 1..)? {
+            last_seq = entry.seq;
+            let relevant = match (entry.op, &entry.new_key) {
+                (JournalOp::RemovePrefix, _) => {
+                    entry.key.starts_with(prefix) || prefix.starts_with(&entry.key)
+                }
+                (_, Some(new_key)) => entry.key.starts_with(prefix) || new_key.starts_with(prefix),
+                _ => entry.key.starts_with(prefix),
+            };
+            if !relevant || remaining == 0 {
+                continue;
+            }
+            remaining -= 1;
+            let value = match (entry.op, &entry.new_key) {
+                (JournalOp::Remove, _) | (JournalOp::RemovePrefix, _) => None,
+                (_, Some(new_key)) => store.get(new_key.clone())?,
+                _ => store.get(entry.key.clone())?,
+            };
+            match mode {
+                "json" => println!(
+                    "{}",
+                    json!({
+                        "seq": entry.seq,
+                        "op": entry.op.to_string(),
+                        "key": entry.key,
+                        "new_key": entry.new_key,
+                        "value": value,
+                    })
+                ),
+                _ => {
+                    let key = match entry.new_key {
+                        Some(new_key) => format!("{} -> {}", entry.key, new_key),
+                        None => entry.key,
+                    };
+                    match value {
+                        Some(value) => println!("{}\t{}\t{}", entry.op, key, value),
+                        None => println!("{}\t{}", entry.op, key),
+                    }
+                }
+            }
+            io::stdout().flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets and then reads keys in a temporary store and reports throughput and latencies
+fn bench(
+    writes: usize,
+    reads: usize,
+    value_size: usize,
+    threads: usize,
+) -> Result<(), Box<dyn Error>> {
+    let store = Arc::new(Mutex::new(KvStore::temporary()?));
+    let value = "x".repeat(value_size);
+    let setter = Arc::clone(&store);
+    let latencies = bench_phase(writes, threads, move |n| {
+        let key = format!("key{:010}", n);
+        setter.lock().unwrap().set(key, value.clone())
+    })?;
+    print_bench("set", &latencies);
+    if writes > 0 {
+        let latencies = bench_phase(reads, threads, move |n| {
+            // Spread reads over the written keys with a multiplicative hash
+            let key = format!("key{:010}", n.wrapping_mul(2_654_435_761) % writes);
+            store.lock().unwrap().get(key).map(|_| ())
+        })?;
+        print_bench("get", &latencies);
+    }
+    Ok(())
+}
+
+/// Runs `ops` operations split across threads and returns their latencies in ascending order,
+/// along with the total time taken
+fn bench_phase<F>(
+    ops: usize,
+    threads: usize,
+    op: F,
+) -> Result<(Vec<Duration>, Duration), Box<dyn Error>>
+where
+    F: Fn(usize) -> kvs::Result<()> + Send + Sync + 'static,
+{
+    let op = Arc::new(op);
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|thread| {
+            let op = Arc::clone(&op);
+            std::thread::spawn(move || -> kvs::Result<Vec<Duration>> {
+                let mut latencies = Vec::new();
+                for n in (thread..ops).step_by(threads) {
+                    let started = Instant::now();
+                    op(n)?;
+                    latencies.push(started.elapsed());
+                }
+                Ok(latencies)
+            })
+        })
+        .collect();
+    let mut latencies = Vec::with_capacity(ops);
+    for handle in handles {
+        let thread_latencies = handle
+            .join()
+            .map_err(|_| err_msg("Benchmark thread panicked"))??;
+        latencies.extend(thread_latencies);
+    }
+    let elapsed = start.elapsed();
+    latencies.sort();
+    Ok((latencies, elapsed))
+}
+
+fn print_bench(name: &str, (latencies, elapsed): &(Vec<Duration>, Duration)) {
+    if latencies.is_empty() {
+        return;
+    }
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    println!(
+        "{}: {} ops in {:.2?} ({:.0} ops/s)",
+        name,
+        latencies.len(),
+        elapsed,
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "  p50 {:.2?}  p95 {:.2?}  p99 {:.2?}  max {:.2?}",
+        percentile(50),
+        percentile(95),
+        percentile(99),
+        latencies[latencies.len() - 1]
+    );
+}
+
+const SHELL_HELP: &str = "\
+get KEY            print the value of a key
+set KEY VALUE      set a key; the value is the rest of the line
+rm KEY             remove a key
+scan [PREFIX]      print every key starting with a prefix, with its value
+history            list the commands entered so far
+exit               leave the shell";
+
+/// Runs commands read line by line from standard input against an open store
+///
+/// At a terminal lines can be edited and earlier commands recalled with the arrow keys. Without
+/// one there is no prompt and lines are read as they come, so scripts can pipe commands in.
+fn shell(kvs: &mut KvStore) -> Result<(), Box<dyn Error>> {
+    let mut history = Vec::new();
+    if !atty::is(atty::Stream::Stdin) {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            if !shell_line(kvs, &line?, &mut history) {
+                break;
+            }
+        }
+        return Ok(());
+    }
+    let mut editor = Editor::<()>::new();
+    loop {
+        match editor.readline("kvs> ") {
+            Ok(line) => {
+                editor.add_history_entry(line.trim());
+                if !shell_line(kvs, &line, &mut history) {
+                    return Ok(());
+                }
+            }
+            // Ctrl-C only abandons the line being typed, as in other shells
+            Err(ReadlineError::Interrupted) => {}
+            Err(ReadlineError::Eof) => return Ok(()),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Runs a line typed into the shell, returning false once it asks to leave
+fn shell_line(kvs: &mut KvStore, line: &str, history: &mut Vec<String>) -> bool {
+    let line = line.trim();
+    if line.is_empty() {
+        return true;
+    }
+    if line == "exit" || line == "quit" {
+        return false;
+    }
+    if line == "history" {
+        for (n, command) in history.iter().enumerate() {
+            println!("{:>4}  {}", n + 1, command);
+        }
+    } else if let Err(err) = shell_command(kvs, line) {
+        println!("error: {}", err);
+    }
+    history.push(line.to_string());
+    true
+}
+
+/// Applies newline-delimited commands from standard input, printing one result per command
+///
+/// Failed commands are reported with their line number and do not stop the batch.
+fn batch(kvs: &mut KvStore) -> Result<(), Box<dyn Error>> {
+    let stdin = io::stdin();
+    let mut failed = 0;
+    for (n, line) in stdin.lock().lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Err(err) = shell_command(kvs, line) {
+            println!("error: line {}: {}", n + 1, err);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(err_msg(format!("{} commands failed", failed)));
+    }
+    Ok(())
+}
+
+fn shell_command(kvs: &mut KvStore, line: &str) -> Result<(), Box<dyn Error>> {
+    let (command, rest) = split_word(line);
+    match command {
+        "get" => match kvs.get(rest.to_string())? {
+            Some(value) => println!("{}", value),
+            None => println!("Key not found"),
+        },
+        "set" => {
+            let (key, value) = split_word(rest);
+            if key.is_empty() {
+                return Err(err_msg("usage: set KEY VALUE"));
+            }
+            kvs.set(key.to_string(), value.to_string())?;
+            println!("OK");
+        }
+        "rm" => {
+            kvs.remove(rest.to_string())?;
+            println!("OK");
+        }
+        "scan" => {
+            for (key, value) in kvs.scan_prefix(rest)? {
+                println!("{}\t{}", key, value);
+            }
+        }
+        "help" => println!("{}", SHELL_HELP),
+        _ => return Err(err_msg(format!("unknown command {}; try help", command))),
+    }
+    Ok(())
+}
+
+/// Splits off the first whitespace-separated word of a line
+fn split_word(line: &str) -> (&str, &str) {
+    match line.find(char::is_whitespace) {
+        Some(end) => (&line[..end], line[end..].trim_start()),
+        None => (line, ""),
+    }
+}
+
+/// Prints the value a key held before a write, like `get` does for its current value
+fn print_previous(mode: &str, key: &str, previous: Option<String>) -> Result<(), Box<dyn Error>> {
+    match (mode, previous) {
+        ("json", previous) => println!("{}", json!({"key": key, "value": previous})),
+        ("quiet", None) => return Err(KvError::KeyNotFound.into()),
+        (_, None) => println!("Key not found"),
+        (_, Some(previous)) => println!("{}", previous),
+    }
+    Ok(())
+}
+
+/// Stale bytes below which compaction is not worth recommending
+const DOCTOR_MIN_GARBAGE: u64 = 1 << 20;
+
+/// Values above this size are better kept in the value log
+const DOCTOR_LARGE_VALUE: u64 = 1 << 20;
+
+/// Describes the state of the log and recommends what to do about it
+fn diagnose(kvs: &KvStore, log_path: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let stats = kvs.stats()?;
+    let value_log = match fs::metadata(log_path.with_extension("vlog")) {
+        Ok(metadata) => metadata.len(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+        Err(err) => return Err(err.into()),
+    };
+    let mut lines = vec![format!(
+        "info: log is {} bytes, value log {} bytes",
+        stats.log_size, value_log
+    )];
+    let garbage = match stats.log_size {
+        0 => 0.0,
+        size => stats.dead_bytes as f64 / size as f64,
+    };
+    lines.push(format!(
+        "info: {:.0}% of the log is stale records ({} bytes)",
+        garbage * 100.0,
+        stats.dead_bytes
+    ));
+    lines.push(format!("info: {} tombstones", stats.tombstones));
+    let mut sizes: Vec<(u64, String, bool)> = kvs
+        .keys()
+        .filter_map(|key| {
+            let location = kvs.metadata(&key)?;
+            let separated = location.separated > 0;
+            Some((location.len + location.separated, key, separated))
+        })
+        .collect();
+    sizes.sort_by(|a, b| b.cmp(a));
+    for (size, key, _) in sizes.iter().take(5) {
+        lines.push(format!("info: large key {} ({} bytes)", key, size));
+    }
+
+    if stats.dead_bytes >= DOCTOR_MIN_GARBAGE && stats.dead_bytes > stats.live_bytes {
+        lines.push(format!(
+            "recommend: compact now with `kvs compact` to reclaim about {} bytes",
+            stats.dead_bytes
+        ));
+    }
+    if stats.tombstones > stats.keys {
+        lines.push(
+            "recommend: most of the index is removed keys; shorten the tombstone retention"
+                .to_string(),
+        );
+    }
+    if sizes
+        .iter()
+        .any(|(size, _, separated)| *size > DOCTOR_LARGE_VALUE && !separated)
+    {
+        lines.push(
+            "recommend: keep large values out of the log with a value log threshold".to_string(),
+        );
+    }
+    if kvs.log_version()? < 1 {
+        lines.push(
+            "recommend: upgrade the log to the current format with `kvs migrate`".to_string(),
+        );
+    }
+    Ok(lines)
+}
+
+/// Writes, reads back, compacts and removes a key in a scratch store in the data directory
+///
+/// The scratch store gets a directory of its own that did not exist before, so removing it
+/// afterwards never touches files of the user.
+fn self_test(data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let mut attempt = 0;
+    let scratch = loop {
+        let scratch = data_dir.join(format!(".kvs-doctor-{}-{}", process::id(), attempt));
+        match fs::create_dir(&scratch) {
+            Ok(()) => break scratch,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(_) => {
+                return Err(err_msg(
+                    "Unable to create files in the data directory; check its permissions",
+                ))
+            }
+        }
+    };
+    let result = round_trip(&scratch);
+    let _ = fs::remove_dir_all(&scratch);
+    result
+}
+
+fn round_trip(path: &Path) -> Result<(), Box<dyn Error>> {
+    let key = "doctor".to_string();
+    let value = "ok".to_string();
+    KvStore::open(path)
+        .and_then(|mut store| store.set(key.clone(), value.clone()))
+        .map_err(|_| err_msg("Unable to write to the data directory; check its permissions"))?;
+    let mut store = KvStore::open(path)?;
+    if store.get(key.clone())? != Some(value.clone()) {
+        return Err(err_msg(
+            "Written value could not be read back; check the disk",
+        ));
+    }
+    store.compact()?;
+    if store.get(key.clone())? != Some(value) {
+        return Err(err_msg(
+            "Value was lost by compaction; check the disk and report a bug",
+        ));
+    }
+    store.remove(key)?;
+    Ok(())
+}
+
+/// Returns the path of the log of the store at `db`, which is either the log or its directory
+fn log_path(db: &Path) -> PathBuf {
+    if db.is_dir() {
+        db.join("data.log")
+    } else {
+        db.to_path_buf()
     }
 }
 
+fn parse_time(time: &str) -> Result<u64, Box<dyn Error>> {
+    let time = humantime::parse_rfc3339_weak(time)?;
+    Ok(time.duration_since(UNIX_EPOCH)?.as_millis() as u64)
+}
+
+fn format_time(millis: u64) -> String {
+    let time = UNIX_EPOCH + Duration::from_millis(millis);
+    humantime::format_rfc3339_millis(time).to_string()
+}
+
+fn format_name(format: LogFormat) -> &'static str {
+    match format {
+        LogFormat::MessagePack => "msgpack",
+        LogFormat::JsonLines => "jsonl",
+    }
+}
+
+/// Reports a missing key through the exit status after the result was already printed
+#[derive(Debug)]
+struct Missing;
+
+impl fmt::Display for Missing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key not found")
+    }
+}
+
+impl Error for Missing {}
+
+fn err_msg<M: Into<String>>(message: M) -> Box<dyn Error> {
+    message.into().into()
+}
+
 fn main() {
-    process::exit(match run_app() {
+    let opts = Opts::from_args();
+    let quiet = opts.output == "quiet";
+    process::exit(match run_app(opts) {
         Ok(_) => 0,
-        Err(err) => {
-            println!("{}", err);
-            1
-        }
+        Err(err) if err.downcast_ref::<Missing>().is_some() => 2,
+        Err(err) => match err.downcast_ref::<KvError>() {
+            Some(KvError::KeyNotFound) if quiet => 2,
+            _ => {
+                println!("{}", err);
+                1
+            }
+        },
     });
 }
@@ -0,0 +1,346 @@
+use crate::log::Log;
+use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// How the read cache chooses which value to evict when it is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Eviction {
+    /// Evict the least recently used value
+    Lru,
+    /// Evict the least frequently used value, oldest first among equals
+    Lfu,
+    /// Segmented LRU: values read only once are evicted before values read repeatedly, so a
+    /// large scan does not push out the hot set
+    ScanResistant,
+}
+
+/// Tracks cached keys and picks eviction victims
+trait Policy: Send {
+    /// Records that a key was added to or read from the cache
+    fn touch(&mut self, key: &[u8]);
+    /// Forgets a key removed from the cache
+    fn remove(&mut self, key: &[u8]);
+    /// Forgets and returns the next key to evict
+    fn evict(&mut self) -> Option<Vec<u8>>;
+}
+
+/// Keys ordered by when they were last touched
+#[derive(Default)]
+struct Recency<K> {
+    order: BTreeMap<u64, K>,
+    ticks: HashMap<K, u64>,
+    tick: u64,
+}
+
+impl<K: Hash + Eq + Clone> Recency<K> {
+    fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.ticks.contains_key(key)
+    }
+
+    fn len(&self) -> usize {
+        self.ticks.len()
+    }
+
+    fn touch<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+    {
+        self.tick += 1;
+        if let Some(tick) = self.ticks.insert(key.to_owned(), self.tick) {
+            self.order.remove(&tick);
+        }
+        self.order.insert(self.tick, key.to_owned());
+    }
+
+    fn remove<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.ticks.remove(key) {
+            Some(tick) => {
+                self.order.remove(&tick);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<K> {
+        let tick = *self.order.keys().next()?;
+        let key = self.order.remove(&tick)?;
+        self.ticks.remove(&key);
+        Some(key)
+    }
+}
+
+impl Policy for Recency<Vec<u8>> {
+    fn touch(&mut self, key: &[u8]) {
+        Recency::touch(self, key)
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        Recency::remove(self, key);
+    }
+
+    fn evict(&mut self) -> Option<Vec<u8>> {
+        self.pop_oldest()
+    }
+}
+
+#[derive(Default)]
+struct Frequency {
+    order: BTreeMap<(u64, u64), Vec<u8>>,
+    counts: HashMap<Vec<u8>, (u64, u64)>,
+    tick: u64,
+}
+
+impl Policy for Frequency {
+    fn touch(&mut self, key: &[u8]) {
+        self.tick += 1;
+        let count = match self.counts.get(key) {
+            Some(&rank) => {
+                self.order.remove(&rank);
+                rank.0 + 1
+            }
+            None => 1,
+        };
+        self.counts.insert(key.to_vec(), (count, self.tick));
+        self.order.insert((count, self.tick), key.to_vec());
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(rank) = self.counts.remove(key) {
+            self.order.remove(&rank);
+        }
+    }
+
+    fn evict(&mut self) -> Option<Vec<u8>> {
+        let rank = *self.order.keys().next()?;
+        let key = self.order.remove(&rank)?;
+        self.counts.remove(&key);
+        Some(key)
+    }
+}
+
+/// Segmented LRU with a probationary segment for new keys and a protected one for keys read again
+struct Segmented {
+    probation: Recency<Vec<u8>>,
+    protected: Recency<Vec<u8>>,
+    protected_capacity: usize,
+}
+
+impl Policy for Segmented {
+    fn touch(&mut self, key: &[u8]) {
+        if self.protected.contains(key) || self.probation.remove(key) {
+            self.protected.touch(key);
+            while self.protected.len() > self.protected_capacity {
+                match self.protected.pop_oldest() {
+                    Some(demoted) => self.probation.touch(&demoted),
+                    None => break,
+                }
+            }
+        } else {
+            self.probation.touch(key);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if !self.probation.remove(key) {
+            self.protected.remove(key);
+        }
+    }
+
+    fn evict(&mut self) -> Option<Vec<u8>> {
+        self.probation
+            .pop_oldest()
+            .or_else(|| self.protected.pop_oldest())
+    }
+}
+
+/// Read cache of values bounded by entry count and, optionally, by total size in bytes
+pub(crate) struct ValueCache {
+    values: HashMap<Vec<u8>, Vec<u8>>,
+    policy: Box<dyn Policy>,
+    capacity: usize,
+    max_bytes: Option<usize>,
+    bytes: usize,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: usize, max_bytes: Option<usize>, eviction: Eviction) -> ValueCache {
+        let policy: Box<dyn Policy> = match eviction {
+            Eviction::Lru => Box::new(Recency::default()),
+            Eviction::Lfu => Box::new(Frequency::default()),
+            Eviction::ScanResistant => Box::new(Segmented {
+                probation: Recency::default(),
+                protected: Recency::default(),
+                protected_capacity: capacity * 4 / 5,
+            }),
+        };
+        ValueCache {
+            values: HashMap::new(),
+            policy,
+            capacity,
+            max_bytes,
+            bytes: 0,
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &[u8]) -> Option<&Vec<u8>> {
+        let value = self.values.get(key)?;
+        self.policy.touch(key);
+        Some(value)
+    }
+
+    pub(crate) fn peek(&self, key: &[u8]) -> Option<&Vec<u8>> {
+        self.values.get(key)
+    }
+
+    /// Caches a value, evicting entries chosen by the policy until it fits, and returns the
+    /// keys of the evicted entries
+    ///
+    /// Values larger than the whole byte budget are not cached.
+    pub(crate) fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Vec<Vec<u8>> {
+        self.pop(&key);
+        let size = key.len() + value.len();
+        let mut evicted = Vec::new();
+        if self.capacity == 0 || self.over_budget(size, 0) {
+            return evicted;
+        }
+        while self.values.len() >= self.capacity || self.over_budget(size, self.bytes) {
+            match self.policy.evict() {
+                Some(victim) => {
+                    if let Some(value) = self.values.remove(&victim) {
+                        self.bytes -= victim.len() + value.len();
+                        evicted.push(victim);
+                    }
+                }
+                None => break,
+            }
+        }
+        self.bytes += size;
+        self.policy.touch(&key);
+        self.values.insert(key, value);
+        evicted
+    }
+
+    fn over_budget(&self, size: usize, used: usize) -> bool {
+        match self.max_bytes {
+            Some(max) => used + size > max,
+            None => false,
+        }
+    }
+
+    pub(crate) fn pop(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.values.remove(key)?;
+        self.policy.remove(key);
+        self.bytes -= key.len() + value.len();
+        Some(value)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        for key in self.values.keys() {
+            self.policy.remove(key);
+        }
+        self.values.clear();
+        self.bytes = 0;
+    }
+
+    /// Returns the keys of the cached values, in no particular order
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.values.keys()
+    }
+
+    /// Returns the total size of the cached keys and values
+    pub(crate) fn size(&self) -> usize {
+        self.bytes
+    }
+}
+
+/// Size of the log blocks held by the block cache
+const BLOCK_SIZE: u64 = 4096;
+
+/// Cache of fixed-size blocks of the log file, keyed by their offset
+///
+/// Only full blocks are cached: the log only grows at its end, so a full block never changes
+/// until the log is truncated or rewritten, at which point the cache must be cleared.
+pub(crate) struct BlockCache {
+    blocks: HashMap<u64, Vec<u8>>,
+    recency: Recency<u64>,
+    capacity: usize,
+    tail: Vec<u8>,
+}
+
+impl BlockCache {
+    pub(crate) fn new(max_bytes: usize) -> BlockCache {
+        BlockCache {
+            blocks: HashMap::new(),
+            recency: Recency::default(),
+            capacity: (max_bytes as u64 / BLOCK_SIZE) as usize,
+            tail: Vec::new(),
+        }
+    }
+
+    fn block(&mut self, log: &Log, start: u64) -> io::Result<&[u8]> {
+        if self.blocks.contains_key(&start) {
+            self.recency.touch(&start);
+            return Ok(&self.blocks[&start]);
+        }
+
+        let mut block = Vec::with_capacity(BLOCK_SIZE as usize);
+        let mut reader = log.reader();
+        reader.seek(SeekFrom::Start(start))?;
+        reader.take(BLOCK_SIZE).read_to_end(&mut block)?;
+        if block.len() as u64 != BLOCK_SIZE || self.capacity == 0 {
+            self.tail = block;
+            return Ok(&self.tail);
+        }
+        while self.blocks.len() >= self.capacity {
+            match self.recency.pop_oldest() {
+                Some(victim) => {
+                    self.blocks.remove(&victim);
+                }
+                None => break,
+            }
+        }
+        self.recency.touch(&start);
+        Ok(self.blocks.entry(start).or_insert(block))
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.blocks.clear();
+        self.recency = Recency::default();
+    }
+}
+
+/// Reads the log from an offset through a block cache
+pub(crate) struct BlockReader<'a> {
+    pub(crate) log: &'a Log,
+    pub(crate) cache: &'a RefCell<BlockCache>,
+    pub(crate) pos: u64,
+}
+
+impl<'a> Read for BlockReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut cache = self.cache.borrow_mut();
+        let start = self.pos - self.pos % BLOCK_SIZE;
+        let block = cache.block(self.log, start)?;
+        let offset = (self.pos - start) as usize;
+        if offset >= block.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(block.len() - offset);
+        buf[..len].copy_from_slice(&block[offset..offset + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
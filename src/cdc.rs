@@ -0,0 +1,118 @@
+use crate::{now_millis, KvStore, Result};
+use serde::Serialize;
+use std::io::Write;
+
+/// A committed change to a key, as delivered to a change sink
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// Sequence number of the write that made the change
+    pub seq: u64,
+    /// Time of the change in milliseconds since the Unix epoch
+    pub time: u64,
+    /// Key that changed
+    pub key: Vec<u8>,
+    /// New value of the key, or `None` if it was removed
+    pub value: Option<Vec<u8>>,
+}
+
+/// Destination for the changes committed to a store, for keeping other systems in sync
+///
+/// Renames are delivered as the removal of the old key and the set of the new one, and merges
+/// and appends as the set of the resulting value.
+pub trait ChangeSink: Send {
+    /// Receives a change after it has been committed to the log
+    fn write(&mut self, change: &Change) -> Result<()>;
+}
+
+impl<F> ChangeSink for F
+where
+    F: FnMut(&Change) -> Result<()> + Send,
+{
+    fn write(&mut self, change: &Change) -> Result<()> {
+        self(change)
+    }
+}
+
+/// Change sink writing one JSON object per change to a file, socket or other writer
+///
+/// Keys and values that are not valid UTF-8 are base64 encoded, as in JSON Lines exports.
+pub struct JsonLinesSink<W> {
+    writer: W,
+}
+
+impl<W: Write + Send> JsonLinesSink<W> {
+    /// Creates a sink writing to `writer`
+    pub fn new(writer: W) -> JsonLinesSink<W> {
+        JsonLinesSink { writer }
+    }
+}
+
+#[derive(Serialize)]
+struct ChangeRecord {
+    seq: u64,
+    time: u64,
+    key: String,
+    value: Option<String>,
+    #[serde(skip_serializing_if = "is_false")]
+    base64: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+impl<W: Write + Send> ChangeSink for JsonLinesSink<W> {
+    fn write(&mut self, change: &Change) -> Result<()> {
+        let text = |bytes: &[u8]| std::str::from_utf8(bytes).map(str::to_string);
+        let record = match (
+            text(&change.key),
+            change.value.as_deref().map(text).transpose(),
+        ) {
+            (Ok(key), Ok(value)) => ChangeRecord {
+                seq: change.seq,
+                time: change.time,
+                key,
+                value,
+                base64: false,
+            },
+            _ => ChangeRecord {
+                seq: change.seq,
+                time: change.time,
+                key: base64::encode(&change.key),
+                value: change.value.as_ref().map(base64::encode),
+                base64: true,
+            },
+        };
+        serde_json::to_writer(&mut self.writer, &record)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl KvStore {
+    /// Mirrors every change committed from now on to a sink
+    ///
+    /// If the sink fails, the write that made the change returns its error, although the
+    /// change itself has already been committed.
+    pub fn add_change_sink<S: ChangeSink + 'static>(&mut self, sink: S) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    /// Delivers a change made by the latest write to every sink
+    pub(crate) fn publish(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        if self.sinks.is_empty() {
+            return Ok(());
+        }
+        let change = Change {
+            seq: self.seq,
+            time: now_millis(),
+            key: key.to_vec(),
+            value: value.map(<[u8]>::to_vec),
+        };
+        for sink in &mut self.sinks {
+            sink.write(&change)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) use std::time::Instant;
+
+/// Returns the current time in milliseconds since the Unix epoch
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Returns the current time in milliseconds since the Unix epoch
+///
+/// The standard library has no clock on wasm32, so the time comes from JavaScript.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn now_millis() -> u64 {
+    js_sys::Date::now() as u64
+}
+
+/// Point in time that operations are timed from, read from the JavaScript clock in place of
+/// `std::time::Instant`, which panics on wasm32
+#[cfg(target_arch = "wasm32")]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Instant(f64);
+
+#[cfg(target_arch = "wasm32")]
+impl Instant {
+    pub(crate) fn now() -> Instant {
+        Instant(js_sys::Date::now())
+    }
+
+    pub(crate) fn elapsed(&self) -> Duration {
+        Duration::from_secs_f64((js_sys::Date::now() - self.0).max(0.0) / 1000.0)
+    }
+}
+
+/// Blocks the current thread for `delay`
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn sleep(delay: Duration) {
+    std::thread::sleep(delay);
+}
+
+/// Threads cannot sleep on wasm32, so waits there return at once
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn sleep(_delay: Duration) {}
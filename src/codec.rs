@@ -0,0 +1,114 @@
+//! Encoding of log records and the header recording which encoding a log uses
+//!
+//! Logs start with an 8-byte header: the magic bytes `KVSL`, a header version, a byte naming
+//! the record format, a zero byte and a newline, so the records of a JSON Lines log each start
+//! on a line of their own. Logs written before there was a header have none and hold
+//! MessagePack records from the first byte; they count as version 0.
+//!
+//! Stores refuse logs of a header version they do not know. Compaction writes the current
+//! version, so `KvStore::migrate` upgrades a log by rewriting it.
+use crate::log::Log;
+use crate::{KvError, LogEntry, Result};
+use serde::Deserialize;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Encoding of the records in a log, chosen when the log is created
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Compact binary records, the default
+    MessagePack,
+    /// One JSON object per line, for reading the log with a text editor or `jq`
+    JsonLines,
+}
+
+/// Length of the header at the start of a log
+pub(crate) const HEADER_LEN: u64 = 8;
+
+const MAGIC: &[u8; 4] = b"KVSL";
+/// Version of the header and record layout written by this build
+pub(crate) const HEADER_VERSION: u8 = 1;
+
+impl LogFormat {
+    fn tag(self) -> u8 {
+        match self {
+            LogFormat::MessagePack => b'm',
+            LogFormat::JsonLines => b'j',
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<LogFormat> {
+        match tag {
+            b'm' => Some(LogFormat::MessagePack),
+            b'j' => Some(LogFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+/// Encodes a record in the given format
+pub(crate) fn encode(format: LogFormat, entry: &LogEntry) -> Result<Vec<u8>> {
+    match format {
+        LogFormat::MessagePack => Ok(rmp_serde::encode::to_vec(entry)?),
+        LogFormat::JsonLines => {
+            let mut buf = serde_json::to_vec(entry)?;
+            buf.push(b'\n');
+            Ok(buf)
+        }
+    }
+}
+
+/// Decodes one record from a reader positioned at its start, leaving it at the next record
+pub(crate) fn decode<R: Read>(format: LogFormat, mut reader: R) -> Result<LogEntry> {
+    match format {
+        LogFormat::MessagePack => Ok(rmp_serde::decode::from_read(reader)?),
+        LogFormat::JsonLines => {
+            let entry =
+                LogEntry::deserialize(&mut serde_json::Deserializer::from_reader(&mut reader))?;
+            let mut newline = [0];
+            reader.read_exact(&mut newline)?;
+            if newline[0] != b'\n' {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "record not followed by a newline",
+                )
+                .into());
+            }
+            Ok(entry)
+        }
+    }
+}
+
+/// Reads the header of a log, returning the format it names, or `None` if the log has none
+pub(crate) fn read_header(log: &Log) -> Result<Option<LogFormat>> {
+    if log.len()? < HEADER_LEN {
+        return Ok(None);
+    }
+    let mut header = [0; HEADER_LEN as usize];
+    let mut reader = log.reader();
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Ok(None);
+    }
+    if header[4] != HEADER_VERSION {
+        return Err(KvError::UnsupportedLogVersion(header[4]));
+    }
+    match LogFormat::from_tag(header[5]) {
+        Some(format) => Ok(Some(format)),
+        None => Err(KvError::Corruption { offset: 5 }),
+    }
+}
+
+/// Writes the header for a format to an empty log
+pub(crate) fn write_header<W: Write>(mut writer: W, format: LogFormat) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[HEADER_VERSION, format.tag(), 0, b'\n'])
+}
+
+/// Returns the offset of the first record of a log, after its header if it has one
+pub(crate) fn records_start(log: &Log) -> Result<u64> {
+    Ok(match read_header(log)? {
+        Some(_) => HEADER_LEN,
+        None => 0,
+    })
+}
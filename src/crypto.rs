@@ -0,0 +1,135 @@
+use crate::codec;
+use crate::{KvError, LogEntry, Options, Result};
+#[cfg(feature = "encryption")]
+use aes_gcm::aead::{Aead, NewAead};
+#[cfg(feature = "encryption")]
+use aes_gcm::Aes256Gcm;
+#[cfg(feature = "encryption")]
+use std::convert::TryInto;
+#[cfg(feature = "encryption")]
+use std::fmt;
+
+/// Length of the random nonce stored with each encrypted record
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+/// Bytes a value grows by when sealed for the value log: its nonce and authentication tag
+const SEAL_OVERHEAD: u64 = 12 + 16;
+
+/// AES-256-GCM cipher used to encrypt the records of a store
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub(crate) struct Cipher(Aes256Gcm);
+
+#[cfg(feature = "encryption")]
+impl Cipher {
+    pub(crate) fn new(key: &[u8; 32]) -> Cipher {
+        Cipher(Aes256Gcm::new(key.into()))
+    }
+
+    /// Encrypts bytes under a fresh random nonce, returning the nonce and the ciphertext
+    fn seal(&self, plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        let mut nonce = [0; NONCE_LEN];
+        getrandom::getrandom(&mut nonce).map_err(|_| KvError::Encryption)?;
+        let data = self
+            .0
+            .encrypt((&nonce).into(), plaintext)
+            .map_err(|_| KvError::Encryption)?;
+        Ok((nonce.to_vec(), data))
+    }
+
+    /// Decrypts and authenticates bytes sealed with the same key
+    fn open(&self, nonce: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let nonce: &[u8; NONCE_LEN] = nonce.try_into().map_err(|_| KvError::Encryption)?;
+        self.0
+            .decrypt(nonce.into(), data)
+            .map_err(|_| KvError::Encryption)
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cipher(..)")
+    }
+}
+
+/// Encodes a record for the log, encrypting it if the store has a key
+///
+/// Encrypted records are always MessagePack inside, as their contents cannot be read anyway.
+pub(crate) fn seal_record(options: &Options, entry: &LogEntry) -> Result<Vec<u8>> {
+    #[cfg(feature = "encryption")]
+    {
+        if let Some(cipher) = &options.cipher {
+            let buf = rmp_serde::encode::to_vec(entry)?;
+            let (nonce, data) = cipher.seal(&buf)?;
+            return codec::encode(options.format, &LogEntry::Sealed { nonce, data });
+        }
+    }
+    codec::encode(options.format, entry)
+}
+
+/// Decrypts a record read from the log; records written unencrypted are returned as they are
+pub(crate) fn unseal_record(options: &Options, entry: LogEntry) -> Result<LogEntry> {
+    match entry {
+        LogEntry::Sealed { nonce, data } => {
+            let buf = open(options, &nonce, &data)?;
+            Ok(rmp_serde::decode::from_slice(&buf)?)
+        }
+        entry => Ok(entry),
+    }
+}
+
+/// Returns the bytes to store in the value log for a value and whether they are encrypted
+pub(crate) fn seal_value(options: &Options, value: &[u8]) -> Result<(Vec<u8>, bool)> {
+    #[cfg(feature = "encryption")]
+    {
+        if let Some(cipher) = &options.cipher {
+            let (mut nonce, data) = cipher.seal(value)?;
+            nonce.extend_from_slice(&data);
+            return Ok((nonce, true));
+        }
+    }
+    #[cfg(not(feature = "encryption"))]
+    let _ = options;
+    Ok((value.to_vec(), false))
+}
+
+/// Returns the length of a value stored in the value log as `stored` bytes
+pub(crate) fn plain_len(stored: u64, sealed: bool) -> u64 {
+    if sealed {
+        stored.saturating_sub(SEAL_OVERHEAD)
+    } else {
+        stored
+    }
+}
+
+/// Returns a value read from the value log, decrypting it if needed
+pub(crate) fn unseal_value(options: &Options, stored: Vec<u8>, sealed: bool) -> Result<Vec<u8>> {
+    if !sealed {
+        return Ok(stored);
+    }
+    #[cfg(feature = "encryption")]
+    {
+        if stored.len() < NONCE_LEN {
+            return Err(KvError::Encryption);
+        }
+        let (nonce, data) = stored.split_at(NONCE_LEN);
+        open(options, nonce, data)
+    }
+    #[cfg(not(feature = "encryption"))]
+    open(options, &[], &stored)
+}
+
+#[cfg(feature = "encryption")]
+fn open(options: &Options, nonce: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    match &options.cipher {
+        Some(cipher) => cipher.open(nonce, data),
+        None => Err(KvError::Encryption),
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+fn open(_options: &Options, _nonce: &[u8], _data: &[u8]) -> Result<Vec<u8>> {
+    Err(KvError::Encryption)
+}
@@ -0,0 +1,60 @@
+//! Writing logs through to the disk, past the page cache
+//!
+//! Records are appended at arbitrary lengths and offsets, which `O_DIRECT` does not allow, so
+//! logs are instead opened with `O_DSYNC` and their pages dropped from the page cache once
+//! written. On macOS `F_NOCACHE` keeps them out of the cache in the first place. Other platforms
+//! open logs as usual.
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+/// Opens a file, writing through to the disk if `direct` is set
+pub(crate) fn open(options: &mut OpenOptions, path: &Path, direct: bool) -> io::Result<File> {
+    if direct {
+        write_through(options);
+    }
+    let file = options.open(path)?;
+    if direct {
+        bypass_cache(&file)?;
+    }
+    Ok(file)
+}
+
+#[cfg(unix)]
+fn write_through(options: &mut OpenOptions) {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.custom_flags(libc::O_DSYNC);
+}
+
+#[cfg(not(unix))]
+fn write_through(_options: &mut OpenOptions) {}
+
+#[cfg(target_os = "macos")]
+fn bypass_cache(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    match unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn bypass_cache(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Drops the pages of a file written through to the disk from the page cache
+#[cfg(target_os = "linux")]
+pub(crate) fn drop_cached(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    match unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) } {
+        0 => Ok(()),
+        err => Err(io::Error::from_raw_os_error(err)),
+    }
+}
+
+/// Drops the pages of a file written through to the disk from the page cache
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn drop_cached(_file: &File) -> io::Result<()> {
+    Ok(())
+}
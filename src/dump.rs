@@ -0,0 +1,141 @@
+use crate::codec;
+use crate::crypto::unseal_record;
+use crate::{KvStore, LogEntry, Result};
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// A record of the log, or a region of it that could not be decoded, as listed by
+/// `KvStore::dump_log`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    /// Position of the record in the log
+    pub offset: u64,
+    /// Serialized size of the record in bytes
+    pub len: u64,
+    /// Kind of record, or `None` for an undecodable region
+    pub kind: Option<&'static str>,
+    /// Key or prefix the record is about, converted lossily to UTF-8
+    pub key: Option<String>,
+    /// Size of the value, merge operand or suffix held by the record
+    pub value_size: Option<usize>,
+    /// Sequence number of the write
+    pub seq: Option<u64>,
+}
+
+impl KvStore {
+    /// Walks the raw log and lists every record with its offset and size
+    ///
+    /// Bytes that cannot be decoded are skipped one at a time until a record can be read again,
+    /// and each such region is listed as a record without a kind. Records encrypted with a key
+    /// the store was not opened with are listed as `sealed`.
+    pub fn dump_log(&self) -> Result<Vec<LogRecord>> {
+        let mut reader = io::BufReader::new(self.log.reader());
+        reader.seek(SeekFrom::Start(0))?;
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        let mut records = Vec::new();
+        let mut blob_sizes = HashMap::new();
+        let mut corrupt_from = None;
+        let mut pos = codec::records_start(&self.log)? as usize;
+        while pos < data.len() {
+            let mut rest = &data[pos..];
+            let entry = match codec::decode(self.options.format, &mut rest) {
+                Ok(entry) => entry,
+                Err(_) => {
+                    corrupt_from.get_or_insert(pos);
+                    pos += 1;
+                    continue;
+                }
+            };
+            if let Some(start) = corrupt_from.take() {
+                records.push(corrupt(start, pos));
+            }
+            let len = data.len() - pos - rest.len();
+            let sealed_size = match &entry {
+                LogEntry::Sealed { data, .. } => Some(data.len()),
+                _ => None,
+            };
+            let entry = unseal_record(&self.options, entry);
+            let (kind, key, value_size, seq) = match &entry {
+                Ok(entry) => describe(entry, &mut blob_sizes),
+                Err(_) => ("sealed", None, sealed_size, None),
+            };
+            records.push(LogRecord {
+                offset: pos as u64,
+                len: len as u64,
+                kind: Some(kind),
+                key: key.map(|key| String::from_utf8_lossy(key).into_owned()),
+                value_size,
+                seq,
+            });
+            pos += len;
+        }
+        if let Some(start) = corrupt_from {
+            records.push(corrupt(start, pos));
+        }
+        Ok(records)
+    }
+}
+
+fn corrupt(start: usize, end: usize) -> LogRecord {
+    LogRecord {
+        offset: start as u64,
+        len: (end - start) as u64,
+        kind: None,
+        key: None,
+        value_size: None,
+        seq: None,
+    }
+}
+
+type Description<'a> = (&'static str, Option<&'a [u8]>, Option<usize>, Option<u64>);
+
+fn describe<'a>(entry: &'a LogEntry, blob_sizes: &mut HashMap<u64, usize>) -> Description<'a> {
+    match entry {
+        LogEntry::Set {
+            key,
+            value,
+            seq,
+            blob,
+            chunks,
+            separated,
+            ..
+        } => {
+            let size = match blob {
+                Some(id) => blob_sizes.get(id).cloned().unwrap_or(0),
+                None => {
+                    value.len()
+                        + chunks.iter().map(|c| c.len as usize).sum::<usize>()
+                        + separated.map_or(0, |location| location.len as usize)
+                }
+            };
+            ("set", Some(key), Some(size), Some(*seq))
+        }
+        LogEntry::Remove { key, seq, .. } => ("rm", Some(key), None, Some(*seq)),
+        LogEntry::Prepare { ops, .. } => ("prepare", None, Some(ops.len()), None),
+        LogEntry::Commit { seq, .. } => ("commit", None, None, Some(*seq)),
+        LogEntry::Abort { .. } => ("abort", None, None, None),
+        LogEntry::Checkpoint { seq, .. } => ("checkpoint", None, None, Some(*seq)),
+        LogEntry::Blob {
+            id,
+            value,
+            location,
+            ..
+        } => {
+            let size = location.map_or(value.len(), |location| location.len as usize);
+            blob_sizes.insert(*id, size);
+            ("blob", None, Some(size), None)
+        }
+        LogEntry::Chunk { data } => ("chunk", None, Some(data.len()), None),
+        LogEntry::RemovePrefix { prefix, seq, .. } => ("rm-prefix", Some(prefix), None, Some(*seq)),
+        LogEntry::Merge {
+            key, operand, seq, ..
+        } => ("merge", Some(key), Some(operand.len()), Some(*seq)),
+        LogEntry::Rename { from, seq, .. } => ("rename", Some(from), None, Some(*seq)),
+        LogEntry::Append {
+            key, suffix, seq, ..
+        } => ("append", Some(key), Some(suffix.len()), Some(*seq)),
+        LogEntry::Sealed { data, .. } => ("sealed", None, Some(data.len()), None),
+    }
+}
@@ -0,0 +1,68 @@
+use crate::{KvStore, Result};
+
+/// A key in a store, loaded for a read-modify-write operation
+///
+/// Each operation writes at most one log record.
+pub struct Entry<'a> {
+    store: &'a mut KvStore,
+    key: String,
+    value: Option<String>,
+}
+
+impl<'a> Entry<'a> {
+    /// Returns the key of the entry
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Returns the current value of the entry, if any
+    pub fn get(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+
+    /// Returns the value, storing `default` first if the key is missing
+    pub fn or_insert(self, default: String) -> Result<String> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Returns the value, storing the result of `f` first if the key is missing
+    pub fn or_insert_with<F: FnOnce() -> String>(self, f: F) -> Result<String> {
+        match self.value {
+            Some(value) => Ok(value),
+            None => {
+                let value = f();
+                self.store.set(self.key, value.clone())?;
+                Ok(value)
+            }
+        }
+    }
+
+    /// Updates the value in place and stores it, if the key is present
+    pub fn and_modify<F: FnOnce(&mut String)>(mut self, f: F) -> Result<Entry<'a>> {
+        if let Some(value) = self.value.as_mut() {
+            f(value);
+            self.store.set(self.key.clone(), value.clone())?;
+        }
+        Ok(self)
+    }
+
+    /// Removes the key if it is present and returns its value
+    pub fn remove(self) -> Result<Option<String>> {
+        if self.value.is_some() {
+            self.store.remove(self.key)?;
+        }
+        Ok(self.value)
+    }
+}
+
+impl KvStore {
+    /// Loads the entry for a key for in-place manipulation
+    pub fn entry(&mut self, key: String) -> Result<Entry<'_>> {
+        let value = self.get(key.clone())?;
+        Ok(Entry {
+            store: self,
+            key,
+            value,
+        })
+    }
+}
@@ -0,0 +1,378 @@
+use crate::crypto::seal_record;
+use crate::{now_millis, ChunkRef, IndexEntry, KvError, KvStore, LogEntry, Result, CHUNK_SIZE};
+use serde::{Deserialize, Serialize};
+use std::collections::{btree_map, HashSet};
+use std::io::{BufRead, Read, Write};
+#[cfg(feature = "sqlite")]
+use std::path::Path;
+
+/// A live key-value pair read from the log by `KvStore::export`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportEntry {
+    /// Key of the pair
+    pub key: Vec<u8>,
+    /// Value of the pair
+    pub value: Vec<u8>,
+    /// Time the key expires, in milliseconds since the Unix epoch
+    pub expires_at: Option<u64>,
+}
+
+/// Iterator over the live entries of a store in key order, reading values lazily
+///
+/// Only one value is held in memory at a time.
+pub struct Export<'a> {
+    store: &'a KvStore,
+    entries: btree_map::Iter<'a, Vec<u8>, IndexEntry>,
+    now: u64,
+}
+
+impl<'a> Iterator for Export<'a> {
+    type Item = Result<ExportEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, entry) = self.entries.next()?;
+            if entry.is_expired(self.now) {
+                continue;
+            }
+            let value = match self.store.cache.peek(key) {
+                Some(value) => value.clone(),
+                None => match self.store.read_log_entry(key, entry.pointer) {
+                    Ok(Some(value)) => value,
+                    Ok(None) => continue,
+                    Err(err) => return Some(Err(err)),
+                },
+            };
+            return Some(Ok(ExportEntry {
+                key: key.clone(),
+                value,
+                expires_at: entry.expires_at,
+            }));
+        }
+    }
+}
+
+/// One key-value pair in a JSON Lines export
+#[derive(Debug, Deserialize, Serialize)]
+struct ExportRecord {
+    key: String,
+    value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    /// Whether the key and value are base64 encoded because they are not valid UTF-8
+    #[serde(default, skip_serializing_if = "is_false")]
+    base64: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+impl ExportRecord {
+    fn new(key: &[u8], value: Vec<u8>, expires_at: Option<u64>) -> ExportRecord {
+        match (std::str::from_utf8(key), String::from_utf8(value)) {
+            (Ok(key), Ok(value)) => ExportRecord {
+                key: key.to_string(),
+                value,
+                expires_at,
+                base64: false,
+            },
+            (_, value) => ExportRecord {
+                key: base64::encode(key),
+                value: match value {
+                    Ok(value) => base64::encode(&value),
+                    Err(err) => base64::encode(err.as_bytes()),
+                },
+                expires_at,
+                base64: true,
+            },
+        }
+    }
+
+    fn into_bytes(self) -> Result<(Vec<u8>, Vec<u8>)> {
+        if !self.base64 {
+            return Ok((self.key.into_bytes(), self.value.into_bytes()));
+        }
+        let invalid = |_| KvError::InvalidImport;
+        Ok((
+            base64::decode(&self.key).map_err(invalid)?,
+            base64::decode(&self.value).map_err(invalid)?,
+        ))
+    }
+}
+
+impl KvStore {
+    /// Writes every live key-value pair as one JSON object per line, in key order
+    ///
+    /// Keys and values that are not valid UTF-8 are written base64 encoded. Returns the number
+    /// of pairs written.
+    pub fn export_jsonl<W: Write>(&self, mut writer: W) -> Result<usize> {
+        let mut count = 0;
+        for entry in self.export() {
+            let entry = entry?;
+            let record = ExportRecord::new(&entry.key, entry.value, entry.expires_at);
+            serde_json::to_writer(&mut writer, &record)?;
+            writer.write_all(b"\n")?;
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// Writes every live key-value pair to a `kv (key, value)` table in a SQLite database
+    ///
+    /// Any existing `kv` table is replaced. Keys that are valid UTF-8 are stored as text and
+    /// others as blobs, while values are arbitrary bytes and always stored in the `BLOB` value
+    /// column as blobs. Returns the number of pairs written.
+    #[cfg(feature = "sqlite")]
+    pub fn export_sqlite(&self, path: &Path) -> Result<usize> {
+        use rusqlite::types::Value;
+
+        let key_value = |bytes: Vec<u8>| match String::from_utf8(bytes) {
+            Ok(text) => Value::Text(text),
+            Err(err) => Value::Blob(err.into_bytes()),
+        };
+        let mut conn = rusqlite::Connection::open(path)?;
+        let tx = conn.transaction()?;
+        tx.execute_batch(
+            "DROP TABLE IF EXISTS kv; CREATE TABLE kv (key TEXT PRIMARY KEY, value BLOB);",
+        )?;
+        let mut count = 0;
+        {
+            let mut insert = tx.prepare("INSERT INTO kv (key, value) VALUES (?1, ?2)")?;
+            for entry in self.export() {
+                let entry = entry?;
+                insert.execute(&[key_value(entry.key), Value::Blob(entry.value)])?;
+                count += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(count)
+    }
+
+    /// Returns an iterator over every live key-value pair in key order, reading each value
+    /// from the log only when it is reached
+    pub fn export(&self) -> Export<'_> {
+        Export {
+            store: self,
+            entries: self.index.iter(),
+            now: now_millis(),
+        }
+    }
+
+    /// Returns an iterator over the live pairs in key order for which `filter` returns true
+    ///
+    /// Values are read one at a time as the iterator advances, so matching entries can be
+    /// processed without exporting the whole store.
+    pub fn scan_filter<'a, F>(
+        &'a self,
+        mut filter: F,
+    ) -> impl Iterator<Item = Result<ExportEntry>> + 'a
+    where
+        F: FnMut(&[u8], &[u8]) -> bool + 'a,
+    {
+        self.export().filter(move |entry| match entry {
+            Ok(entry) => filter(&entry.key, &entry.value),
+            Err(_) => true,
+        })
+    }
+
+    /// Stores every key-value pair read from lines written by `export_jsonl`
+    ///
+    /// Pairs that have already expired are skipped. Returns the number of pairs stored.
+    pub fn import_jsonl<R: BufRead>(&mut self, reader: R) -> Result<usize> {
+        let now = now_millis();
+        let mut count = 0;
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: ExportRecord = serde_json::from_str(&line)?;
+            let expires_at = record.expires_at;
+            if let Some(t) = expires_at {
+                if t <= now {
+                    continue;
+                }
+            }
+            let (key, value) = record.into_bytes()?;
+            self.write_value(key, value, expires_at, None)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Appends many key-value pairs to the log at once
+    ///
+    /// Every pair is checked against the size limits and room is made for all of them under the
+    /// quotas before anything is written, so a refused load leaves the store as it was. Values
+    /// are stored as `set` stores them, but their records are appended in a single write and
+    /// the index is only updated once all of them are on disk, skipping the per-key reads and
+    /// caching of `set`. Returns the number of pairs written.
+    pub fn bulk_load<I, K, V>(&mut self, entries: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.check_writable()?;
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = entries
+            .into_iter()
+            .map(|(key, value)| (key.into(), value.into()))
+            .collect();
+        let mut new_keys = HashSet::new();
+        let mut bytes = 0;
+        for (key, value) in &entries {
+            self.options.check_size(key, value.len())?;
+            if self.is_new_key(key) {
+                new_keys.insert(key.as_slice());
+            }
+            bytes += (key.len() + value.len()) as u64;
+        }
+        let new_keys = new_keys.len();
+        self.reserve_keys(new_keys)?;
+        self.reserve(bytes)?;
+
+        // Shared values append their own records to the log, so they are all written before
+        // the position of the batch is known
+        let mut placed = Vec::with_capacity(entries.len());
+        for (key, value) in entries {
+            let blob = if self.options.dedup_values {
+                Some(self.store_blob(&value)?)
+            } else {
+                None
+            };
+            let separated = match self.options.value_log_threshold {
+                Some(threshold) if blob.is_none() && value.len() > threshold => {
+                    Some(self.store_separated(&value)?)
+                }
+                _ => None,
+            };
+            placed.push((key, value, blob, separated));
+        }
+
+        let start = self.log.len()?;
+        let mut buf = Vec::new();
+        let mut loaded = Vec::with_capacity(placed.len());
+        let mut watched = Vec::new();
+        for (key, value, blob, separated) in placed {
+            if self.is_observed(&key) {
+                watched.push((key.clone(), value.clone()));
+            }
+            let seq = self.next_seq();
+            let time = now_millis();
+            let created = self.index.get(&key).map_or(time, |entry| entry.created);
+
+            let mut chunks = Vec::new();
+            let mut chunk_bytes = 0;
+            if blob.is_none() && separated.is_none() && value.len() > CHUNK_SIZE {
+                for data in value.chunks(CHUNK_SIZE) {
+                    let chunk = LogEntry::Chunk {
+                        data: data.to_vec(),
+                    };
+                    let pointer = start + buf.len() as u64;
+                    buf.extend(seal_record(&self.options, &chunk)?);
+                    chunks.push(ChunkRef {
+                        pointer,
+                        len: data.len() as u64,
+                        sealed: false,
+                    });
+                    chunk_bytes += data.len() as u64;
+                }
+            }
+            let (stored, compressed) =
+                if blob.is_some() || separated.is_some() || !chunks.is_empty() {
+                    (Vec::new(), false)
+                } else {
+                    self.encode_value(value)
+                };
+            let entry = LogEntry::Set {
+                key: key.clone(),
+                value: stored,
+                expires_at: None,
+                token: None,
+                seq,
+                time,
+                created,
+                blob,
+                chunks,
+                separated,
+                compressed,
+            };
+            let pointer = start + buf.len() as u64;
+            buf.extend(seal_record(&self.options, &entry)?);
+            let entry = IndexEntry {
+                pointer,
+                len: start + buf.len() as u64 - pointer + chunk_bytes,
+                expires_at: None,
+                seq,
+                time,
+                created,
+                blob,
+                separated: separated.map_or(0, |location| location.len),
+            };
+            loaded.push((key, entry));
+        }
+        self.append_raw(&buf)?;
+
+        let mut olds = Vec::with_capacity(watched.len());
+        for (key, _) in &watched {
+            olds.push(self.read_value(key)?);
+        }
+        let count = loaded.len();
+        let mut replaced = 0;
+        for (key, entry) in loaded {
+            self.cache.pop(&key);
+            let captured = self.versions.capture(&self.index, Some(&key));
+            if self.index.insert(key, entry).is_some() {
+                replaced += 1;
+            }
+            self.versions
+                .record(&self.index, captured, entry.seq, entry.time);
+        }
+        for ((key, value), old) in watched.into_iter().zip(olds) {
+            self.notify(&key, old, Some(&value))?;
+        }
+        // Overwritten keys count towards the next compaction as they do for `set`
+        self.compaction_counter = self.compaction_counter.saturating_add(replaced);
+        if self.compaction_counter > self.options.compaction_threshold {
+            self.compact()?;
+        }
+        Ok(count)
+    }
+
+    /// Stores the key and value columns of every row of a CSV file with a header row
+    ///
+    /// Columns are matched by header name, or by zero-based position if no header has that
+    /// name. Returns the number of rows stored.
+    pub fn import_csv<R: Read>(
+        &mut self,
+        reader: R,
+        key_column: &str,
+        value_column: &str,
+    ) -> Result<usize> {
+        let mut reader = csv::Reader::from_reader(reader);
+        let headers = reader.byte_headers()?.clone();
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|header| header == name.as_bytes())
+                .or_else(|| name.parse().ok())
+                .ok_or(KvError::InvalidImport)
+        };
+        let (key_index, value_index) = (column(key_column)?, column(value_column)?);
+
+        let mut count = 0;
+        for record in reader.byte_records() {
+            let record = record?;
+            match (record.get(key_index), record.get(value_index)) {
+                (Some(key), Some(value)) => {
+                    self.write_value(key.to_vec(), value.to_vec(), None, None)?
+                }
+                _ => return Err(KvError::InvalidImport),
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+}
@@ -0,0 +1,62 @@
+use crate::KvStore;
+
+/// Something that happened in a store, as delivered to event hooks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreEvent<'a> {
+    /// A key was given a new value, by a set, merge, append, rename or batch
+    Write {
+        /// Key that was written
+        key: &'a [u8],
+        /// New value of the key
+        value: &'a [u8],
+    },
+    /// A key was removed
+    Remove {
+        /// Key that was removed
+        key: &'a [u8],
+    },
+    /// Compaction of the log is about to start
+    CompactionStarted,
+    /// Compaction of the log finished, successfully or not
+    CompactionFinished {
+        /// Size of the log before compaction in bytes
+        size_before: u64,
+        /// Size of the log after compaction in bytes
+        size_after: u64,
+    },
+    /// A value was evicted from the read cache to make room for another
+    CacheEviction {
+        /// Key whose value was evicted
+        key: &'a [u8],
+    },
+}
+
+/// Callback receiving the events of a store
+pub(crate) type EventHook = Box<dyn FnMut(&StoreEvent) + Send>;
+
+impl KvStore {
+    /// Calls `hook` with every event from now on: writes, removals, compactions and cache
+    /// evictions
+    ///
+    /// Hooks run synchronously on the thread making the change, after it has been committed, so
+    /// they should be quick. Writes and removals are reported like they are to change sinks.
+    pub fn add_event_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&StoreEvent) + Send + 'static,
+    {
+        self.hooks.push(Box::new(hook));
+    }
+
+    pub(crate) fn emit(&mut self, event: StoreEvent) {
+        for hook in &mut self.hooks {
+            hook(&event);
+        }
+    }
+
+    /// Caches a value and reports any values evicted to make room for it
+    pub(crate) fn cache_value(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        for evicted in self.cache.put(key, value) {
+            self.emit(StoreEvent::CacheEviction { key: &evicted });
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use crate::{now_millis, IndexEntry, KvStore, Result};
+use std::collections::btree_map;
+
+/// Iterator over the live entries of a store in key order
+///
+/// Values are read from the log as the iterator advances.
+pub struct Iter<'a> {
+    store: &'a KvStore,
+    entries: btree_map::Iter<'a, Vec<u8>, IndexEntry>,
+    now: u64,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, entry) = self.entries.next()?;
+            if entry.is_expired(self.now) {
+                continue;
+            }
+            let value = match self.store.cache.peek(key) {
+                Some(value) => value.clone(),
+                None => match self.store.read_log_entry(key, entry.pointer) {
+                    Ok(Some(value)) => value,
+                    Ok(None) => continue,
+                    Err(err) => return Some(Err(err)),
+                },
+            };
+            return Some(utf8_pair(key.clone(), value));
+        }
+    }
+}
+
+fn utf8_pair(key: Vec<u8>, value: Vec<u8>) -> Result<(String, String)> {
+    Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+}
+
+impl<'a> IntoIterator for &'a KvStore {
+    type Item = Result<(String, String)>;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl KvStore {
+    /// Returns an iterator over all live key-value pairs in key order
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            store: self,
+            entries: self.index.iter(),
+            now: now_millis(),
+        }
+    }
+}
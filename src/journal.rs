@@ -0,0 +1,294 @@
+use crate::codec;
+use crate::crypto::{plain_len, unseal_record};
+use crate::{decoded_len, BatchOp, KvStore, LogEntry, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Seek, SeekFrom};
+use std::ops::RangeBounds;
+
+/// Kind of write recorded in the journal
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JournalOp {
+    /// A key was set
+    Set,
+    /// A key was removed
+    Remove,
+    /// All keys starting with a prefix were removed
+    RemovePrefix,
+    /// A merge operand was applied to a key
+    Merge,
+    /// A suffix was appended to the value of a key
+    Append,
+    /// A key was renamed
+    Rename,
+}
+
+impl fmt::Display for JournalOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JournalOp::Set => write!(f, "set"),
+            JournalOp::Remove => write!(f, "rm"),
+            JournalOp::RemovePrefix => write!(f, "rm-prefix"),
+            JournalOp::Merge => write!(f, "merge"),
+            JournalOp::Append => write!(f, "append"),
+            JournalOp::Rename => write!(f, "rename"),
+        }
+    }
+}
+
+/// A write operation reconstructed from the log
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    /// Sequence number of the write
+    pub seq: u64,
+    /// Time of the write in milliseconds since the Unix epoch
+    pub time: u64,
+    /// Kind of write
+    pub op: JournalOp,
+    /// Key that was written, or the prefix for prefix removals, converted lossily to UTF-8
+    pub key: String,
+    /// New name of the key for renames
+    pub new_key: Option<String>,
+    /// Size of the value, merge operand or suffix written, if any
+    pub value_size: Option<usize>,
+}
+
+/// A value a key held, or its removal, as listed by `KvStore::history`
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyVersion {
+    /// Sequence number of the write
+    pub seq: u64,
+    /// Time of the write in milliseconds since the Unix epoch
+    pub time: u64,
+    /// Value written, or `None` if the key was removed
+    pub value: Option<String>,
+}
+
+impl KvStore {
+    /// Lists the writes still present in the log with sequence numbers in the given range,
+    /// in the order they were applied
+    pub fn journal(&self, seqs: impl RangeBounds<u64>) -> Result<Vec<JournalEntry>> {
+        let mut reader = io::BufReader::new(self.log.reader());
+        reader.seek(SeekFrom::Start(codec::records_start(&self.log)?))?;
+        let mut prepared = HashMap::new();
+        let mut blob_sizes = HashMap::new();
+        let mut journal = Vec::new();
+
+        while let Ok(entry) = codec::decode(self.options.format, &mut reader) {
+            match unseal_record(&self.options, entry)? {
+                LogEntry::Set {
+                    key,
+                    value,
+                    seq,
+                    time,
+                    blob,
+                    chunks,
+                    separated,
+                    compressed,
+                    ..
+                } => journal.push(JournalEntry {
+                    seq,
+                    time,
+                    op: JournalOp::Set,
+                    key: String::from_utf8_lossy(&key).into_owned(),
+                    new_key: None,
+                    value_size: Some(match blob {
+                        Some(id) => blob_sizes.get(&id).cloned().unwrap_or(0),
+                        None => {
+                            decoded_len(&value, compressed)
+                                + chunks.iter().map(|c| c.len as usize).sum::<usize>()
+                                + separated.map_or(0, |location| {
+                                    plain_len(location.len, location.sealed) as usize
+                                })
+                        }
+                    }),
+                }),
+                LogEntry::Remove { key, seq, time, .. } => journal.push(JournalEntry {
+                    seq,
+                    time,
+                    op: JournalOp::Remove,
+                    key: String::from_utf8_lossy(&key).into_owned(),
+                    new_key: None,
+                    value_size: None,
+                }),
+                LogEntry::Prepare { token, ops } => {
+                    prepared.insert(token, ops);
+                }
+                LogEntry::Commit { token, seq, time } => {
+                    for op in prepared.remove(&token).unwrap_or_default() {
+                        journal.push(match op {
+                            BatchOp::Set { key, value } => JournalEntry {
+                                seq,
+                                time,
+                                op: JournalOp::Set,
+                                key: String::from_utf8_lossy(&key).into_owned(),
+                                new_key: None,
+                                value_size: Some(value.len()),
+                            },
+                            BatchOp::Remove { key } => JournalEntry {
+                                seq,
+                                time,
+                                op: JournalOp::Remove,
+                                key: String::from_utf8_lossy(&key).into_owned(),
+                                new_key: None,
+                                value_size: None,
+                            },
+                        });
+                    }
+                }
+                LogEntry::RemovePrefix { prefix, seq, time } => journal.push(JournalEntry {
+                    seq,
+                    time,
+                    op: JournalOp::RemovePrefix,
+                    key: String::from_utf8_lossy(&prefix).into_owned(),
+                    new_key: None,
+                    value_size: None,
+                }),
+                LogEntry::Merge {
+                    key,
+                    operand,
+                    seq,
+                    time,
+                    ..
+                } => journal.push(JournalEntry {
+                    seq,
+                    time,
+                    op: JournalOp::Merge,
+                    key: String::from_utf8_lossy(&key).into_owned(),
+                    new_key: None,
+                    value_size: Some(operand.len()),
+                }),
+                LogEntry::Append {
+                    key,
+                    suffix,
+                    seq,
+                    time,
+                    ..
+                } => journal.push(JournalEntry {
+                    seq,
+                    time,
+                    op: JournalOp::Append,
+                    key: String::from_utf8_lossy(&key).into_owned(),
+                    new_key: None,
+                    value_size: Some(suffix.len()),
+                }),
+                LogEntry::Rename {
+                    from,
+                    to,
+                    seq,
+                    time,
+                    ..
+                } => journal.push(JournalEntry {
+                    seq,
+                    time,
+                    op: JournalOp::Rename,
+                    key: String::from_utf8_lossy(&from).into_owned(),
+                    new_key: Some(String::from_utf8_lossy(&to).into_owned()),
+                    value_size: None,
+                }),
+                LogEntry::Abort { token } => {
+                    prepared.remove(&token);
+                }
+                LogEntry::Blob {
+                    id,
+                    value,
+                    location,
+                    ..
+                } => {
+                    let size = location.map_or(value.len(), |location| {
+                        plain_len(location.len, location.sealed) as usize
+                    });
+                    blob_sizes.insert(id, size);
+                }
+                LogEntry::Checkpoint { .. } | LogEntry::Chunk { .. } | LogEntry::Sealed { .. } => {}
+            }
+        }
+
+        journal.retain(|entry| seqs.contains(&entry.seq));
+        Ok(journal)
+    }
+
+    /// Lists up to `limit` of the values a key held, newest first, reconstructed from the log
+    ///
+    /// The whole log is read. Compaction drops superseded values other than those kept by
+    /// `Options::retained_versions`.
+    pub fn history(&self, key: &str, limit: usize) -> Result<Vec<KeyVersion>> {
+        let key = key.as_bytes();
+        let mut reader = io::BufReader::new(self.log.reader());
+        let mut pointer = reader.seek(SeekFrom::Start(codec::records_start(&self.log)?))?;
+        let mut prepared = HashMap::new();
+        // Values are read once the scan is done, from a pointer into the log or, for writes
+        // made by a batch, from the values kept by sequence number
+        let mut writes: Vec<(u64, u64, Option<u64>)> = Vec::new();
+        let mut batch_values = HashMap::new();
+
+        while let Ok(entry) = codec::decode(self.options.format, &mut reader) {
+            let next = reader.stream_position()?;
+            match unseal_record(&self.options, entry)? {
+                LogEntry::Set {
+                    key: k, seq, time, ..
+                }
+                | LogEntry::Merge {
+                    key: k, seq, time, ..
+                }
+                | LogEntry::Append {
+                    key: k, seq, time, ..
+                } if k == key => writes.push((seq, time, Some(pointer))),
+                LogEntry::Remove {
+                    key: k, seq, time, ..
+                } if k == key => writes.push((seq, time, None)),
+                LogEntry::RemovePrefix { prefix, seq, time } if key.starts_with(&prefix) => {
+                    writes.push((seq, time, None))
+                }
+                LogEntry::Rename {
+                    from, seq, time, ..
+                } if from == key => writes.push((seq, time, None)),
+                LogEntry::Rename { to, seq, time, .. } if to == key => {
+                    writes.push((seq, time, Some(pointer)))
+                }
+                LogEntry::Prepare { token, ops } => {
+                    prepared.insert(token, ops);
+                }
+                LogEntry::Abort { token } => {
+                    prepared.remove(&token);
+                }
+                LogEntry::Commit { token, seq, time } => {
+                    let ops = prepared.remove(&token).unwrap_or_default();
+                    match ops.into_iter().rev().find_map(|op| match op {
+                        BatchOp::Set { key: k, value } if k == key => Some(Some(value)),
+                        BatchOp::Remove { key: k } if k == key => Some(None),
+                        _ => None,
+                    }) {
+                        Some(Some(value)) => {
+                            batch_values.insert(seq, value);
+                            writes.push((seq, time, None));
+                        }
+                        Some(None) => writes.push((seq, time, None)),
+                        None => {}
+                    }
+                }
+                _ => {}
+            }
+            pointer = next;
+        }
+
+        // Retained versions are written ahead of the live values by compaction
+        writes.sort_by_key(|&(seq, _, _)| seq);
+        writes
+            .into_iter()
+            .rev()
+            .take(limit)
+            .map(|(seq, time, pointer)| {
+                let value = match pointer {
+                    Some(pointer) => self.read_log_entry(key, pointer)?,
+                    None => batch_values.remove(&seq),
+                };
+                Ok(KeyVersion {
+                    seq,
+                    time,
+                    value: value.map(String::from_utf8).transpose()?,
+                })
+            })
+            .collect()
+    }
+}
@@ -1,40 +1,346 @@
-//! In-memory key-value store
-#![feature(seek_convenience)]
-#![feature(bind_by_move_pattern_guards)]
+//! Log-structured key-value store
+//!
+//! Every write is appended to a log on disk, and an index in memory points each key at its
+//! latest record. Compaction rewrites the log with only the live records. Large values can be
+//! kept apart in a value log, and stores can be backed up, restored, split into buckets or
+//! shards, or held in memory instead of on disk.
 #![deny(missing_docs)]
 
-extern crate failure;
-#[macro_use]
-extern crate failure_derive;
-extern crate lru;
+#[cfg(feature = "encryption")]
+extern crate aes_gcm;
+extern crate base64;
+extern crate csv;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate fs2;
+#[cfg(feature = "encryption")]
+extern crate getrandom;
+#[cfg(target_arch = "wasm32")]
+extern crate js_sys;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate memmap;
+#[cfg(feature = "python")]
+extern crate pyo3;
 extern crate rmp_serde;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
+extern crate serde_bytes;
+extern crate serde_json;
+#[cfg(feature = "compression")]
+extern crate snap;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate tempfile;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
-use lru::LruCache;
+/// Enters a `tracing` span at the given level until the end of the enclosing block, when the
+/// `tracing` feature is enabled; otherwise expands to nothing
+macro_rules! span {
+    ($level:ident, $name:expr $(, $field:ident = $value:expr)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::span!(tracing::Level::$level, $name $(, $field = $value)*).entered();
+    };
+}
+
+use cache::{BlockCache, BlockReader, ValueCache};
+use clock::{now_millis, Instant};
+use crypto::{seal_record, seal_value, unseal_record, unseal_value};
+#[cfg(not(target_arch = "wasm32"))]
+use fs2::FileExt;
+use hooks::EventHook;
+use log::Log;
+#[cfg(not(target_arch = "wasm32"))]
+use memmap::Mmap;
+use secondary::SecondaryIndex;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use slow::{Op, Phase, Phases};
+use stats::OpCounters;
+use std::cell::RefCell;
+use std::collections::hash_map::{self, DefaultHasher};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
 use std::io;
-use std::io::{Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use tempfile::TempDir;
+use throttle::Throttle;
+use tombstones::Tombstones;
+use versions::{changed_keys, Version, Versions};
+use watch::Watcher;
+
+pub use backup::RestorePoint;
+pub use cache::Eviction;
+pub use cdc::{Change, ChangeSink, JsonLinesSink};
+pub use codec::LogFormat;
+pub use dump::LogRecord;
+pub use entry::Entry;
+pub use export::{Export, ExportEntry};
+pub use hooks::StoreEvent;
+pub use iter::Iter;
+pub use journal::{JournalEntry, JournalOp, KeyVersion};
+pub use manager::{KvStoreManager, StoreHandle};
+pub use options::{Options, SyncPolicy};
+pub use order::natural_order;
+pub use shard::ShardedStore;
+pub use snapshot::Snapshot;
+pub use stats::{BucketUsage, OpStats, SizeHistogram, Stats};
+pub use storage::{MemoryStorage, Storage, StorageFile};
+pub use sweeper::ExpirationSweeper;
+pub use watch::{Watch, WatchEvent};
+
+mod backpressure;
+mod backup;
+mod cache;
+mod cdc;
+mod clock;
+mod codec;
+mod crypto;
+mod direct;
+mod dump;
+mod entry;
+mod export;
+mod hooks;
+mod iter;
+mod journal;
+mod lock;
+mod log;
+mod manager;
+mod options;
+mod order;
+mod prealloc;
+#[cfg(feature = "python")]
+mod python;
+mod quota;
+mod rdb;
+mod readahead;
+mod sample;
+mod secondary;
+mod shard;
+mod slow;
+mod snapshot;
+mod stats;
+mod storage;
+mod sweeper;
+mod throttle;
+mod tombstones;
+mod versions;
+mod warmup;
+mod watch;
 
 /// Custom error type
-#[derive(Fail, Debug)]
+#[derive(Debug)]
 pub enum KvError {
     /// I/O Error
-    #[fail(display = "IO error")]
-    IoError(#[cause] io::Error),
+    IoError(io::Error),
     /// Encode error
-    #[fail(display = "Encode error")]
-    EncodeError(#[cause] rmp_serde::encode::Error),
+    EncodeError(rmp_serde::encode::Error),
     /// Decode error
-    #[fail(display = "Decode error")]
-    DecodeError(#[cause] rmp_serde::decode::Error),
+    DecodeError(rmp_serde::decode::Error),
+    /// Typed value (de)serialization error
+    JsonError(serde_json::Error),
     /// Key not found error
-    #[fail(display = "Key not found")]
     KeyNotFound,
-    /// Unknown error
-    #[fail(display = "Unknown error")]
-    Unknown,
+    /// Key or value is not valid UTF-8
+    InvalidUtf8(std::string::FromUtf8Error),
+    /// Range does not fall on character boundaries of the value
+    InvalidRange,
+    /// Bucket or store name is empty or contains characters other than ASCII letters, digits, `-`
+    /// and `_`
+    InvalidBucketName,
+    /// Value is not an integer, or the result of incrementing it overflows
+    NotAnInteger,
+    /// CSV error
+    CsvError(csv::Error),
+    /// SQLite error
+    #[cfg(feature = "sqlite")]
+    SqliteError(rusqlite::Error),
+    /// Imported data is malformed
+    InvalidImport,
+    /// Merge attempted without a registered merge operator
+    NoMergeOperator,
+    /// Log is locked for writing by another open store
+    AlreadyLocked,
+    /// Write attempted on a store opened read-only
+    ReadOnly,
+    /// Stored value is compressed and could not be decompressed
+    Compression,
+    /// Record is encrypted and the store was opened without the right key
+    Encryption,
+    /// Key is longer than the configured maximum key size
+    KeyTooLarge,
+    /// Value is longer than the configured maximum value size
+    ValueTooLarge,
+    /// Write would take the store over its size quota
+    QuotaExceeded,
+    /// Sharded store opened with a different number of shards than it was created with
+    ShardCountMismatch,
+    /// Write refused because the log holds more stale bytes than the hard write stall limit
+    CompactionBackpressure,
+    /// Store opened with `Options::error_if_exists`, or created in a `KvStoreManager`, already
+    /// exists
+    StoreExists,
+    /// Store opened with `Options::create_if_missing` turned off, or looked up in a
+    /// `KvStoreManager`, does not exist
+    StoreNotFound,
+    /// Store of a `KvStoreManager` dropped while handles on it are still held
+    StoreInUse,
+    /// No secondary index is registered under the given name
+    IndexNotFound,
+    /// Prepared batch not found error
+    TransactionNotFound,
+    /// A record the index points at is missing or of the wrong kind
+    Corruption {
+        /// Position of the record in the log or value log
+        offset: u64,
+    },
+    /// A value refers to a deduplicated blob that is not in the log
+    MissingBlob(u64),
+    /// Log header names a version of the log format this build cannot read
+    UnsupportedLogVersion(u8),
+    /// Scan cursor was not returned by `scan_page`
+    InvalidCursor,
+    /// File of a backup is missing or does not match the checksum in the backup manifest
+    CorruptBackup(String),
+    /// An I/O, encoding or decoding error, with where it happened
+    Context {
+        /// Operation, key and location of the failure
+        context: ErrorContext,
+        /// Underlying error
+        source: Box<KvError>,
+    },
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvError::IoError(err) => write!(f, "IO error: {}", err),
+            KvError::EncodeError(err) => write!(f, "Encode error: {}", err),
+            KvError::DecodeError(err) => write!(f, "Decode error: {}", err),
+            KvError::JsonError(err) => write!(f, "JSON error: {}", err),
+            KvError::KeyNotFound => write!(f, "Key not found"),
+            KvError::InvalidUtf8(err) => write!(f, "Invalid UTF-8: {}", err),
+            KvError::InvalidRange => write!(f, "Invalid range"),
+            KvError::InvalidBucketName => write!(f, "Invalid bucket name"),
+            KvError::NotAnInteger => write!(f, "Value is not an integer or out of range"),
+            KvError::CsvError(err) => write!(f, "CSV error: {}", err),
+            #[cfg(feature = "sqlite")]
+            KvError::SqliteError(err) => write!(f, "SQLite error: {}", err),
+            KvError::InvalidImport => write!(f, "Invalid import data"),
+            KvError::NoMergeOperator => write!(f, "No merge operator registered"),
+            KvError::AlreadyLocked => write!(f, "Store is locked by another process"),
+            KvError::ReadOnly => write!(f, "Store is read-only"),
+            KvError::Compression => write!(f, "Compressed value cannot be read"),
+            KvError::Encryption => write!(f, "Encrypted record cannot be read"),
+            KvError::KeyTooLarge => write!(f, "Key too large"),
+            KvError::ValueTooLarge => write!(f, "Value too large"),
+            KvError::QuotaExceeded => write!(f, "Quota exceeded"),
+            KvError::ShardCountMismatch => write!(f, "Shard count does not match the store"),
+            KvError::CompactionBackpressure => {
+                write!(f, "Write refused until compaction reclaims stale records")
+            }
+            KvError::StoreExists => write!(f, "Store already exists"),
+            KvError::StoreNotFound => write!(f, "Store not found"),
+            KvError::StoreInUse => write!(f, "Store is still in use"),
+            KvError::IndexNotFound => write!(f, "Index not found"),
+            KvError::TransactionNotFound => write!(f, "Transaction not found"),
+            KvError::Corruption { offset } => write!(f, "Corrupt record at offset {}", offset),
+            KvError::MissingBlob(id) => write!(f, "Blob {} is missing from the log", id),
+            KvError::UnsupportedLogVersion(version) => write!(
+                f,
+                "Log format version {} is not supported; this build reads up to version {}",
+                version,
+                codec::HEADER_VERSION
+            ),
+            KvError::InvalidCursor => write!(f, "Invalid scan cursor"),
+            KvError::CorruptBackup(file) => {
+                write!(f, "Backup file {} does not match its manifest", file)
+            }
+            KvError::Context { context, source } => write!(f, "{} ({})", source, context),
+        }
+    }
+}
+
+impl std::error::Error for KvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KvError::IoError(err) => Some(err),
+            KvError::EncodeError(err) => Some(err),
+            KvError::DecodeError(err) => Some(err),
+            KvError::JsonError(err) => Some(err),
+            KvError::InvalidUtf8(err) => Some(err),
+            KvError::CsvError(err) => Some(err),
+            #[cfg(feature = "sqlite")]
+            KvError::SqliteError(err) => Some(err),
+            KvError::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl KvError {
+    /// Returns the error without any context attached to it
+    pub fn root(&self) -> &KvError {
+        match self {
+            KvError::Context { source, .. } => source.root(),
+            err => err,
+        }
+    }
+
+    /// Attaches context to I/O, encoding, decoding and corruption errors, leaving errors about
+    /// the request or the configuration, such as a missing key or a wrong encryption key, as
+    /// they are
+    pub(crate) fn with_context(self, context: impl FnOnce() -> ErrorContext) -> KvError {
+        match self {
+            KvError::IoError(_)
+            | KvError::EncodeError(_)
+            | KvError::DecodeError(_)
+            | KvError::Compression
+            | KvError::Corruption { .. }
+            | KvError::MissingBlob(_) => KvError::Context {
+                context: context(),
+                source: Box::new(self),
+            },
+            err => err,
+        }
+    }
+}
+
+/// Where an I/O, encoding or decoding error happened
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ErrorContext {
+    /// Operation that failed, such as `get`, `set`, `remove`, `open` or `compact`
+    pub operation: &'static str,
+    /// Key the operation was on, converted lossily to UTF-8
+    pub key: Option<String>,
+    /// File that was being read or written
+    pub path: Option<PathBuf>,
+    /// Position in the log of the record that was being read
+    pub offset: Option<u64>,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "during {}", self.operation)?;
+        if let Some(key) = &self.key {
+            write!(f, " of key {:?}", key)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, " in {}", path.display())?;
+        }
+        Ok(())
+    }
 }
 
 /// Alias for io results.
@@ -58,161 +364,2823 @@ impl From<rmp_serde::decode::Error> for KvError {
     }
 }
 
+impl From<serde_json::Error> for KvError {
+    fn from(err: serde_json::Error) -> KvError {
+        KvError::JsonError(err)
+    }
+}
+
+impl From<csv::Error> for KvError {
+    fn from(err: csv::Error) -> KvError {
+        KvError::CsvError(err)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for KvError {
+    fn from(err: rusqlite::Error) -> KvError {
+        KvError::SqliteError(err)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for KvError {
+    fn from(err: std::string::FromUtf8Error) -> KvError {
+        KvError::InvalidUtf8(err)
+    }
+}
+
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 #[serde(tag = "type")]
 enum LogEntry {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        #[serde(default)]
+        expires_at: Option<u64>,
+        #[serde(default)]
+        token: Option<String>,
+        #[serde(default)]
+        seq: u64,
+        #[serde(default)]
+        time: u64,
+        #[serde(default)]
+        created: u64,
+        #[serde(default)]
+        blob: Option<u64>,
+        #[serde(default)]
+        chunks: Vec<ChunkRef>,
+        #[serde(default)]
+        separated: Option<ChunkRef>,
+        #[serde(default)]
+        compressed: bool,
+    },
+    Remove {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(default)]
+        token: Option<String>,
+        #[serde(default)]
+        seq: u64,
+        #[serde(default)]
+        time: u64,
+    },
+    Prepare {
+        token: u64,
+        ops: Vec<BatchOp>,
+    },
+    Commit {
+        token: u64,
+        #[serde(default)]
+        seq: u64,
+        #[serde(default)]
+        time: u64,
+    },
+    Abort {
+        token: u64,
+    },
+    Checkpoint {
+        seq: u64,
+        tokens: Vec<String>,
+    },
+    Blob {
+        id: u64,
+        hash: u64,
+        /// Value of a blob written before blobs moved to the value log, empty otherwise
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        #[serde(default)]
+        location: Option<ChunkRef>,
+    },
+    Chunk {
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    RemovePrefix {
+        #[serde(with = "serde_bytes")]
+        prefix: Vec<u8>,
+        seq: u64,
+        time: u64,
+    },
+    Merge {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        operand: Vec<u8>,
+        base: Option<u64>,
+        seq: u64,
+        time: u64,
+    },
+    Rename {
+        #[serde(with = "serde_bytes")]
+        from: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        to: Vec<u8>,
+        base: u64,
+        seq: u64,
+        time: u64,
+    },
+    Append {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        suffix: Vec<u8>,
+        base: Option<u64>,
+        seq: u64,
+        time: u64,
+    },
+    /// Any other record, encrypted with the key of the store
+    Sealed {
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+}
+
+/// Combines the current value of a key, if any, with a merge operand into the new value
+type MergeOperator = Arc<dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync>;
+
+/// Location of one piece of a value that is split across several records, or of a value held
+/// in the value log
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+struct ChunkRef {
+    pointer: u64,
+    len: u64,
+    /// Whether the bytes in the value log are encrypted
+    #[serde(default)]
+    sealed: bool,
+}
+
+/// A value shared by the keys that store identical values, when de-duplication is enabled
+#[derive(Debug, Clone, Copy)]
+struct BlobEntry {
+    /// Position of the record describing the blob in the log
+    pointer: u64,
+    hash: u64,
+    /// Location of the value in the value log, or `None` if the record holds it
+    location: Option<ChunkRef>,
+}
+
+/// Values larger than this are split into chunks of at most this many bytes
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "op")]
+enum BatchOp {
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+}
+
+/// A group of writes that are applied together
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Adds a set operation to the batch
+    pub fn set(&mut self, key: String, value: String) -> &mut WriteBatch {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Adds a set operation with a binary key and value to the batch
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set { key, value });
+        self
+    }
+
+    /// Adds a remove operation to the batch
+    pub fn remove(&mut self, key: String) -> &mut WriteBatch {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Adds a remove operation for a binary key to the batch
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Remove { key });
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PreparedBatch {
+    pointer: u64,
+    ops: Vec<BatchOp>,
+}
+
+/// Bounded, insertion-ordered set of recently applied idempotency tokens
+#[derive(Debug)]
+struct RecentTokens {
+    order: VecDeque<String>,
+    seen: HashSet<String>,
+    capacity: usize,
+}
+
+impl RecentTokens {
+    fn new(capacity: usize) -> RecentTokens {
+        RecentTokens {
+            order: VecDeque::with_capacity(capacity),
+            seen: HashSet::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    fn contains(&self, token: &str) -> bool {
+        self.seen.contains(token)
+    }
+
+    fn extend<I: IntoIterator<Item = String>>(&mut self, tokens: I) {
+        for token in tokens {
+            self.insert(token);
+        }
+    }
+
+    fn insert(&mut self, token: String) {
+        if self.seen.contains(&token) {
+            return;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(token.clone());
+        self.order.push_back(token);
+    }
+}
+
+/// Metadata about a stored key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyMetadata {
+    /// Time the key was first set, in milliseconds since the Unix epoch
+    pub created: u64,
+    /// Time the key was last modified, in milliseconds since the Unix epoch
+    pub modified: u64,
+    /// Time the key expires, in milliseconds since the Unix epoch
+    pub expires_at: Option<u64>,
+    /// Sequence number of the last write to the key
+    pub seq: u64,
+}
+
+/// Result of a conditional get
+#[derive(Debug, Clone, PartialEq)]
+pub enum Conditional {
+    /// The key has changed, with its current value and metadata
+    Modified(String, KeyMetadata),
+    /// The key has not changed, so its value was not read
+    NotModified,
+    /// The key is not in the store
+    NotFound,
+}
+
+/// Where the live entry of a key is stored, as returned by `KvStore::metadata`
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyLocation {
+    /// Times and sequence number of the entry
+    pub meta: KeyMetadata,
+    /// Log file holding the entry, empty for a store held in memory
+    pub path: PathBuf,
+    /// Position of the entry's first record in the log
+    pub offset: u64,
+    /// Bytes of log records needed to read the value, excluding shared blobs
+    pub len: u64,
+    /// Bytes of the value held in the value log rather than the main log
+    pub separated: u64,
+    /// Identifier of the deduplicated blob holding the value, if any
+    pub blob: Option<u64>,
+    /// Whether the value is held in the value cache
+    pub cached: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    pointer: u64,
+    /// Bytes of log records needed to read the value, excluding shared blobs
+    len: u64,
+    expires_at: Option<u64>,
+    seq: u64,
+    time: u64,
+    created: u64,
+    blob: Option<u64>,
+    /// Bytes of the value held in the value log rather than the main log
+    separated: u64,
+}
+
+impl IndexEntry {
+    fn metadata(&self) -> KeyMetadata {
+        KeyMetadata {
+            created: self.created,
+            modified: self.time,
+            expires_at: self.expires_at,
+            seq: self.seq,
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        match self.expires_at {
+            Some(t) => t <= now,
+            None => false,
+        }
+    }
+}
+
+fn apply_batch(
+    index: &mut BTreeMap<Vec<u8>, IndexEntry>,
+    pointer: u64,
+    ops: &[BatchOp],
+    seq: u64,
+    time: u64,
+) {
+    for op in ops {
+        match op {
+            BatchOp::Set { key, value } => {
+                let created = index.get(key).map_or(time, |entry| entry.created);
+                let entry = IndexEntry {
+                    pointer,
+                    len: (key.len() + value.len()) as u64,
+                    expires_at: None,
+                    seq,
+                    time,
+                    created,
+                    blob: None,
+                    separated: 0,
+                };
+                index.insert(key.clone(), entry);
+            }
+            BatchOp::Remove { key } => {
+                index.remove(key);
+            }
+        }
+    }
+}
+
+fn record_batch_removals(tombstones: &mut Tombstones, ops: &[BatchOp], seq: u64, time: u64) {
+    for op in ops {
+        if let BatchOp::Remove { key } = op {
+            tombstones.record(key.clone(), seq, time);
+        }
+    }
+}
+
+/// Returns whether a bucket or store name is non-empty and only has ASCII letters, digits, `-`
+/// and `_`
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Returns whether a record the index points at for a key holds a value of that key
+fn holds_key(entry: &LogEntry, key: &[u8]) -> bool {
+    match entry {
+        LogEntry::Set { key: k, .. }
+        | LogEntry::Merge { key: k, .. }
+        | LogEntry::Append { key: k, .. }
+        | LogEntry::Rename { to: k, .. } => k == key,
+        LogEntry::Prepare { ops, .. } => ops.iter().any(|op| match op {
+            BatchOp::Set { key: k, .. } => k == key,
+            BatchOp::Remove { .. } => false,
+        }),
+        _ => false,
+    }
+}
+
+fn keys_with_prefix(index: &BTreeMap<Vec<u8>, IndexEntry>, prefix: &[u8]) -> Vec<Vec<u8>> {
+    index
+        .range(prefix.to_vec()..)
+        .map(|(k, _)| k)
+        .take_while(|k| k.starts_with(prefix))
+        .cloned()
+        .collect()
+}
+
+/// Converts a range of string keys to the equivalent range of byte keys
+fn byte_bound(bound: Bound<&String>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.clone().into_bytes()),
+        Bound::Excluded(key) => Bound::Excluded(key.clone().into_bytes()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Returns up to `len` bytes of a value starting at `offset`, clamped to the end of the value
+fn slice_value(value: &[u8], offset: usize, len: usize) -> Vec<u8> {
+    let start = offset.min(value.len());
+    let end = offset.saturating_add(len).min(value.len());
+    value[start..end].to_vec()
+}
+
+/// Returns the value held inline in a record, decompressing it if needed
+fn decode_value(value: Vec<u8>, compressed: bool) -> Result<Vec<u8>> {
+    if !compressed {
+        return Ok(value);
+    }
+    #[cfg(feature = "compression")]
+    {
+        snap::raw::Decoder::new()
+            .decompress_vec(&value)
+            .map_err(|_| KvError::Compression)
+    }
+    #[cfg(not(feature = "compression"))]
+    Err(KvError::Compression)
+}
+
+/// Returns the length of the value held inline in a record, without decompressing it
+///
+/// Compressed values start with their uncompressed length as a varint, so this works even
+/// without the `compression` feature.
+fn decoded_len(value: &[u8], compressed: bool) -> usize {
+    if !compressed {
+        return value.len();
+    }
+    let mut len = 0;
+    for (i, byte) in value.iter().take(10).enumerate() {
+        len |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    len
+}
+
+fn hash_value(value: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Starting value of an FNV-1a hash, the hash of no bytes
+pub(crate) const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Continues an FNV-1a hash over more bytes; used where hashes are persisted and must not change
+/// between builds
+pub(crate) fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn open_append(path: &Path, direct_io: bool) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.read(true).append(true).create(true);
+    direct::open(&mut options, path, direct_io)
+}
+
+/// Finishes a compaction that was interrupted after replacing the log but before replacing the
+/// value log it refers to, or discards the new value log if the log was never replaced
+fn recover_value_log(options: &Options, path: &Path) -> Result<()> {
+    let pending = path.with_extension("vlog.new");
+    if options.file_exists(&pending) {
+        if options.file_exists(&path.with_extension("bak")) {
+            options.remove_file(&pending)?;
+        } else {
+            options.rename_file(&pending, &path.with_extension("vlog"))?;
+            options.sync_dir(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks that every entry of an index points at a record of its key in a log
+fn verify_index(log: &Log, index: &BTreeMap<Vec<u8>, IndexEntry>, options: &Options) -> Result<()> {
+    let mut reader = io::BufReader::new(log.reader());
+    for (key, entry) in index {
+        reader.seek(SeekFrom::Start(entry.pointer))?;
+        let record = unseal_record(options, codec::decode(options.format, &mut reader)?)?;
+        if !holds_key(&record, key) {
+            return Err(KvError::Corruption {
+                offset: entry.pointer,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Opens a log for appending, taking an exclusive advisory lock on it
+#[cfg(not(target_arch = "wasm32"))]
+fn open_locked(path: &Path, direct_io: bool) -> Result<File> {
+    let log = open_append(path, direct_io)?;
+    match log.try_lock_exclusive() {
+        Ok(()) => Ok(log),
+        Err(ref err) if err.kind() == fs2::lock_contended_error().kind() => {
+            Err(KvError::AlreadyLocked)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// There is no memory mapping or temporary directory on wasm32, so stores there never hold one
+#[cfg(target_arch = "wasm32")]
+type Mmap = std::convert::Infallible;
+#[cfg(target_arch = "wasm32")]
+type TempDir = std::convert::Infallible;
+
+/// Opens a log for appending; there are no other processes to lock it against on wasm32
+#[cfg(target_arch = "wasm32")]
+fn open_locked(path: &Path, direct_io: bool) -> Result<File> {
+    Ok(open_append(path, direct_io)?)
 }
 
 /// Implements a KV store
 pub struct KvStore {
     path: PathBuf,
-    log: File,
-    index: HashMap<String, u64>,
-    cache: LruCache<String, String>,
+    log: Log,
+    values: Option<Log>,
+    index: BTreeMap<Vec<u8>, IndexEntry>,
+    cache: ValueCache,
+    block_cache: Option<RefCell<BlockCache>>,
+    mapping: RefCell<Option<Mmap>>,
+    prepared: HashMap<u64, PreparedBatch>,
+    last_token: u64,
+    tokens: RecentTokens,
+    seq: u64,
+    blobs: HashMap<u64, BlobEntry>,
+    blob_hashes: HashMap<u64, u64>,
+    last_blob: u64,
+    buckets: HashMap<String, KvStore>,
+    merge_operator: Option<MergeOperator>,
+    cache_hits: u64,
+    cache_misses: u64,
+    compactions: u64,
+    op_counters: OpCounters,
+    phases: Phases,
     compaction_counter: u32,
+    stale_bytes: (u64, u64),
+    stalls: u64,
+    evictions: u64,
+    /// Last read of each key, kept only when quota eviction is enabled
+    accessed: HashMap<Vec<u8>, u64>,
+    watchers: Vec<Watcher>,
+    sinks: Vec<Box<dyn ChangeSink>>,
+    hooks: Vec<EventHook>,
+    secondary: HashMap<String, SecondaryIndex>,
+    versions: Versions,
+    tombstones: Tombstones,
+    options: Options,
+    // Declared last so that the log files are closed before the directory is removed
+    temp_dir: Option<TempDir>,
 }
 
 impl KvStore {
     /// Opens an existing database
     pub fn open(path: &Path) -> Result<KvStore> {
-        let path = if path.is_dir() {
+        KvStore::open_until(path, None, Options::default())
+    }
+
+    /// Opens an existing database for reading only
+    ///
+    /// The store never writes or compacts and does not take the write lock, so it can be opened
+    /// while another process is writing. It sees the contents of the log as of opening.
+    pub fn open_read_only(path: &Path) -> Result<KvStore> {
+        KvStore::options().read_only(true).open(path)
+    }
+
+    /// Creates an empty store held entirely in memory
+    ///
+    /// It supports the same operations as a store opened from a file, including buckets,
+    /// snapshots, compaction and backups to disk, but its contents are lost when it is dropped.
+    pub fn in_memory() -> Result<KvStore> {
+        KvStore::options().open_in_memory()
+    }
+
+    /// Creates an empty store in a new temporary directory that is deleted when the store is
+    /// dropped, along with any buckets opened through it
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn temporary() -> Result<KvStore> {
+        let temp_dir = TempDir::new()?;
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.temp_dir = Some(temp_dir);
+        Ok(store)
+    }
+
+    /// Returns the path of the log file, which is empty for a store held in memory
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opens a database, replaying only the writes up to the given point
+    fn open_until(path: &Path, until: Option<RestorePoint>, options: Options) -> Result<KvStore> {
+        KvStore::open_log(path, until, options).map_err(|err| {
+            err.with_context(|| ErrorContext {
+                operation: "open",
+                path: Some(path.to_path_buf()),
+                ..ErrorContext::default()
+            })
+        })
+    }
+
+    fn open_log(path: &Path, until: Option<RestorePoint>, options: Options) -> Result<KvStore> {
+        let path = if options.storage.is_none() && path.is_dir() {
             path.join("data.log")
         } else {
             path.to_path_buf()
         };
 
-        let mut log = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(path.as_path())?;
+        let exists = options.file_exists(&path);
+        if exists && options.error_if_exists {
+            return Err(KvError::StoreExists);
+        }
+        if !exists && !options.create_if_missing {
+            return Err(KvError::StoreNotFound);
+        }
+
+        let values_path = path.with_extension("vlog");
+        let (log, values) = if options.read_only {
+            let values = match options.open_existing(&values_path) {
+                Ok(values) => Some(values),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            };
+            (options.open_existing(&path)?, values)
+        } else {
+            let log = options.open_log_file(&path, true)?;
+            recover_value_log(&options, &path)?;
+            let values = if options.file_exists(&values_path) {
+                Some(options.open_log_file(&values_path, false)?)
+            } else {
+                None
+            };
+            (log, values)
+        };
+        let mut store = KvStore::load(path, log, values, until, options)?;
+        store.warm_cache();
+        Ok(store)
+    }
+
+    /// Builds a store by replaying a log, stopping after the given point if there is one
+    fn load(
+        path: PathBuf,
+        log: Log,
+        values: Option<Log>,
+        until: Option<RestorePoint>,
+        mut options: Options,
+    ) -> Result<KvStore> {
+        // The format of an existing log is the one in its header, or MessagePack for logs from
+        // before there were headers, whatever the options ask for
+        let start = match codec::read_header(&log)? {
+            Some(format) => {
+                options.format = format;
+                codec::HEADER_LEN
+            }
+            None if log.len()? == 0 && !options.read_only => {
+                codec::write_header(log.writer(), options.format)?;
+                codec::HEADER_LEN
+            }
+            None => {
+                options.format = LogFormat::MessagePack;
+                0
+            }
+        };
+        let mut reader = io::BufReader::new(log.reader());
+        let mut pointer = reader.seek(SeekFrom::Start(start))?;
+        let mut index: BTreeMap<Vec<u8>, IndexEntry> = BTreeMap::new();
+        let mut prepared: HashMap<u64, PreparedBatch> = HashMap::new();
+        let mut last_token = 0;
+        let mut tokens = RecentTokens::new(1000);
+        let mut last_seq = 0;
+        let mut blobs = HashMap::new();
+        let mut blob_hashes = HashMap::new();
+        let mut last_blob = 0;
+        let mut versions = Versions::new(options.retained_versions);
+        let mut tombstones = Tombstones::default();
+        let now = now_millis();
 
-        let mut reader = io::BufReader::new(&mut log);
-        let mut pointer = reader.stream_position()?;
-        let mut index: HashMap<String, u64> = HashMap::new();
+        let log_len = log.len()?;
 
-        while let Ok(entry) = rmp_serde::decode::from_read(&mut reader) {
+        loop {
+            let entry = match codec::decode(options.format, &mut reader) {
+                Ok(entry) => entry,
+                // The log ends at the first record that cannot be decoded, which is usually one
+                // torn by a crash, unless damage is to be reported
+                Err(_) if options.strict_open && pointer < log_len => {
+                    return Err(KvError::Corruption { offset: pointer });
+                }
+                Err(_) => break,
+            };
+            let entry = unseal_record(&options, entry)?;
+            let next = reader.stream_position()?;
+            let len = next - pointer;
+            if let Some(point) = until {
+                if !point.includes(&entry) {
+                    pointer = next;
+                    continue;
+                }
+            }
+            let (changed, seq, time) = if options.retained_versions > 0 {
+                changed_keys(&entry, &index, &prepared)
+            } else {
+                (Vec::new(), 0, 0)
+            };
+            let captured = versions.capture(&index, changed);
             match entry {
-                LogEntry::Remove { key } => index.remove(&key),
-                LogEntry::Set { key, .. } => index.insert(key, pointer),
+                LogEntry::Remove {
+                    key,
+                    token,
+                    seq,
+                    time,
+                } => {
+                    index.remove(&key);
+                    tokens.extend(token);
+                    last_seq = last_seq.max(seq);
+                    tombstones.record(key, seq, time);
+                }
+                LogEntry::Set {
+                    key,
+                    expires_at,
+                    token,
+                    seq,
+                    time,
+                    created,
+                    blob,
+                    chunks,
+                    separated,
+                    ..
+                } => {
+                    tokens.extend(token);
+                    last_seq = last_seq.max(seq);
+                    let entry = IndexEntry {
+                        pointer,
+                        len: len + chunks.iter().map(|chunk| chunk.len).sum::<u64>(),
+                        expires_at,
+                        seq,
+                        time,
+                        created: if created == 0 { time } else { created },
+                        blob,
+                        separated: separated.map_or(0, |location| location.len),
+                    };
+                    if entry.is_expired(now) {
+                        index.remove(&key);
+                    } else {
+                        index.insert(key, entry);
+                    }
+                }
+                LogEntry::Prepare { token, ops } => {
+                    last_token = last_token.max(token);
+                    prepared.insert(token, PreparedBatch { pointer, ops });
+                }
+                LogEntry::Commit { token, seq, time } => {
+                    last_seq = last_seq.max(seq);
+                    if let Some(batch) = prepared.remove(&token) {
+                        apply_batch(&mut index, batch.pointer, &batch.ops, seq, time);
+                        record_batch_removals(&mut tombstones, &batch.ops, seq, time);
+                    }
+                }
+                LogEntry::Abort { token } => {
+                    prepared.remove(&token);
+                }
+                LogEntry::Checkpoint {
+                    seq,
+                    tokens: applied,
+                } => {
+                    last_seq = last_seq.max(seq);
+                    tokens.extend(applied);
+                }
+                LogEntry::Blob {
+                    id, hash, location, ..
+                } => {
+                    last_blob = last_blob.max(id);
+                    blobs.insert(
+                        id,
+                        BlobEntry {
+                            pointer,
+                            hash,
+                            location,
+                        },
+                    );
+                    blob_hashes.insert(hash, id);
+                }
+                LogEntry::RemovePrefix { prefix, seq, .. } => {
+                    last_seq = last_seq.max(seq);
+                    for key in keys_with_prefix(&index, &prefix) {
+                        index.remove(&key);
+                    }
+                }
+                LogEntry::Merge { key, seq, time, .. }
+                | LogEntry::Append { key, seq, time, .. } => {
+                    last_seq = last_seq.max(seq);
+                    let previous = index.get(&key).cloned();
+                    let entry = IndexEntry {
+                        pointer,
+                        len: len + previous.map_or(0, |entry| entry.len),
+                        expires_at: previous.and_then(|entry| entry.expires_at),
+                        seq,
+                        time,
+                        created: previous.map_or(time, |entry| entry.created),
+                        blob: None,
+                        separated: previous.map_or(0, |entry| entry.separated),
+                    };
+                    if entry.is_expired(now) {
+                        index.remove(&key);
+                    } else {
+                        index.insert(key, entry);
+                    }
+                }
+                LogEntry::Rename {
+                    from,
+                    to,
+                    seq,
+                    time,
+                    ..
+                } => {
+                    last_seq = last_seq.max(seq);
+                    if let Some(entry) = index.remove(&from) {
+                        let entry = IndexEntry {
+                            pointer,
+                            len: len + entry.len,
+                            seq,
+                            time,
+                            ..entry
+                        };
+                        index.insert(to, entry);
+                    }
+                }
+                LogEntry::Chunk { .. } | LogEntry::Sealed { .. } => {}
             };
-            pointer = reader.stream_position()?;
+            versions.record(&index, captured, seq, time);
+            pointer = next;
         }
 
         Ok(KvStore {
             path,
             log,
+            values,
             index,
-            cache: LruCache::new(100),
+            cache: options.new_value_cache(),
+            block_cache: options.new_block_cache(),
+            mapping: RefCell::new(None),
+            prepared,
+            last_token,
+            tokens,
+            seq: last_seq,
+            blobs,
+            blob_hashes,
+            last_blob,
+            buckets: HashMap::new(),
+            merge_operator: None,
+            cache_hits: 0,
+            cache_misses: 0,
+            compactions: 0,
+            op_counters: OpCounters::default(),
+            phases: Phases::default(),
             compaction_counter: 0,
+            stale_bytes: (0, 0),
+            stalls: 0,
+            evictions: 0,
+            accessed: HashMap::new(),
+            watchers: Vec::new(),
+            sinks: Vec::new(),
+            hooks: Vec::new(),
+            secondary: HashMap::new(),
+            versions,
+            tombstones,
+            options,
+            temp_dir: None,
         })
     }
 
     /// Retrieve the value for a key
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Retrieve the value for a binary key
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        span!(INFO, "kvs.get", key_len = key.len());
+        let start = self.start_op();
+        let result = self.read_value(key);
+        self.finish_op(Op::Read, start);
+        result.map_err(|err| err.with_context(|| self.error_context("get", key)))
+    }
+
+    fn read_value(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let entry = match self.live_entry(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        let key = key.to_vec();
         if let Some(value) = self.cache.get(&key) {
-            return Ok(Some(value.to_string()));
+            self.cache_hits += 1;
+            return Ok(Some(value.clone()));
         }
+        self.cache_misses += 1;
 
-        if let Some(pointer) = self.index.get(&key) {
-            let res = self.read_log_entry(*pointer)?;
-            return Ok(res.map(|v| {
-                self.cache.put(key, v.clone());
-                v
-            }));
+        let res = self.read_log_entry(&key, entry.pointer)?;
+        if let Some(v) = &res {
+            self.cache_value(key, v.clone());
+            self.read_ahead(entry.pointer);
         }
+        Ok(res)
+    }
 
-        Ok(None)
+    /// Retrieve the value for a key, computing and storing it first if the key is missing
+    pub fn get_or_insert_with<F>(&mut self, key: String, f: F) -> Result<String>
+    where
+        F: FnOnce() -> String,
+    {
+        if let Some(value) = self.get(key.clone())? {
+            return Ok(value);
+        }
+        let value = f();
+        self.write_value(key.into_bytes(), value.clone().into_bytes(), None, None)?;
+        Ok(value)
     }
 
-    fn read_log_entry(&self, pointer: u64) -> Result<Option<String>> {
-        let mut reader = io::BufReader::new(&self.log);
-        reader.seek(SeekFrom::Start(pointer))?;
-        let entry: LogEntry = rmp_serde::decode::from_read(&mut reader)?;
-        match entry {
-            LogEntry::Remove { .. } => Ok(None),
-            LogEntry::Set { value, .. } => Ok(Some(value)),
+    /// Retrieve the value for a key, deserialized from JSON
+    pub fn get_as<T: DeserializeOwned>(&mut self, key: String) -> Result<Option<T>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
         }
     }
 
-    /// Set the value for a key
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        match self.get(key.clone()) {
-            Ok(Some(v)) if v == value => Ok(()),
-            _ => {
-                let entry = LogEntry::Set {
-                    key: key.clone(),
-                    value: value.clone(),
-                };
-                let pointer = self.append_to_log(&entry)?;
-                for _ in self.index.insert(key.clone(), pointer).iter() {
-                    self.compact()?;
-                }
-                self.cache.put(key.clone(), value);
-                Ok(())
-            }
+    /// Retrieve up to `len` bytes of the value for a key, starting at byte `offset`
+    ///
+    /// Only the chunks overlapping the range are read for values stored in chunks. The range
+    /// is clamped to the end of the value and must fall on character boundaries.
+    pub fn get_range(&mut self, key: String, offset: usize, len: usize) -> Result<Option<String>> {
+        match self.get_range_bytes(key.as_bytes(), offset, len)? {
+            Some(value) => String::from_utf8(value)
+                .map(Some)
+                .map_err(|_| KvError::InvalidRange),
+            None => Ok(None),
         }
     }
 
-    /// Delete a key
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        match self.index.remove(&key) {
-            None => Err(KvError::KeyNotFound),
-            Some(_) => {
-                self.cache.pop(&key);
-                let entry = LogEntry::Remove { key };
-                self.append_to_log(&entry).map(|_| ())?;
-                self.compact()
+    /// Retrieve up to `len` bytes of the value for a binary key, starting at byte `offset`
+    pub fn get_range_bytes(
+        &mut self,
+        key: &[u8],
+        offset: usize,
+        len: usize,
+    ) -> Result<Option<Vec<u8>>> {
+        let entry = match self.live_entry(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        if let Some(value) = self.cache.get(key) {
+            return Ok(Some(slice_value(value, offset, len)));
+        }
+
+        let record = self.read_record(entry.pointer)?;
+        let record = self.check_key(key, entry.pointer, record)?;
+        let chunks = match record {
+            LogEntry::Set {
+                separated: Some(location),
+                ..
+            } if location.sealed => {
+                let value = self.read_separated(location)?;
+                return Ok(Some(slice_value(&value, offset, len)));
+            }
+            LogEntry::Set {
+                separated: Some(location),
+                ..
+            } => {
+                let start = (offset as u64).min(location.len);
+                let end = (offset as u64).saturating_add(len as u64).min(location.len);
+                let range = ChunkRef {
+                    pointer: location.pointer + start,
+                    len: end - start,
+                    sealed: false,
+                };
+                return self.read_separated(range).map(Some);
+            }
+            LogEntry::Set { ref chunks, .. } if !chunks.is_empty() => chunks.clone(),
+            _ => {
+                let value = self.entry_value(key, record)?;
+                return Ok(value.map(|v| slice_value(&v, offset, len)));
+            }
+        };
+
+        let end = offset.saturating_add(len);
+        let mut value = Vec::new();
+        let mut value_start = None;
+        let mut chunk_start = 0;
+        for chunk in chunks {
+            let chunk_end = chunk_start + chunk.len as usize;
+            if chunk_end > offset && chunk_start < end {
+                value.extend(self.read_chunk(chunk.pointer)?);
+                value_start.get_or_insert(chunk_start);
             }
+            chunk_start = chunk_end;
+        }
+        match value_start {
+            Some(start) => Ok(Some(slice_value(&value, offset - start, len))),
+            None => Ok(Some(Vec::new())),
         }
     }
 
-    fn append_to_log(&mut self, entry: &LogEntry) -> Result<u64> {
-        self.log.seek(SeekFrom::End(0))?;
-        let pointer = self.log.stream_position()?;
-        rmp_serde::encode::write(&mut self.log, entry)?;
-        Ok(pointer)
+    /// Retrieve the value for a key along with its metadata
+    pub fn get_with_meta(&mut self, key: String) -> Result<Option<(String, KeyMetadata)>> {
+        let meta = match self.live_entry(key.as_bytes()) {
+            Some(entry) => entry.metadata(),
+            None => return Ok(None),
+        };
+        Ok(self.get(key)?.map(|value| (value, meta)))
     }
 
-    fn compact(&mut self) -> Result<()> {
-        self.compaction_counter += 1;
-        if self.compaction_counter > 1000 {
-            let old_path = self.path.as_path();
-            let new_path = self.path.with_extension("bak");
-            {
-                let mut new_log = File::create(&new_path)?;
-                let mut compactor = io::BufWriter::new(&mut new_log);
-                for (key, ptr) in &self.index {
-                    if let Some(value) = self.read_log_entry(*ptr)? {
-                        let entry = LogEntry::Set {
-                            key: key.to_string(),
-                            value,
-                        };
-                        rmp_serde::encode::write(&mut compactor, &entry)?;
-                    }
-                }
-            }
+    /// Retrieve the value for a key only if it was modified after the given time, in
+    /// milliseconds since the Unix epoch
+    pub fn get_if_modified_since(&mut self, key: String, since: u64) -> Result<Conditional> {
+        self.get_unless(key, |meta| meta.modified <= since)
+    }
+
+    /// Retrieve the value for a key only if its version, the sequence number of its last write
+    /// as found in `KeyMetadata::seq`, differs from the one given
+    ///
+    /// Callers keeping a copy of a large value can pass the version of their copy to avoid
+    /// reading the value again while it is current.
+    pub fn get_if_version_differs(&mut self, key: String, version: u64) -> Result<Conditional> {
+        self.get_unless(key, |meta| meta.seq == version)
+    }
 
-            std::mem::drop(&self.log);
-            std::fs::rename(&new_path, &old_path)?;
-            self.log = OpenOptions::new()
-                .read(true)
-                .append(true)
-                .create(true)
-                .open(&old_path)?;
-            self.path = old_path.to_path_buf();
-            self.compaction_counter = 0;
+    fn get_unless(
+        &mut self,
+        key: String,
+        unchanged: impl FnOnce(&KeyMetadata) -> bool,
+    ) -> Result<Conditional> {
+        let meta = match self.live_entry(key.as_bytes()) {
+            Some(entry) => entry.metadata(),
+            None => return Ok(Conditional::NotFound),
+        };
+        if unchanged(&meta) {
+            return Ok(Conditional::NotModified);
         }
-        Ok(())
+        Ok(match self.get(key)? {
+            Some(value) => Conditional::Modified(value, meta),
+            None => Conditional::NotFound,
+        })
     }
-}
 
-/*
-impl Drop for KvStore {
-    fn drop(&mut self) {
-        self.compact().unwrap();
+    /// Describes where the live entry of a key is stored, without reading its value
+    pub fn metadata(&self, key: &str) -> Option<KeyLocation> {
+        let entry = self.index.get(key.as_bytes())?;
+        if entry.is_expired(now_millis()) {
+            return None;
+        }
+        Some(KeyLocation {
+            meta: entry.metadata(),
+            path: self.path.clone(),
+            offset: entry.pointer,
+            len: entry.len,
+            separated: entry.separated,
+            blob: entry.blob,
+            cached: self.cache.peek(key.as_bytes()).is_some(),
+        })
+    }
+
+    /// Returns whether a live value is stored for a key, without reading it
+    pub fn contains_key(&self, key: &str) -> bool {
+        match self.index.get(key.as_bytes()) {
+            Some(entry) => !entry.is_expired(now_millis()),
+            None => false,
+        }
+    }
+
+    /// Returns all live keys in key order, using the comparator of the store if it has one
+    ///
+    /// Keys that are not valid UTF-8 are converted lossily; use `keys_bytes` to list them as is.
+    pub fn keys(&self) -> impl Iterator<Item = String> + '_ {
+        let mut keys: Vec<&[u8]> = self.keys_bytes().collect();
+        if let Some(comparator) = &self.options.comparator {
+            comparator.sort(&mut keys);
+        }
+        keys.into_iter()
+            .map(|key| String::from_utf8_lossy(key).into_owned())
+    }
+
+    /// Returns all live keys in byte order, as bytes
+    pub fn keys_bytes(&self) -> impl Iterator<Item = &[u8]> {
+        let now = now_millis();
+        self.index
+            .iter()
+            .filter(move |(_, entry)| !entry.is_expired(now))
+            .map(|(key, _)| key.as_slice())
+    }
+
+    /// Returns the number of live keys
+    pub fn len(&self) -> usize {
+        self.keys_bytes().count()
+    }
+
+    /// Returns whether the store has no live keys
+    pub fn is_empty(&self) -> bool {
+        self.keys_bytes().next().is_none()
+    }
+
+    /// Returns a rough estimate of the memory used by the in-memory index, in bytes
+    pub fn estimated_index_size(&self) -> usize {
+        let per_entry = mem::size_of::<Vec<u8>>() + mem::size_of::<IndexEntry>();
+        self.index
+            .keys()
+            .map(|key| key.capacity() + per_entry)
+            .sum()
+    }
+
+    /// Retrieve all key-value pairs within a range of keys, in key order
+    ///
+    /// With a comparator set by `Options::comparator`, both the range and the order of the
+    /// results follow the comparator, and every key is checked against the range.
+    pub fn scan(&mut self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        let range = (
+            byte_bound(range.start_bound()),
+            byte_bound(range.end_bound()),
+        );
+        let keys: Vec<Vec<u8>> = match &self.options.comparator {
+            Some(comparator) => {
+                let mut keys: Vec<Vec<u8>> = self
+                    .index
+                    .keys()
+                    .filter(|key| comparator.contains(&range, key))
+                    .cloned()
+                    .collect();
+                comparator.sort(&mut keys);
+                keys
+            }
+            None => self.index.range(range).map(|(k, _)| k.clone()).collect(),
+        };
+        self.get_all(keys)
+    }
+
+    /// Retrieve all key-value pairs whose key starts with the given prefix, in key order
+    pub fn scan_prefix(&mut self, prefix: &str) -> Result<impl Iterator<Item = (String, String)>> {
+        let mut keys = keys_with_prefix(&self.index, prefix.as_bytes());
+        if let Some(comparator) = &self.options.comparator {
+            comparator.sort(&mut keys);
+        }
+        Ok(self.get_all(keys)?.into_iter())
+    }
+
+    /// Returns up to `count` key-value pairs following a cursor, with the cursor of the next page
+    ///
+    /// Scans start from an empty cursor and are complete when the returned cursor is empty.
+    /// A cursor only records the last key returned, so no state is kept between pages and keys
+    /// written during the scan are returned if they sort after the cursor. Malformed cursors
+    /// fail with `KvError::InvalidCursor`.
+    pub fn scan_page(
+        &mut self,
+        cursor: &str,
+        count: usize,
+    ) -> Result<(Vec<(String, String)>, String)> {
+        let start = match cursor {
+            "" => Bound::Unbounded,
+            cursor => Bound::Excluded(base64::decode(cursor).map_err(|_| KvError::InvalidCursor)?),
+        };
+        let range = (start, Bound::Unbounded);
+        // An empty page could not carry the scan forward
+        let count = count.max(1);
+        let now = now_millis();
+        let mut keys: Vec<Vec<u8>> = match &self.options.comparator {
+            Some(comparator) => {
+                let mut keys: Vec<Vec<u8>> = self
+                    .index
+                    .iter()
+                    .filter(|(key, entry)| {
+                        !entry.is_expired(now) && comparator.contains(&range, key)
+                    })
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                comparator.sort(&mut keys);
+                keys.truncate(count + 1);
+                keys
+            }
+            None => self
+                .index
+                .range(range)
+                .filter(|(_, entry)| !entry.is_expired(now))
+                .map(|(key, _)| key.clone())
+                .take(count + 1)
+                .collect(),
+        };
+        // One key past the page tells whether there is another page to come
+        let next = if keys.len() > count {
+            keys.truncate(count);
+            keys.last().map(base64::encode).unwrap_or_default()
+        } else {
+            String::new()
+        };
+        Ok((self.get_all(keys)?, next))
+    }
+
+    /// Lists the keys modified after the given time (in milliseconds since the Unix epoch),
+    /// oldest modification first
+    ///
+    /// Only the in-memory index is consulted, so no values are read from disk.
+    pub fn modified_since(&self, since: u64) -> Vec<String> {
+        self.modified_where(|time| time > since)
+    }
+
+    /// Lists the keys last modified before the given time (in milliseconds since the Unix
+    /// epoch), oldest modification first
+    ///
+    /// Like `modified_since`, this only consults the in-memory index.
+    pub fn modified_before(&self, until: u64) -> Vec<String> {
+        self.modified_where(|time| time < until)
+    }
+
+    fn modified_where(&self, matches: impl Fn(u64) -> bool) -> Vec<String> {
+        let now = now_millis();
+        let mut modified: Vec<(&Vec<u8>, &IndexEntry)> = self
+            .index
+            .iter()
+            .filter(|(_, entry)| matches(entry.time) && !entry.is_expired(now))
+            .collect();
+        modified.sort_by_key(|(_, entry)| (entry.time, entry.seq));
+        modified
+            .into_iter()
+            .map(|(key, _)| String::from_utf8_lossy(key).into_owned())
+            .collect()
+    }
+
+    fn get_all(&mut self, keys: Vec<Vec<u8>>) -> Result<Vec<(String, String)>> {
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.get_bytes(&key)? {
+                entries.push((String::from_utf8(key)?, String::from_utf8(value)?));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Returns the index entry for a key, dropping it if it has expired
+    fn live_entry(&mut self, key: &[u8]) -> Option<IndexEntry> {
+        let entry = *self.index.get(key)?;
+        if entry.is_expired(now_millis()) {
+            self.index.remove(key);
+            self.cache.pop(key);
+            return None;
+        }
+        self.touch(key);
+        Some(entry)
+    }
+
+    /// Retrieve the values for several keys at once
+    ///
+    /// Values that are not cached are read in log order through a single reader.
+    pub fn multi_get(&mut self, keys: &[String]) -> Result<Vec<Option<String>>> {
+        let keys: Vec<Vec<u8>> = keys.iter().map(|key| key.clone().into_bytes()).collect();
+        let mut values = vec![None; keys.len()];
+        let mut pending = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            if let Some(entry) = self.live_entry(key) {
+                match self.cache.get(key) {
+                    Some(value) => values[i] = Some(value.clone()),
+                    None => pending.push((entry.pointer, i)),
+                }
+            }
+        }
+
+        pending.sort();
+        let mut reader = io::BufReader::new(self.log.reader());
+        for (pointer, i) in pending {
+            reader.seek(SeekFrom::Start(pointer))?;
+            let entry = unseal_record(
+                &self.options,
+                codec::decode(self.options.format, &mut reader)?,
+            )?;
+            let entry = self.check_key(&keys[i], pointer, entry)?;
+            values[i] = self.entry_value(&keys[i], entry)?;
+        }
+
+        for (key, value) in keys.into_iter().zip(values.iter()) {
+            if let Some(value) = value {
+                self.cache_value(key, value.clone());
+            }
+        }
+        values
+            .into_iter()
+            .map(|value| value.map(String::from_utf8).transpose())
+            .collect::<std::result::Result<_, _>>()
+            .map_err(KvError::from)
+    }
+
+    fn read_record(&self, pointer: u64) -> Result<LogEntry> {
+        let entry = self.read_raw_record(pointer)?;
+        unseal_record(&self.options, entry)
+    }
+
+    fn read_raw_record(&self, pointer: u64) -> Result<LogEntry> {
+        if self.options.mmap {
+            if let Some(entry) = self.read_mapped(pointer)? {
+                return Ok(entry);
+            }
+        }
+        if let Some(cache) = &self.block_cache {
+            let mut reader = BlockReader {
+                log: &self.log,
+                cache,
+                pos: pointer,
+            };
+            return codec::decode(self.options.format, &mut reader);
+        }
+        let mut reader = io::BufReader::new(self.log.reader());
+        reader.seek(SeekFrom::Start(pointer))?;
+        codec::decode(self.options.format, &mut reader)
+    }
+
+    /// Decodes a record from a memory mapping of the log, or returns `None` if the record is not
+    /// covered by the mapping and must be read from the file instead
+    ///
+    /// The log is mapped again when a record past the end of the current mapping is requested.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn read_mapped(&self, pointer: u64) -> Result<Option<LogEntry>> {
+        let file = match &self.log {
+            Log::File(file) => file,
+            Log::Storage(_) | Log::Memory(_) => return Ok(None),
+        };
+        let mut mapping = self.mapping.borrow_mut();
+        let stale = match &*mapping {
+            Some(map) => pointer >= map.len() as u64,
+            None => true,
+        };
+        if stale {
+            *mapping = if file.metadata()?.len() > pointer {
+                // The log is only ever appended to or replaced by rename, never truncated
+                // in place, so the mapped bytes stay valid for the life of the mapping
+                Some(unsafe { Mmap::map(file)? })
+            } else {
+                None
+            };
+        }
+
+        let entry = match &*mapping {
+            Some(map) if pointer < map.len() as u64 => {
+                codec::decode(self.options.format, &map[pointer as usize..])
+            }
+            _ => return Ok(None),
+        };
+        match entry {
+            Ok(entry) => Ok(Some(entry)),
+            // The record runs past the end of the mapping
+            Err(_) => {
+                *mapping = None;
+                Ok(None)
+            }
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn read_mapped(&self, _pointer: u64) -> Result<Option<LogEntry>> {
+        Ok(None)
+    }
+
+    /// Describes an operation on a key for errors, with the log position of its live entry
+    fn error_context(&self, operation: &'static str, key: &[u8]) -> ErrorContext {
+        ErrorContext {
+            operation,
+            key: Some(String::from_utf8_lossy(key).into_owned()),
+            path: Some(self.path.clone()),
+            offset: self.index.get(key).map(|entry| entry.pointer),
+        }
+    }
+
+    fn read_log_entry(&self, key: &[u8], pointer: u64) -> Result<Option<Vec<u8>>> {
+        span!(DEBUG, "kvs.disk_read", offset = pointer);
+        let start = Instant::now();
+        let value = self
+            .read_record(pointer)
+            .and_then(|entry| self.check_key(key, pointer, entry))
+            .and_then(|entry| self.entry_value(key, entry));
+        self.phases.add(Phase::DiskRead, start);
+        value
+    }
+
+    /// Fails with `KvError::Corruption` if paranoid checks are on and a record read for a key
+    /// belongs to another key
+    fn check_key(&self, key: &[u8], pointer: u64, entry: LogEntry) -> Result<LogEntry> {
+        if self.options.paranoid_checks && !holds_key(&entry, key) {
+            return Err(KvError::Corruption { offset: pointer });
+        }
+        Ok(entry)
+    }
+
+    /// Extracts the value for a key from a decoded log entry
+    fn entry_value(&self, key: &[u8], entry: LogEntry) -> Result<Option<Vec<u8>>> {
+        match entry {
+            LogEntry::Set {
+                separated: Some(location),
+                ..
+            } => self.read_separated(location).map(Some),
+            LogEntry::Set { blob: Some(id), .. } => self.read_blob(id).map(Some),
+            LogEntry::Set { ref chunks, .. } if !chunks.is_empty() => {
+                let mut value = Vec::new();
+                for chunk in chunks {
+                    value.extend(self.read_chunk(chunk.pointer)?);
+                }
+                Ok(Some(value))
+            }
+            LogEntry::Set {
+                value, compressed, ..
+            } => decode_value(value, compressed).map(Some),
+            LogEntry::Prepare { ops, .. } => Ok(ops.into_iter().rev().find_map(|op| match op {
+                BatchOp::Set { key: k, value } if k == key => Some(value),
+                _ => None,
+            })),
+            LogEntry::Merge { .. } | LogEntry::Append { .. } => self.resolve_chain(key, entry),
+            LogEntry::Rename { from, base, .. } => self.read_log_entry(&from, base),
+            _ => Ok(None),
+        }
+    }
+
+    /// Resolves a chain of merge and append records back to the last full value and applies
+    /// them in order
+    fn resolve_chain(&self, key: &[u8], entry: LogEntry) -> Result<Option<Vec<u8>>> {
+        let mut records = Vec::new();
+        let mut record = entry;
+        let mut value = loop {
+            let base = match record {
+                LogEntry::Merge { base, .. } | LogEntry::Append { base, .. } => base,
+                record => break self.entry_value(key, record)?,
+            };
+            records.push(record);
+            match base {
+                Some(pointer) => record = self.read_record(pointer)?,
+                None => break None,
+            }
+        };
+        for record in records.into_iter().rev() {
+            value = match record {
+                LogEntry::Merge { operand, .. } => {
+                    let merge = self
+                        .merge_operator
+                        .as_ref()
+                        .ok_or(KvError::NoMergeOperator)?;
+                    Some(merge(key, value.as_deref(), &operand))
+                }
+                LogEntry::Append { suffix, .. } => {
+                    let mut appended = value.unwrap_or_default();
+                    appended.extend(suffix);
+                    Some(appended)
+                }
+                _ => value,
+            };
+        }
+        Ok(value)
+    }
+
+    /// Returns the chunks making up the value stored at a pointer, if it was chunked
+    fn read_chunk_refs(&self, pointer: u64) -> Result<Vec<ChunkRef>> {
+        match self.read_record(pointer)? {
+            LogEntry::Set { chunks, .. } => Ok(chunks),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn read_chunk(&self, pointer: u64) -> Result<Vec<u8>> {
+        match self.read_record(pointer)? {
+            LogEntry::Chunk { data } => Ok(data),
+            _ => Err(KvError::Corruption { offset: pointer }),
+        }
+    }
+
+    fn read_separated(&self, location: ChunkRef) -> Result<Vec<u8>> {
+        let corrupt = KvError::Corruption {
+            offset: location.pointer,
+        };
+        let values = self.values.as_ref().ok_or(corrupt)?;
+        let mut reader = values.reader();
+        reader.seek(SeekFrom::Start(location.pointer))?;
+        let mut value = Vec::with_capacity(location.len as usize);
+        reader.take(location.len).read_to_end(&mut value)?;
+        if value.len() as u64 != location.len {
+            return Err(KvError::Corruption {
+                offset: location.pointer,
+            });
+        }
+        unseal_value(&self.options, value, location.sealed)
+    }
+
+    fn read_blob(&self, id: u64) -> Result<Vec<u8>> {
+        let blob = *self.blobs.get(&id).ok_or(KvError::MissingBlob(id))?;
+        if let Some(location) = blob.location {
+            return self.read_separated(location);
+        }
+        match self.read_record(blob.pointer)? {
+            LogEntry::Blob { value, .. } => Ok(value),
+            _ => Err(KvError::Corruption {
+                offset: blob.pointer,
+            }),
+        }
+    }
+
+    /// Counts the live keys referring to each blob
+    ///
+    /// A blob is only kept by compaction while it has references.
+    fn blob_refs(&self) -> HashMap<u64, usize> {
+        let now = now_millis();
+        let mut refs = HashMap::new();
+        for entry in self.index.values().filter(|entry| !entry.is_expired(now)) {
+            if let Some(id) = entry.blob {
+                *refs.entry(id).or_insert(0) += 1;
+            }
+        }
+        refs
+    }
+
+    /// Returns the bytes of the value log held by blobs that live keys refer to
+    fn live_blob_bytes(&self) -> u64 {
+        self.blob_refs()
+            .keys()
+            .filter_map(|id| self.blobs.get(id)?.location)
+            .map(|location| location.len)
+            .sum()
+    }
+
+    /// Returns the store for a named bucket with its own key space, opening it if needed
+    ///
+    /// Each bucket is kept in its own log file in a directory next to this store's log.
+    pub fn bucket(&mut self, name: &str) -> Result<&mut KvStore> {
+        if !valid_name(name) {
+            return Err(KvError::InvalidBucketName);
+        }
+
+        match self.buckets.entry(name.to_string()) {
+            hash_map::Entry::Occupied(slot) => Ok(slot.into_mut()),
+            hash_map::Entry::Vacant(slot) if self.log.is_memory() => {
+                let store = KvStore::load(
+                    PathBuf::new(),
+                    Log::memory(),
+                    None,
+                    None,
+                    self.options.clone(),
+                )?;
+                Ok(slot.insert(store))
+            }
+            hash_map::Entry::Vacant(slot) => {
+                let dir = self.path.with_extension("buckets");
+                self.options.create_dir_all(&dir)?;
+                let store = self.options.open(&dir.join(format!("{}.log", name)))?;
+                Ok(slot.insert(store))
+            }
+        }
+    }
+
+    /// Registers the function used to combine merge operands with the existing value of a key
+    ///
+    /// Operators are not persisted, so the same operator must be registered again after opening
+    /// a store that has pending merges.
+    pub fn set_merge_operator<F>(&mut self, operator: F)
+    where
+        F: Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        self.merge_operator = Some(Arc::new(operator));
+    }
+
+    /// Appends a merge operand for a key, which is combined with its value when it is read
+    pub fn merge(&mut self, key: String, operand: String) -> Result<()> {
+        self.merge_bytes(key.into_bytes(), operand.into_bytes())
+    }
+
+    /// Appends a merge operand for a binary key
+    pub fn merge_bytes(&mut self, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        if self.merge_operator.is_none() {
+            return Err(KvError::NoMergeOperator);
+        }
+        self.options.check_size(&key, operand.len())?;
+        self.reserve_keys(usize::from(self.is_new_key(&key)))?;
+        self.reserve((key.len() + operand.len()) as u64)?;
+        let old = self.watched_value(&key)?;
+        let previous = self.live_entry(&key);
+        let seq = self.next_seq();
+        let time = now_millis();
+        let entry = LogEntry::Merge {
+            key: key.clone(),
+            operand,
+            base: previous.map(|entry| entry.pointer),
+            seq,
+            time,
+        };
+        self.append_chained(key.clone(), previous, &entry, seq, time)?;
+        self.cache.pop(&key);
+        if self.is_observed(&key) {
+            let new = self.read_value(&key)?;
+            self.notify(&key, old, new.as_deref())?;
+        }
+        self.maybe_compact()
+    }
+
+    /// Appends to the value of a key, creating it if missing, and returns the new length
+    ///
+    /// Only the suffix is written to the log.
+    pub fn append(&mut self, key: String, suffix: String) -> Result<usize> {
+        self.append_bytes(key.into_bytes(), suffix.into_bytes())
+    }
+
+    /// Appends to the value of a binary key and returns the new length
+    pub fn append_bytes(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<usize> {
+        // Reserving space may compact the log, so the previous record is looked up after it
+        self.reserve_keys(usize::from(self.is_new_key(&key)))?;
+        self.reserve((key.len() + suffix.len()) as u64)?;
+        let previous = self.live_entry(&key);
+        let old = self.watched_value(&key)?;
+        let mut value = self.read_value(&key)?.unwrap_or_default();
+        value.extend_from_slice(&suffix);
+        self.options.check_size(&key, value.len())?;
+        let seq = self.next_seq();
+        let time = now_millis();
+        let entry = LogEntry::Append {
+            key: key.clone(),
+            suffix,
+            base: previous.map(|entry| entry.pointer),
+            seq,
+            time,
+        };
+        self.append_chained(key.clone(), previous, &entry, seq, time)?;
+        self.notify(&key, old, Some(&value))?;
+        let len = value.len();
+        self.cache_value(key, value);
+        self.maybe_compact()?;
+        Ok(len)
+    }
+
+    /// Writes a record that builds on the previous value of a key and points the index at it
+    fn append_chained(
+        &mut self,
+        key: Vec<u8>,
+        previous: Option<IndexEntry>,
+        entry: &LogEntry,
+        seq: u64,
+        time: u64,
+    ) -> Result<()> {
+        let pointer = self.append_to_log(entry)?;
+        let len = self.log.len()? - pointer;
+        let entry = IndexEntry {
+            pointer,
+            len: len + previous.map_or(0, |entry| entry.len),
+            expires_at: previous.and_then(|entry| entry.expires_at),
+            seq,
+            time,
+            created: previous.map_or(time, |entry| entry.created),
+            blob: None,
+            separated: previous.map_or(0, |entry| entry.separated),
+        };
+        let captured = self.versions.capture(&self.index, Some(&key));
+        self.index.insert(key, entry);
+        self.versions.record(&self.index, captured, seq, time);
+        Ok(())
+    }
+
+    /// Set the value for a key
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Set the value for a binary key
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let persistent = match self.live_entry(&key) {
+            Some(entry) => entry.expires_at.is_none(),
+            None => false,
+        };
+        match self.read_value(&key) {
+            Ok(Some(v)) if persistent && v == value => Ok(()),
+            _ => self.write_value(key, value, None, None),
+        }
+    }
+
+    /// Set the value for a key and return the value it replaced, if any
+    ///
+    /// This is the `GETSET` of other stores, and is exposed as `kvs getset`.
+    pub fn replace(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let previous = self.get(key.clone())?;
+        self.write_value(key.into_bytes(), value.into_bytes(), None, None)?;
+        Ok(previous)
+    }
+
+    /// Set the value for a key to the JSON serialization of a value
+    pub fn set_as<T: Serialize>(&mut self, key: String, value: &T) -> Result<()> {
+        let value = serde_json::to_vec(value)?;
+        self.set_bytes(key.into_bytes(), value)
+    }
+
+    /// Set the value for a key that expires after the given duration
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.write_value(key.into_bytes(), value.into_bytes(), Some(expires_at), None)
+    }
+
+    /// Returns how long a key has left before it expires, or `None` if it never does
+    ///
+    /// Returns `KvError::KeyNotFound` if the key is not stored or has already expired.
+    pub fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        let now = now_millis();
+        match self.index.get(key.as_bytes()) {
+            Some(entry) if !entry.is_expired(now) => Ok(entry
+                .expires_at
+                .map(|expires_at| Duration::from_millis(expires_at - now))),
+            _ => Err(KvError::KeyNotFound),
+        }
+    }
+
+    /// Adds `delta` to the integer stored at a key and returns the new value
+    ///
+    /// Missing keys count as zero and negative deltas decrement. Any expiry on the key is kept.
+    pub fn incr(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.add_to(key, delta, None)
+    }
+
+    /// Adds `delta` to the integer stored at a key unless a write with the same idempotency
+    /// token was already applied
+    ///
+    /// Returns the new value, or `None` if the increment was not applied, so a retried request
+    /// never counts twice.
+    pub fn incr_idempotent(
+        &mut self,
+        token: String,
+        key: String,
+        delta: i64,
+    ) -> Result<Option<i64>> {
+        if self.tokens.contains(&token) {
+            return Ok(None);
+        }
+        let value = self.add_to(key, delta, Some(token.clone()))?;
+        self.tokens.insert(token);
+        Ok(Some(value))
+    }
+
+    fn add_to(&mut self, key: String, delta: i64, token: Option<String>) -> Result<i64> {
+        let key = key.into_bytes();
+        let expires_at = self.live_entry(&key).and_then(|entry| entry.expires_at);
+        let current = match self.read_value(&key)? {
+            Some(value) => std::str::from_utf8(&value)
+                .ok()
+                .and_then(|value| value.parse::<i64>().ok())
+                .ok_or(KvError::NotAnInteger)?,
+            None => 0,
+        };
+        let value = current.checked_add(delta).ok_or(KvError::NotAnInteger)?;
+        self.write_value(key, value.to_string().into_bytes(), expires_at, token)?;
+        Ok(value)
+    }
+
+    /// Set the value for a key unless a write with the same idempotency token was already applied
+    ///
+    /// Returns whether the write was applied.
+    pub fn set_idempotent(&mut self, token: String, key: String, value: String) -> Result<bool> {
+        if self.tokens.contains(&token) {
+            return Ok(false);
+        }
+        self.write_value(
+            key.into_bytes(),
+            value.into_bytes(),
+            None,
+            Some(token.clone()),
+        )?;
+        self.tokens.insert(token);
+        Ok(true)
+    }
+
+    fn write_value(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+        token: Option<String>,
+    ) -> Result<()> {
+        span!(
+            INFO,
+            "kvs.set",
+            key_len = key.len(),
+            value_len = value.len()
+        );
+        let start = self.start_op();
+        let context_key = key.clone();
+        let result = self.store_value(key, value, expires_at, token);
+        self.finish_op(Op::Write, start);
+        result.map_err(|err| err.with_context(|| self.error_context("set", &context_key)))
+    }
+
+    fn store_value(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+        token: Option<String>,
+    ) -> Result<()> {
+        self.options.check_size(&key, value.len())?;
+        self.reserve_keys(usize::from(self.is_new_key(&key)))?;
+        self.reserve((key.len() + value.len()) as u64)?;
+        let old = self.watched_value(&key)?;
+        let seq = self.next_seq();
+        let time = now_millis();
+        let created = self.live_entry(&key).map_or(time, |entry| entry.created);
+        let blob = if self.options.dedup_values {
+            Some(self.store_blob(&value)?)
+        } else {
+            None
+        };
+        let separated = match self.options.value_log_threshold {
+            Some(threshold) if blob.is_none() && value.len() > threshold => {
+                Some(self.store_separated(&value)?)
+            }
+            _ => None,
+        };
+        let chunks = if blob.is_none() && separated.is_none() && value.len() > CHUNK_SIZE {
+            self.store_chunks(&value)?
+        } else {
+            Vec::new()
+        };
+        let chunk_bytes = chunks.iter().map(|chunk| chunk.len).sum::<u64>();
+        let (stored, compressed) = if blob.is_some() || separated.is_some() || !chunks.is_empty() {
+            (Vec::new(), false)
+        } else {
+            self.encode_value(value.clone())
+        };
+        let entry = LogEntry::Set {
+            key: key.clone(),
+            value: stored,
+            expires_at,
+            token,
+            seq,
+            time,
+            created,
+            blob,
+            chunks,
+            separated,
+            compressed,
+        };
+        let pointer = self.append_to_log(&entry)?;
+        let len = self.log.len()? - pointer + chunk_bytes;
+        let entry = IndexEntry {
+            pointer,
+            len,
+            expires_at,
+            seq,
+            time,
+            created,
+            blob,
+            separated: separated.map_or(0, |location| location.len),
+        };
+        let captured = self.versions.capture(&self.index, Some(&key));
+        for _ in self.index.insert(key.clone(), entry).iter() {
+            self.maybe_compact()?;
+        }
+        self.versions.record(&self.index, captured, seq, time);
+        self.notify(&key, old, Some(&value))?;
+        self.cache_value(key, value);
+        Ok(())
+    }
+
+    /// Returns the bytes to store inline in a record for a value and whether they are compressed
+    ///
+    /// Values are only stored compressed if compression is enabled and makes them smaller.
+    fn encode_value(&self, value: Vec<u8>) -> (Vec<u8>, bool) {
+        #[cfg(feature = "compression")]
+        {
+            if self.options.compression {
+                if let Ok(compressed) = snap::raw::Encoder::new().compress_vec(&value) {
+                    if compressed.len() < value.len() {
+                        return (compressed, true);
+                    }
+                }
+            }
+        }
+        (value, false)
+    }
+
+    /// Appends a value to the value log, creating the value log if needed
+    fn store_separated(&mut self, value: &[u8]) -> Result<ChunkRef> {
+        self.check_writable()?;
+        let values = match self.values.take() {
+            Some(values) => values,
+            None if self.log.is_memory() => Log::memory(),
+            None => self
+                .options
+                .open_log_file(&self.path.with_extension("vlog"), false)?,
+        };
+        let values = &*self.values.get_or_insert(values);
+        let (stored, sealed) = seal_value(&self.options, value)?;
+        let pointer = values.len()?;
+        if let Some(chunk) = self.options.preallocate {
+            prealloc::extend(values, pointer, pointer + stored.len() as u64, chunk)?;
+        }
+        values.writer().write_all(&stored)?;
+        if self.options.direct_io {
+            values.drop_cached()?;
+        }
+        if self.options.sync == SyncPolicy::Always {
+            values.sync()?;
+        }
+        Ok(ChunkRef {
+            pointer,
+            len: stored.len() as u64,
+            sealed,
+        })
+    }
+
+    /// Appends a value held in the value log to a new value log, for compaction
+    fn copy_separated(
+        &self,
+        location: ChunkRef,
+        values: &Log,
+        throttle: &Throttle,
+    ) -> Result<ChunkRef> {
+        let value = self.read_separated(location)?;
+        let (stored, sealed) = seal_value(&self.options, &value)?;
+        let pointer = values.len()?;
+        values.writer().write_all(&stored)?;
+        throttle.consume(stored.len());
+        Ok(ChunkRef {
+            pointer,
+            len: stored.len() as u64,
+            sealed,
+        })
+    }
+
+    /// Returns a writable handle on the value log, for a store replacing this one
+    fn reopen_values(&self) -> Result<Option<Log>> {
+        match &self.values {
+            Some(Log::Memory(buf)) => Ok(Some(Log::Memory(buf.clone()))),
+            Some(_) => Ok(Some(
+                self.options
+                    .open_log_file(&self.path.with_extension("vlog"), false)?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn store_chunks(&mut self, value: &[u8]) -> Result<Vec<ChunkRef>> {
+        let mut chunks = Vec::new();
+        for data in value.chunks(CHUNK_SIZE) {
+            let entry = LogEntry::Chunk {
+                data: data.to_vec(),
+            };
+            let pointer = self.append_to_log(&entry)?;
+            chunks.push(ChunkRef {
+                pointer,
+                len: data.len() as u64,
+                sealed: false,
+            });
+        }
+        Ok(chunks)
+    }
+
+    /// Returns the id of a blob holding the value, writing a new one if none exists yet
+    fn store_blob(&mut self, value: &[u8]) -> Result<u64> {
+        let hash = hash_value(value);
+        if let Some(&id) = self.blob_hashes.get(&hash) {
+            if self.read_blob(id)? == value {
+                return Ok(id);
+            }
+        }
+
+        // The value goes to the value log first, so a crash never leaves a record without it
+        let location = Some(self.store_separated(value)?);
+        self.last_blob += 1;
+        let id = self.last_blob;
+        let entry = LogEntry::Blob {
+            id,
+            hash,
+            value: Vec::new(),
+            location,
+        };
+        let pointer = self.append_to_log(&entry)?;
+        self.blobs.insert(
+            id,
+            BlobEntry {
+                pointer,
+                hash,
+                location,
+            },
+        );
+        self.blob_hashes.insert(hash, id);
+        Ok(id)
+    }
+
+    /// Delete a key
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_entry(key.into_bytes(), None)
+    }
+
+    /// Delete a binary key
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        self.remove_entry(key.to_vec(), None)
+    }
+
+    /// Delete a key and return its value, or `None` if it was not present
+    ///
+    /// This is the `GETDEL` of other stores, and is exposed as `kvs getdel`.
+    pub fn take(&mut self, key: String) -> Result<Option<String>> {
+        let previous = self.get(key.clone())?;
+        if previous.is_some() {
+            self.remove_entry(key.into_bytes(), None)?;
+        }
+        Ok(previous)
+    }
+
+    /// Delete a key unless a write with the same idempotency token was already applied
+    ///
+    /// Returns whether the removal was applied.
+    pub fn remove_idempotent(&mut self, token: String, key: String) -> Result<bool> {
+        if self.tokens.contains(&token) {
+            return Ok(false);
+        }
+        self.remove_entry(key.into_bytes(), Some(token.clone()))?;
+        self.tokens.insert(token);
+        Ok(true)
+    }
+
+    fn remove_entry(&mut self, key: Vec<u8>, token: Option<String>) -> Result<()> {
+        span!(INFO, "kvs.remove", key_len = key.len());
+        let start = self.start_op();
+        let context_key = key.clone();
+        let result = self.delete_entry(key, token);
+        self.finish_op(Op::Remove, start);
+        result.map_err(|err| err.with_context(|| self.error_context("remove", &context_key)))
+    }
+
+    fn delete_entry(&mut self, key: Vec<u8>, token: Option<String>) -> Result<()> {
+        self.live_entry(&key);
+        let old = self.watched_value(&key)?;
+        let captured = self.versions.capture(&self.index, Some(&key));
+        match self.index.remove(&key) {
+            None => Err(KvError::KeyNotFound),
+            Some(_) => {
+                self.cache.pop(&key);
+                self.accessed.remove(&key);
+                let (seq, time) = (self.next_seq(), now_millis());
+                let entry = LogEntry::Remove {
+                    key: key.clone(),
+                    token,
+                    seq,
+                    time,
+                };
+                self.append_to_log(&entry).map(|_| ())?;
+                self.versions.record(&self.index, captured, seq, time);
+                self.tombstones.record(key.clone(), seq, time);
+                self.notify(&key, old, None)?;
+                self.maybe_compact()
+            }
+        }
+    }
+
+    /// Moves the value of a key to a new key in a single log record, replacing any value there
+    pub fn rename(&mut self, from: String, to: String) -> Result<()> {
+        let (from, to) = (from.into_bytes(), to.into_bytes());
+        let source = self.live_entry(&from).ok_or(KvError::KeyNotFound)?;
+        if from == to {
+            return Ok(());
+        }
+        self.options.check_size(&to, 0)?;
+
+        let value = if self.is_observed(&from) || self.is_observed(&to) {
+            self.read_value(&from)?
+        } else {
+            None
+        };
+        let replaced = self.watched_value(&to)?;
+
+        let seq = self.next_seq();
+        let time = now_millis();
+        let entry = LogEntry::Rename {
+            from: from.clone(),
+            to: to.clone(),
+            base: source.pointer,
+            seq,
+            time,
+        };
+        let pointer = self.append_to_log(&entry)?;
+        let len = self.log.len()? - pointer;
+        let captured = self.versions.capture(&self.index, [&from, &to]);
+        self.index.remove(&from);
+        let entry = IndexEntry {
+            pointer,
+            len: len + source.len,
+            seq,
+            time,
+            ..source
+        };
+        self.index.insert(to.clone(), entry);
+        self.versions.record(&self.index, captured, seq, time);
+        self.cache.pop(&to);
+        self.notify(&from, value.clone(), None)?;
+        self.notify(&to, replaced, value.as_deref())?;
+        if let Some(value) = self.cache.pop(&from) {
+            self.cache_value(to, value);
+        }
+        self.maybe_compact()
+    }
+
+    /// Deletes every key starting with the given prefix in a single log record
+    ///
+    /// Returns how many live keys were removed.
+    pub fn remove_prefix(&mut self, prefix: &str) -> Result<usize> {
+        let now = now_millis();
+        let keys = keys_with_prefix(&self.index, prefix.as_bytes());
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        let mut watched = Vec::new();
+        for key in &keys {
+            if self.is_observed(key) {
+                watched.push((key.clone(), self.read_value(key)?));
+            }
+        }
+
+        let seq = self.next_seq();
+        let entry = LogEntry::RemovePrefix {
+            prefix: prefix.as_bytes().to_vec(),
+            seq,
+            time: now,
+        };
+        self.append_to_log(&entry)?;
+        let captured = self.versions.capture(&self.index, &keys);
+
+        let mut removed = 0;
+        for key in &keys {
+            if let Some(entry) = self.index.remove(key) {
+                if !entry.is_expired(now) {
+                    removed += 1;
+                }
+            }
+            self.cache.pop(key);
+        }
+        self.versions.record(&self.index, captured, seq, now);
+        for (key, old) in watched {
+            if old.is_some() {
+                self.notify(&key, old, None)?;
+            }
+        }
+        self.compaction_counter += keys.len() as u32;
+        self.maybe_compact()?;
+        Ok(removed)
+    }
+
+    /// Deletes every key, replacing the log with an empty one
+    ///
+    /// Pending prepared batches and remembered idempotency tokens are discarded as well.
+    pub fn clear(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let keys: Vec<Vec<u8>> = self
+            .index
+            .keys()
+            .filter(|key| self.is_observed(key))
+            .cloned()
+            .collect();
+        let mut watched = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(old) = self.read_value(&key)? {
+                watched.push((key, old));
+            }
+        }
+        self.index.clear();
+        self.cache.clear();
+        self.accessed.clear();
+        self.versions.clear();
+        self.tombstones.clear();
+        self.prepared.clear();
+        self.tokens = RecentTokens::new(self.tokens.capacity);
+        self.blobs.clear();
+        self.blob_hashes.clear();
+
+        // Replace the log rather than truncating it in place, so that snapshots and mappings
+        // of the old file stay valid; the new log keeps sequence numbers increasing
+        self.rewrite_log(self.options.format)?;
+        for (key, old) in watched {
+            self.notify(&key, Some(old), None)?;
+        }
+        Ok(())
+    }
+
+    /// Drops all expired keys from the index and returns how many were removed
+    pub fn purge_expired(&mut self) -> Result<usize> {
+        let now = now_millis();
+        let expired: Vec<Vec<u8>> = self
+            .index
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &expired {
+            self.index.remove(key);
+            self.cache.pop(key);
+        }
+
+        if !expired.is_empty() {
+            self.compaction_counter += expired.len() as u32;
+            self.maybe_compact()?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Durably stages a batch of writes and returns a token to commit or abort it with
+    pub fn prepare_batch(&mut self, batch: WriteBatch) -> Result<u64> {
+        for op in &batch.ops {
+            if let BatchOp::Set { key, value } = op {
+                self.options.check_size(key, value.len())?;
+            }
+        }
+        let size = batch
+            .ops
+            .iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => (key.len() + value.len()) as u64,
+                BatchOp::Remove { key } => key.len() as u64,
+            })
+            .sum();
+        let new_keys: HashSet<&[u8]> = batch
+            .ops
+            .iter()
+            .filter_map(|op| match op {
+                BatchOp::Set { key, .. } if self.is_new_key(key) => Some(key.as_slice()),
+                _ => None,
+            })
+            .collect();
+        self.reserve_keys(new_keys.len())?;
+        self.reserve(size)?;
+        let token = self.next_token();
+        let entry = LogEntry::Prepare {
+            token,
+            ops: batch.ops.clone(),
+        };
+        let pointer = self.append_to_log(&entry)?;
+        self.log.sync()?;
+        self.prepared.insert(
+            token,
+            PreparedBatch {
+                pointer,
+                ops: batch.ops,
+            },
+        );
+        Ok(token)
+    }
+
+    /// Applies a previously prepared batch
+    pub fn commit(&mut self, token: u64) -> Result<()> {
+        if !self.prepared.contains_key(&token) {
+            return Err(KvError::TransactionNotFound);
+        }
+        let mut watched = Vec::new();
+        if let Some(batch) = self.prepared.get(&token) {
+            for op in &batch.ops {
+                let key = match op {
+                    BatchOp::Set { key, .. } | BatchOp::Remove { key } => key,
+                };
+                if self.is_observed(key) && !watched.contains(key) {
+                    watched.push(key.clone());
+                }
+            }
+        }
+        let mut olds = Vec::with_capacity(watched.len());
+        for key in &watched {
+            olds.push(self.read_value(key)?);
+        }
+        let seq = self.next_seq();
+        let time = now_millis();
+        self.append_to_log(&LogEntry::Commit { token, seq, time })?;
+        self.log.sync()?;
+
+        if let Some(batch) = self.prepared.remove(&token) {
+            for op in &batch.ops {
+                match op {
+                    BatchOp::Set { key, value } => self.cache_value(key.clone(), value.clone()),
+                    BatchOp::Remove { key } => {
+                        self.cache.pop(key);
+                    }
+                }
+            }
+            let captured = self
+                .versions
+                .capture(&self.index, versions::batch_keys(&batch.ops));
+            apply_batch(&mut self.index, batch.pointer, &batch.ops, seq, time);
+            self.versions.record(&self.index, captured, seq, time);
+            record_batch_removals(&mut self.tombstones, &batch.ops, seq, time);
+            for (key, old) in watched.into_iter().zip(olds) {
+                let new = batch.ops.iter().rev().find_map(|op| match op {
+                    BatchOp::Set { key: k, value } if *k == key => Some(Some(value)),
+                    BatchOp::Remove { key: k } if *k == key => Some(None),
+                    _ => None,
+                });
+                if let Some(new) = new {
+                    self.notify(&key, old, new.map(Vec::as_slice))?;
+                }
+            }
+        }
+        self.maybe_compact()
+    }
+
+    /// Discards a previously prepared batch
+    pub fn abort(&mut self, token: u64) -> Result<()> {
+        if !self.prepared.contains_key(&token) {
+            return Err(KvError::TransactionNotFound);
+        }
+        self.append_to_log(&LogEntry::Abort { token })?;
+        self.log.sync()?;
+        self.prepared.remove(&token);
+        self.maybe_compact()
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    fn next_token(&mut self) -> u64 {
+        // Tokens are seeded from the clock so that they are not reused across restarts
+        // after compaction has dropped the records of finished batches.
+        self.last_token = (self.last_token + 1).max(now_millis() << 16);
+        self.last_token
+    }
+
+    fn append_to_log(&mut self, entry: &LogEntry) -> Result<u64> {
+        let buf = seal_record(&self.options, entry)?;
+        self.append_raw(&buf)
+    }
+
+    /// Appends encoded records to the log, returning the position of the first
+    fn append_raw(&mut self, buf: &[u8]) -> Result<u64> {
+        self.check_writable()?;
+        let pointer = self.log.len()?;
+        span!(DEBUG, "kvs.append", offset = pointer, len = buf.len());
+        let start = Instant::now();
+        if let Some(chunk) = self.options.preallocate {
+            prealloc::extend(&self.log, pointer, pointer + buf.len() as u64, chunk)?;
+        }
+        self.log.writer().write_all(buf)?;
+        if self.options.direct_io {
+            self.log.drop_cached()?;
+        }
+        self.phases.add(Phase::Append, start);
+        if self.options.sync == SyncPolicy::Always {
+            span!(DEBUG, "kvs.fsync");
+            let start = Instant::now();
+            self.log.sync()?;
+            self.phases.add(Phase::Fsync, start);
+        }
+        Ok(pointer)
+    }
+
+    /// Hands writes made so far to the operating system
+    ///
+    /// The store does not buffer writes itself, so they are already with the operating system
+    /// when a write returns and this only flushes the file handles. Use `sync` to make them
+    /// durable.
+    pub fn flush(&self) -> Result<()> {
+        self.log.writer().flush()?;
+        if let Some(values) = &self.values {
+            values.writer().flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the log, value log and the logs of open buckets to stable storage
+    ///
+    /// This makes every write made so far durable whatever the sync policy, so a store opened
+    /// with `SyncPolicy::Never` can be synced at checkpoints of the application's choosing,
+    /// such as after each batch of writes.
+    pub fn sync(&self) -> Result<()> {
+        self.sync_logs()?;
+        for bucket in self.buckets.values() {
+            bucket.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the log and value log to stable storage
+    fn sync_logs(&self) -> io::Result<()> {
+        self.log.sync()?;
+        if let Some(values) = &self.values {
+            values.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Drops cached blocks and the mapping of the log after it has been replaced
+    fn reset_read_caches(&self) {
+        if let Some(cache) = &self.block_cache {
+            cache.borrow_mut().clear();
+        }
+        *self.mapping.borrow_mut() = None;
+    }
+
+    /// Closes the log and value log, and drops any mapping of the log, so that they can be
+    /// replaced by rename
+    ///
+    /// Windows refuses to replace a file that is open or mapped, so every rename over the log
+    /// closes it first and reopens whatever ends up at its path with `reopen_files`. Stores held
+    /// in memory are left as they are.
+    fn close_files(&mut self) {
+        self.reset_read_caches();
+        if !self.log.is_memory() {
+            self.log = Log::memory();
+            self.values = None;
+        }
+    }
+
+    /// Reopens the log for writing, and the value log if there is one, after `close_files`
+    fn reopen_files(&mut self) -> Result<()> {
+        if self.path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        self.log = self.options.open_log_file(&self.path, true)?;
+        let values_path = self.path.with_extension("vlog");
+        self.values = if self.options.file_exists(&values_path) {
+            Some(self.options.open_log_file(&values_path, false)?)
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.options.read_only {
+            Err(KvError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the version of the log format the log was written in, or 0 for logs written
+    /// before logs had a header
+    pub fn log_version(&self) -> Result<u8> {
+        Ok(match codec::read_header(&self.log)? {
+            Some(_) => codec::HEADER_VERSION,
+            None => 0,
+        })
+    }
+
+    /// Returns the encoding of the records in the log
+    pub fn log_format(&self) -> LogFormat {
+        self.options.format
+    }
+
+    /// Rewrites the log in the current version of the log format with records encoded in
+    /// `format`, compacting it on the way
+    pub fn migrate(&mut self, format: LogFormat) -> Result<()> {
+        self.compact_to(format)
+    }
+
+    /// Rewrites the log to hold only live data, reclaiming the space of stale records
+    ///
+    /// Live records are written in key order, so scans of a freshly compacted log read it
+    /// from front to back.
+    pub fn compact(&mut self) -> Result<()> {
+        self.compact_to(self.options.format)
+    }
+
+    /// Compacts the log, writing the new one in `format`
+    fn compact_to(&mut self, format: LogFormat) -> Result<()> {
+        self.emit(StoreEvent::CompactionStarted);
+        let size_before = self.log.len()?;
+        span!(INFO, "kvs.compact", size_before = size_before);
+        let start = Instant::now();
+        let result = self.rewrite_log(format).map_err(|err| {
+            err.with_context(|| ErrorContext {
+                operation: "compact",
+                path: Some(self.path.clone()),
+                ..ErrorContext::default()
+            })
+        });
+        self.phases.add(Phase::Compaction, start);
+        let size_after = self.log.len()?;
+        self.emit(StoreEvent::CompactionFinished {
+            size_before,
+            size_after,
+        });
+        result?;
+        self.compactions += 1;
+        if self.options.warm_cache {
+            self.save_hot_keys()?;
+        }
+        Ok(())
+    }
+
+    fn maybe_compact(&mut self) -> Result<()> {
+        self.compaction_counter += 1;
+        if self.compaction_counter > self.options.compaction_threshold {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the log with only the live state of the store
+    fn rewrite_log(&mut self, format: LogFormat) -> Result<()> {
+        self.check_writable()?;
+        // Records are read in the format of the old log and written in that of the new one
+        let mut options = self.options.clone();
+        options.format = format;
+        let old_path = self.path.clone();
+        let new_path = self.path.with_extension("bak");
+        let mut index = BTreeMap::new();
+        let mut prepared = Vec::with_capacity(self.prepared.len());
+        let mut blobs = HashMap::new();
+        let mut blob_hashes = HashMap::new();
+        let new_log = if self.log.is_memory() {
+            Log::memory()
+        } else {
+            self.options.create_file(&new_path)?
+        };
+
+        // Blobs of logs written before blobs moved to the value log are moved there now
+        let blob_refs = self.blob_refs();
+        for &id in blob_refs.keys() {
+            if self
+                .blobs
+                .get(&id)
+                .is_some_and(|blob| blob.location.is_none())
+            {
+                let value = self.read_blob(id)?;
+                let location = self.store_separated(&value)?;
+                if let Some(blob) = self.blobs.get_mut(&id) {
+                    blob.location = Some(location);
+                }
+            }
+        }
+
+        // Values in the value log are only copied once at least half of it is garbage
+        let now = now_millis();
+        let live_values: u64 = self
+            .index
+            .values()
+            .filter(|entry| !entry.is_expired(now))
+            .map(|entry| entry.separated)
+            .sum::<u64>()
+            + self.live_blob_bytes();
+        let values_path = self.path.with_extension("vlog.new");
+        let new_values = match &self.values {
+            Some(values) if values.len()? > 2 * live_values => Some(if values.is_memory() {
+                Log::memory()
+            } else {
+                self.options.create_file(&values_path)?
+            }),
+            _ => None,
+        };
+        let mut versions = Versions::new(self.options.retained_versions);
+        let mut tombstones = Tombstones::default();
+        let throttle = Throttle::new(self.options.compaction_rate);
+        {
+            let mut compactor = io::BufWriter::new(throttle.writer(new_log.writer()));
+            codec::write_header(&mut compactor, format)?;
+            let mut pointer = codec::HEADER_LEN;
+            // Retained versions go first so that replaying the log ends on the live values
+            for (key, retained) in &self.versions.keys {
+                for version in retained {
+                    let log_entry = match version.entry {
+                        Some(entry) => match self.read_log_entry(key, entry.pointer)? {
+                            Some(value) => {
+                                let (value, compressed) = self.encode_value(value);
+                                LogEntry::Set {
+                                    key: key.clone(),
+                                    value,
+                                    expires_at: entry.expires_at,
+                                    token: None,
+                                    seq: entry.seq,
+                                    time: entry.time,
+                                    created: entry.created,
+                                    blob: None,
+                                    chunks: Vec::new(),
+                                    separated: None,
+                                    compressed,
+                                }
+                            }
+                            None => continue,
+                        },
+                        None => LogEntry::Remove {
+                            key: key.clone(),
+                            token: None,
+                            seq: version.seq,
+                            time: version.time,
+                        },
+                    };
+                    let buf = seal_record(&options, &log_entry)?;
+                    compactor.write_all(&buf)?;
+                    let entry = version.entry.map(|entry| IndexEntry {
+                        pointer,
+                        len: buf.len() as u64,
+                        blob: None,
+                        separated: 0,
+                        ..entry
+                    });
+                    versions.push(key, Version { entry, ..*version });
+                    pointer += buf.len() as u64;
+                }
+            }
+            // Only blobs still referenced by a live key survive compaction
+            let mut blob_ids: Vec<u64> = blob_refs.keys().cloned().collect();
+            blob_ids.sort_unstable();
+            for id in blob_ids {
+                let blob = *self.blobs.get(&id).ok_or(KvError::MissingBlob(id))?;
+                let location = match (blob.location, &new_values) {
+                    (Some(location), Some(values)) => {
+                        Some(self.copy_separated(location, values, &throttle)?)
+                    }
+                    (location, _) => location,
+                };
+                let log_entry = LogEntry::Blob {
+                    id,
+                    hash: blob.hash,
+                    value: Vec::new(),
+                    location,
+                };
+                let buf = seal_record(&options, &log_entry)?;
+                compactor.write_all(&buf)?;
+                blobs.insert(
+                    id,
+                    BlobEntry {
+                        pointer,
+                        hash: blob.hash,
+                        location,
+                    },
+                );
+                blob_hashes.insert(blob.hash, id);
+                pointer += buf.len() as u64;
+            }
+            for (key, entry) in &self.index {
+                if entry.is_expired(now) {
+                    continue;
+                }
+                let stored_chunks = self.read_chunk_refs(entry.pointer)?;
+                let mut chunks = Vec::with_capacity(stored_chunks.len());
+                let mut chunk_bytes = 0;
+                for chunk in stored_chunks {
+                    // Chunks are copied one at a time so large values are never fully buffered
+                    let log_entry = LogEntry::Chunk {
+                        data: self.read_chunk(chunk.pointer)?,
+                    };
+                    let buf = seal_record(&options, &log_entry)?;
+                    compactor.write_all(&buf)?;
+                    chunks.push(ChunkRef { pointer, ..chunk });
+                    pointer += buf.len() as u64;
+                    chunk_bytes += chunk.len;
+                }
+                let mut separated = None;
+                if entry.separated > 0 {
+                    if let LogEntry::Set {
+                        separated: Some(location),
+                        ..
+                    } = self.read_record(entry.pointer)?
+                    {
+                        separated = Some(match &new_values {
+                            Some(values) => self.copy_separated(location, values, &throttle)?,
+                            None => location,
+                        });
+                    }
+                }
+                let value = if entry.blob.is_some() || separated.is_some() || !chunks.is_empty() {
+                    Some(Vec::new())
+                } else {
+                    self.read_log_entry(key, entry.pointer)?
+                };
+                if let Some(value) = value {
+                    let (value, compressed) = self.encode_value(value);
+                    let log_entry = LogEntry::Set {
+                        key: key.clone(),
+                        value,
+                        expires_at: entry.expires_at,
+                        token: None,
+                        seq: entry.seq,
+                        time: entry.time,
+                        created: entry.created,
+                        blob: entry.blob,
+                        chunks,
+                        separated,
+                        compressed,
+                    };
+                    let buf = seal_record(&options, &log_entry)?;
+                    compactor.write_all(&buf)?;
+                    let len = buf.len() as u64 + chunk_bytes;
+                    index.insert(
+                        key.clone(),
+                        IndexEntry {
+                            pointer,
+                            len,
+                            separated: separated.map_or(0, |location| location.len),
+                            ..*entry
+                        },
+                    );
+                    pointer += buf.len() as u64;
+                }
+            }
+            // Removals are kept for the retention period so readers of the log still see them
+            for (key, seq, time) in
+                self.tombstones
+                    .retained(&index, now, self.options.tombstone_retention)
+            {
+                let log_entry = LogEntry::Remove {
+                    key: key.to_vec(),
+                    token: None,
+                    seq,
+                    time,
+                };
+                let buf = seal_record(&options, &log_entry)?;
+                compactor.write_all(&buf)?;
+                tombstones.record(key.to_vec(), seq, time);
+                pointer += buf.len() as u64;
+            }
+            for (token, batch) in &self.prepared {
+                let log_entry = LogEntry::Prepare {
+                    token: *token,
+                    ops: batch.ops.clone(),
+                };
+                let buf = seal_record(&options, &log_entry)?;
+                compactor.write_all(&buf)?;
+                prepared.push((*token, pointer));
+                pointer += buf.len() as u64;
+            }
+            let log_entry = LogEntry::Checkpoint {
+                seq: self.seq,
+                tokens: self.tokens.order.iter().cloned().collect(),
+            };
+            compactor.write_all(&seal_record(&options, &log_entry)?)?;
+            compactor.flush()?;
+        }
+        // The new files must be on disk before they replace the old ones
+        new_log.sync()?;
+        if let Some(values) = &new_values {
+            values.sync()?;
+        }
+        if self.options.direct_io {
+            new_log.drop_cached()?;
+            if let Some(values) = &new_values {
+                values.drop_cached()?;
+            }
+        }
+
+        if self.options.paranoid_checks {
+            verify_index(&new_log.read_handle(&new_path)?, &index, &options)?;
+        }
+
+        if new_log.is_memory() {
+            self.log = new_log;
+            self.options.format = format;
+            if new_values.is_some() {
+                self.values = new_values;
+            }
+        } else {
+            std::mem::drop(new_log);
+            self.close_files();
+            let mut renamed = self.options.rename_file(&new_path, &old_path);
+            if renamed.is_ok() {
+                self.options.format = format;
+            }
+            if let Some(values) = new_values {
+                std::mem::drop(values);
+                // A crash between the two renames is finished on the next open
+                if renamed.is_ok() {
+                    renamed = self
+                        .options
+                        .rename_file(&values_path, &self.path.with_extension("vlog"));
+                }
+            }
+            if renamed.is_ok() {
+                renamed = self.options.sync_dir(&old_path);
+            }
+            self.reopen_files()?;
+            renamed?;
+        }
+        self.reset_read_caches();
+        self.index = index;
+        self.versions = versions;
+        self.tombstones = tombstones;
+        self.blobs = blobs;
+        self.blob_hashes = blob_hashes;
+        for (token, pointer) in prepared {
+            if let Some(batch) = self.prepared.get_mut(&token) {
+                batch.pointer = pointer;
+            }
+        }
+        self.path = old_path;
+        self.compaction_counter = 0;
+        Ok(())
+    }
+}
+
+impl Drop for KvStore {
+    /// Syncs the log and value log, so that writes made under `SyncPolicy::Never` are on disk
+    /// once the store has gone out of scope
+    fn drop(&mut self) {
+        if self.options.read_only || self.temp_dir.is_some() {
+            return;
+        }
+        if let Err(err) = self.sync_logs() {
+            ::log::warn!("failed to sync {} on close: {}", self.path.display(), err);
+        }
+        if self.options.warm_cache {
+            if let Err(err) = self.save_hot_keys() {
+                ::log::warn!(
+                    "failed to save hot keys of {}: {}",
+                    self.path.display(),
+                    err
+                );
+            }
+        }
     }
 }
-*/
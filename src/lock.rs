@@ -0,0 +1,63 @@
+//! Locks with a lease, for coordinating exclusive work between users of a store
+//!
+//! Locks are plain keys holding the token of their holder and expiring with the lease, so a
+//! holder that dies without releasing its lock loses it once the lease runs out.
+use crate::clock::now_millis;
+use crate::{KvStore, Result};
+use std::time::Duration;
+
+impl KvStore {
+    /// Takes the lock `name` for `ttl`, unless another holder has it
+    ///
+    /// Returns the token proving ownership, or `None` if the lock is held. The lock is stored
+    /// as the key `name`, so lock names should not clash with other keys.
+    pub fn acquire_lock(&mut self, name: &str, ttl: Duration) -> Result<Option<u64>> {
+        if self.live_entry(name.as_bytes()).is_some() {
+            return Ok(None);
+        }
+        let token = self.next_token();
+        self.write_value(
+            name.as_bytes().to_vec(),
+            token.to_string().into_bytes(),
+            Some(expiry(ttl)),
+            None,
+        )?;
+        Ok(Some(token))
+    }
+
+    /// Extends the lease on a lock to `ttl` from now, if `token` still holds it
+    ///
+    /// Returns whether the lease was extended; `false` means the lock expired and may have
+    /// been taken by someone else.
+    pub fn renew_lock(&mut self, name: &str, token: u64, ttl: Duration) -> Result<bool> {
+        if !self.holds_lock(name, token)? {
+            return Ok(false);
+        }
+        self.write_value(
+            name.as_bytes().to_vec(),
+            token.to_string().into_bytes(),
+            Some(expiry(ttl)),
+            None,
+        )?;
+        Ok(true)
+    }
+
+    /// Releases a lock, if `token` still holds it
+    ///
+    /// Returns whether the lock was released; a stale token never releases a lock taken since.
+    pub fn release_lock(&mut self, name: &str, token: u64) -> Result<bool> {
+        if !self.holds_lock(name, token)? {
+            return Ok(false);
+        }
+        self.remove_bytes(name.as_bytes())?;
+        Ok(true)
+    }
+
+    fn holds_lock(&mut self, name: &str, token: u64) -> Result<bool> {
+        Ok(self.get_bytes(name.as_bytes())? == Some(token.to_string().into_bytes()))
+    }
+}
+
+fn expiry(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
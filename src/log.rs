@@ -0,0 +1,178 @@
+use crate::storage::StorageFile;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Storage backing the log of a store: a file, a file of a custom `Storage`, or a buffer for
+/// stores kept only in memory
+pub(crate) enum Log {
+    File(File),
+    Storage(Arc<dyn StorageFile>),
+    Memory(Arc<Mutex<Vec<u8>>>),
+}
+
+impl Log {
+    pub(crate) fn memory() -> Log {
+        Log::with_contents(Vec::new())
+    }
+
+    pub(crate) fn with_contents(buf: Vec<u8>) -> Log {
+        Log::Memory(Arc::new(Mutex::new(buf)))
+    }
+
+    pub(crate) fn is_memory(&self) -> bool {
+        match self {
+            Log::File(_) | Log::Storage(_) => false,
+            Log::Memory(_) => true,
+        }
+    }
+
+    /// Returns a second read-only handle on the same log, which keeps seeing the current
+    /// contents even after the store compacts into a new log
+    pub(crate) fn read_handle(&self, path: &Path) -> io::Result<Log> {
+        match self {
+            Log::File(_) => File::open(path).map(Log::File),
+            Log::Storage(file) => Ok(Log::Storage(file.clone())),
+            Log::Memory(buf) => Ok(Log::Memory(buf.clone())),
+        }
+    }
+
+    pub(crate) fn len(&self) -> io::Result<u64> {
+        match self {
+            Log::File(file) => Ok(file.metadata()?.len()),
+            Log::Storage(file) => file.len(),
+            Log::Memory(buf) => Ok(lock(buf).len() as u64),
+        }
+    }
+
+    /// Returns a reader over the log that must be positioned with `seek` before reading
+    pub(crate) fn reader(&self) -> LogReader<'_> {
+        match self {
+            Log::File(file) => LogReader::File(file),
+            Log::Storage(file) => LogReader::Storage(file.as_ref(), 0),
+            Log::Memory(buf) => LogReader::Memory(buf, 0),
+        }
+    }
+
+    /// Returns a writer that appends to the end of the log
+    pub(crate) fn writer(&self) -> LogWriter<'_> {
+        match self {
+            Log::File(file) => LogWriter::File(file),
+            Log::Storage(file) => LogWriter::Storage(file.as_ref()),
+            Log::Memory(buf) => LogWriter::Memory(buf),
+        }
+    }
+
+    /// Drops the pages of a log written through to the disk from the page cache
+    pub(crate) fn drop_cached(&self) -> io::Result<()> {
+        match self {
+            Log::File(file) => crate::direct::drop_cached(file),
+            Log::Storage(_) | Log::Memory(_) => Ok(()),
+        }
+    }
+
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        match self {
+            Log::File(file) => file.sync_data(),
+            Log::Storage(file) => file.sync(),
+            Log::Memory(_) => Ok(()),
+        }
+    }
+}
+
+fn lock(buf: &Mutex<Vec<u8>>) -> std::sync::MutexGuard<'_, Vec<u8>> {
+    buf.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+pub(crate) enum LogReader<'a> {
+    File(&'a File),
+    Storage(&'a dyn StorageFile, u64),
+    Memory(&'a Mutex<Vec<u8>>, u64),
+}
+
+impl<'a> Read for LogReader<'a> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        match self {
+            LogReader::File(file) => file.read(out),
+            LogReader::Storage(file, pos) => {
+                let len = file.read_at(out, *pos)?;
+                *pos += len as u64;
+                Ok(len)
+            }
+            LogReader::Memory(buf, pos) => {
+                let buf = lock(buf);
+                let start = (*pos as usize).min(buf.len());
+                let len = out.len().min(buf.len() - start);
+                out[..len].copy_from_slice(&buf[start..start + len]);
+                *pos += len as u64;
+                Ok(len)
+            }
+        }
+    }
+}
+
+impl<'a> Seek for LogReader<'a> {
+    fn seek(&mut self, to: SeekFrom) -> io::Result<u64> {
+        match self {
+            LogReader::File(file) => file.seek(to),
+            LogReader::Storage(file, pos) => {
+                let len = file.len()?;
+                seek_to(pos, len, to)
+            }
+            LogReader::Memory(buf, pos) => {
+                let len = lock(buf).len() as u64;
+                seek_to(pos, len, to)
+            }
+        }
+    }
+}
+
+/// Moves the position of a reader over a log of `len` bytes
+fn seek_to(pos: &mut u64, len: u64, to: SeekFrom) -> io::Result<u64> {
+    let target = match to {
+        SeekFrom::Start(offset) => Some(offset),
+        SeekFrom::End(delta) => offset_by(len, delta),
+        SeekFrom::Current(delta) => offset_by(*pos, delta),
+    };
+    *pos = target
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start of log"))?;
+    Ok(*pos)
+}
+
+fn offset_by(base: u64, delta: i64) -> Option<u64> {
+    if delta < 0 {
+        base.checked_sub(delta.unsigned_abs())
+    } else {
+        base.checked_add(delta as u64)
+    }
+}
+
+pub(crate) enum LogWriter<'a> {
+    File(&'a File),
+    Storage(&'a dyn StorageFile),
+    Memory(&'a Mutex<Vec<u8>>),
+}
+
+impl<'a> Write for LogWriter<'a> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            LogWriter::File(file) => file.write(data),
+            LogWriter::Storage(file) => {
+                file.append(data)?;
+                Ok(data.len())
+            }
+            LogWriter::Memory(buf) => {
+                lock(buf).extend_from_slice(data);
+                Ok(data.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            LogWriter::File(file) => file.flush(),
+            LogWriter::Storage(_) | LogWriter::Memory(_) => Ok(()),
+        }
+    }
+}
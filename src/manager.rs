@@ -0,0 +1,146 @@
+use crate::{open_locked, valid_name, KvError, KvStore, Options, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Handle on a store opened through a `KvStoreManager`, shared by everyone who opened it
+pub type StoreHandle = Arc<Mutex<KvStore>>;
+
+/// Directory of named stores, each kept in its own subdirectory with its own log and lock
+///
+/// Opening the same name twice returns the same handle, so every thread writes through one
+/// open store. Every method takes `&self`, so a manager can be shared between threads.
+pub struct KvStoreManager {
+    root: PathBuf,
+    options: Options,
+    stores: Mutex<HashMap<String, StoreHandle>>,
+}
+
+impl KvStoreManager {
+    /// Manages stores in a directory, creating it if needed
+    pub fn open(root: &Path) -> Result<KvStoreManager> {
+        Options::default().open_manager(root)
+    }
+
+    /// Returns the directory holding the stores
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Creates a new store, failing with `KvError::StoreExists` if there is one by that name
+    ///
+    /// Names follow the rules of bucket names.
+    pub fn create(&self, name: &str) -> Result<StoreHandle> {
+        let mut stores = self.stores();
+        if self.exists(name)? {
+            return Err(KvError::StoreExists);
+        }
+        self.load(&mut stores, name)
+    }
+
+    /// Returns a handle on an existing store, failing with `KvError::StoreNotFound` if there is
+    /// none by that name
+    pub fn get(&self, name: &str) -> Result<StoreHandle> {
+        let mut stores = self.stores();
+        if !self.exists(name)? {
+            return Err(KvError::StoreNotFound);
+        }
+        self.load(&mut stores, name)
+    }
+
+    /// Returns a handle on a store, creating it if needed
+    pub fn get_or_create(&self, name: &str) -> Result<StoreHandle> {
+        let mut stores = self.stores();
+        self.exists(name)?;
+        self.load(&mut stores, name)
+    }
+
+    /// Lists the names of all stores, sorted
+    pub fn list(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                if valid_name(name) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Deletes a store and all of its files
+    ///
+    /// Fails with `KvError::StoreInUse` while any handle on the store is still held or another
+    /// process has it open, and with `KvError::StoreNotFound` if there is no store by that name.
+    pub fn drop_store(&self, name: &str) -> Result<()> {
+        let mut stores = self.stores();
+        if !self.exists(name)? {
+            return Err(KvError::StoreNotFound);
+        }
+        let dir = self.root.join(name);
+        match stores.remove(name) {
+            Some(handle) => match Arc::try_unwrap(handle) {
+                // Dropping the store closes its files, which Windows requires before removing them
+                Ok(store) => drop(store),
+                Err(handle) => {
+                    stores.insert(name.to_string(), handle);
+                    return Err(KvError::StoreInUse);
+                }
+            },
+            // A store this manager never opened may still be open in another process
+            None => {
+                let log = dir.join("data.log");
+                if log.exists() {
+                    match open_locked(&log, false) {
+                        Ok(log) => drop(log),
+                        Err(KvError::AlreadyLocked) => return Err(KvError::StoreInUse),
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+        }
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    fn stores(&self) -> MutexGuard<'_, HashMap<String, StoreHandle>> {
+        self.stores.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Checks a name, and returns whether a store by that name exists
+    fn exists(&self, name: &str) -> Result<bool> {
+        if !valid_name(name) {
+            return Err(KvError::InvalidBucketName);
+        }
+        Ok(self.root.join(name).is_dir())
+    }
+
+    fn load(&self, stores: &mut HashMap<String, StoreHandle>, name: &str) -> Result<StoreHandle> {
+        if let Some(handle) = stores.get(name) {
+            return Ok(handle.clone());
+        }
+        let dir = self.root.join(name);
+        fs::create_dir_all(&dir)?;
+        let handle = Arc::new(Mutex::new(self.options.open(&dir)?));
+        stores.insert(name.to_string(), handle.clone());
+        Ok(handle)
+    }
+}
+
+impl Options {
+    /// Manages stores in a directory, opening each with these settings
+    pub fn open_manager(&self, root: &Path) -> Result<KvStoreManager> {
+        fs::create_dir_all(root)?;
+        Ok(KvStoreManager {
+            root: root.to_path_buf(),
+            options: self.clone(),
+            stores: Mutex::new(HashMap::new()),
+        })
+    }
+}
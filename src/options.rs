@@ -0,0 +1,429 @@
+use crate::cache::{BlockCache, ValueCache};
+use crate::codec::LogFormat;
+#[cfg(feature = "encryption")]
+use crate::crypto::Cipher;
+use crate::log::Log;
+use crate::order::Comparator;
+use crate::storage::{SharedStorage, Storage};
+use crate::{Eviction, KvError, KvStore, Result};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Controls when writes are flushed to stable storage
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Leave flushing to the operating system
+    Never,
+    /// Sync the log after every write
+    Always,
+}
+
+/// Settings used to open a store, created with `KvStore::options`
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub(crate) cache_capacity: usize,
+    pub(crate) cache_bytes: Option<usize>,
+    pub(crate) eviction: Eviction,
+    pub(crate) read_ahead: usize,
+    pub(crate) warm_cache: bool,
+    pub(crate) block_cache_bytes: Option<usize>,
+    pub(crate) compaction_threshold: u32,
+    pub(crate) compaction_rate: Option<u64>,
+    pub(crate) sync: SyncPolicy,
+    pub(crate) read_only: bool,
+    pub(crate) create_if_missing: bool,
+    pub(crate) error_if_exists: bool,
+    pub(crate) mmap: bool,
+    pub(crate) direct_io: bool,
+    pub(crate) preallocate: Option<u64>,
+    pub(crate) paranoid_checks: bool,
+    pub(crate) strict_open: bool,
+    pub(crate) value_log_threshold: Option<usize>,
+    pub(crate) dedup_values: bool,
+    pub(crate) max_key_size: Option<usize>,
+    pub(crate) max_value_size: Option<usize>,
+    pub(crate) quota: Option<u64>,
+    pub(crate) max_keys: Option<usize>,
+    pub(crate) quota_eviction: bool,
+    pub(crate) write_stall: Option<(u64, u64)>,
+    pub(crate) retained_versions: usize,
+    pub(crate) tombstone_retention: Duration,
+    pub(crate) comparator: Option<Comparator>,
+    pub(crate) slow_op_threshold: Option<Duration>,
+    pub(crate) storage: Option<SharedStorage>,
+    pub(crate) format: LogFormat,
+    #[cfg(feature = "compression")]
+    pub(crate) compression: bool,
+    #[cfg(feature = "encryption")]
+    pub(crate) cipher: Option<Cipher>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            cache_capacity: 100,
+            cache_bytes: None,
+            eviction: Eviction::Lru,
+            read_ahead: 0,
+            warm_cache: false,
+            block_cache_bytes: None,
+            compaction_threshold: 1000,
+            compaction_rate: None,
+            sync: SyncPolicy::Never,
+            read_only: false,
+            create_if_missing: true,
+            error_if_exists: false,
+            mmap: false,
+            direct_io: false,
+            preallocate: None,
+            paranoid_checks: false,
+            strict_open: false,
+            value_log_threshold: None,
+            dedup_values: false,
+            max_key_size: None,
+            max_value_size: None,
+            quota: None,
+            max_keys: None,
+            quota_eviction: false,
+            write_stall: None,
+            retained_versions: 0,
+            tombstone_retention: Duration::from_secs(0),
+            comparator: None,
+            slow_op_threshold: None,
+            storage: None,
+            format: LogFormat::MessagePack,
+            #[cfg(feature = "compression")]
+            compression: false,
+            #[cfg(feature = "encryption")]
+            cipher: None,
+        }
+    }
+}
+
+impl Options {
+    /// Sets the number of values kept in the read cache
+    pub fn cache_capacity(mut self, capacity: usize) -> Options {
+        self.cache_capacity = capacity;
+        self
+    }
+
+    /// Limits the total size of the keys and values kept in the read cache
+    pub fn cache_bytes(mut self, bytes: usize) -> Options {
+        self.cache_bytes = Some(bytes);
+        self
+    }
+
+    /// Caches the values of up to `records` live keys stored after each value read from disk
+    ///
+    /// Suits stores whose keys are read in about the order they were written, such as after
+    /// batch writes. Has no effect with the block cache, which already keeps neighbouring
+    /// records.
+    pub fn read_ahead(mut self, records: usize) -> Options {
+        self.read_ahead = records;
+        self
+    }
+
+    /// Saves the keys in the read cache when the store is compacted or closed, and reads their
+    /// values back into the cache when it is opened again
+    ///
+    /// Avoids the slow reads of a cold cache in the first minutes after a restart.
+    pub fn warm_cache(mut self, warm: bool) -> Options {
+        self.warm_cache = warm;
+        self
+    }
+
+    /// Sets how the read cache chooses values to evict
+    pub fn eviction(mut self, eviction: Eviction) -> Options {
+        self.eviction = eviction;
+        self
+    }
+
+    /// Caches blocks of the log file totalling up to `bytes` in place of the per-value cache
+    ///
+    /// Values read close together in the log share cached blocks, and large values are not held
+    /// a second time as whole values.
+    pub fn block_cache(mut self, bytes: usize) -> Options {
+        self.block_cache_bytes = Some(bytes);
+        self
+    }
+
+    /// Sets the number of stale writes after which the log is compacted automatically
+    pub fn compaction_threshold(mut self, threshold: u32) -> Options {
+        self.compaction_threshold = threshold;
+        self
+    }
+
+    /// Limits compaction to writing about `bytes` per second to the new log and value log, so it
+    /// does not starve other users of a shared disk
+    ///
+    /// Compaction reads about as much as it writes, so this paces its reads too. A store is
+    /// unavailable while it compacts, so a low limit makes compactions correspondingly longer
+    /// pauses.
+    pub fn compaction_rate_limit(mut self, bytes: u64) -> Options {
+        self.compaction_rate = Some(bytes);
+        self
+    }
+
+    /// Logs reads, sets and removes that take at least `threshold` as warnings through the `log`
+    /// crate, with the time spent reading the log, appending, syncing and compacting
+    pub fn slow_op_threshold(mut self, threshold: Duration) -> Options {
+        self.slow_op_threshold = Some(threshold);
+        self
+    }
+
+    /// Sets when writes are flushed to stable storage
+    pub fn sync_policy(mut self, sync: SyncPolicy) -> Options {
+        self.sync = sync;
+        self
+    }
+
+    /// Opens the store without write access; writes fail with `KvError::ReadOnly`
+    pub fn read_only(mut self, read_only: bool) -> Options {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Creates the store if there is none at the path, which is the default; when turned off,
+    /// opening a missing store fails with `KvError::StoreNotFound`
+    pub fn create_if_missing(mut self, create: bool) -> Options {
+        self.create_if_missing = create;
+        self
+    }
+
+    /// Fails with `KvError::StoreExists` if there already is a store at the path, so that a new
+    /// store is never initialized over an existing one
+    pub fn error_if_exists(mut self, error: bool) -> Options {
+        self.error_if_exists = error;
+        self
+    }
+
+    /// Reads records from a memory mapping of the log instead of seeking and reading the file
+    pub fn mmap(mut self, mmap: bool) -> Options {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Writes the log, value log and compaction output through to the disk and keeps them out of
+    /// the page cache, so values are not cached twice and each write pays its own flush
+    ///
+    /// Appends return once the record is on the disk, which makes them slower but `sync` cheap.
+    /// Where the platform offers no way to bypass the page cache, writes are still written
+    /// through. Has no effect on custom storage.
+    pub fn direct_io(mut self, direct: bool) -> Options {
+        self.direct_io = direct;
+        self
+    }
+
+    /// Reserves disk space for the log and value log `bytes` at a time ahead of appends, so the
+    /// file system allocates them in large extents instead of with every append
+    ///
+    /// The reserved space does not count towards the length of the files. Only Linux supports
+    /// it; elsewhere files grow as they are appended to.
+    pub fn preallocate(mut self, bytes: u64) -> Options {
+        self.preallocate = Some(bytes);
+        self
+    }
+
+    /// Checks that every record read for a key is a record of that key, and that the index built
+    /// by a compaction matches the new log before it replaces the old one
+    ///
+    /// A mismatch fails with `KvError::Corruption` instead of returning the value of another key.
+    /// Each check costs a little time on every read and a full read of the new log per compaction.
+    pub fn paranoid_checks(mut self, paranoid: bool) -> Options {
+        self.paranoid_checks = paranoid;
+        self
+    }
+
+    /// Fails to open a store whose log holds a record that cannot be decoded with
+    /// `KvError::Corruption`, giving the offset of the record
+    ///
+    /// By default the log is taken to end before such a record, which recovers from a write
+    /// torn by a crash but also hides damage in the middle of the log along with every record
+    /// after it.
+    pub fn strict_open(mut self, strict: bool) -> Options {
+        self.strict_open = strict;
+        self
+    }
+
+    /// Stores values larger than `bytes` in a separate value log, so compacting the main log
+    /// does not copy them
+    ///
+    /// The value log is itself only rewritten by a compaction once at least half of it is stale.
+    pub fn value_log_threshold(mut self, bytes: usize) -> Options {
+        self.value_log_threshold = Some(bytes);
+        self
+    }
+
+    /// Stores identical values once in the value log and shares them between keys
+    ///
+    /// A shared value is kept while any live key refers to it and dropped by the compaction
+    /// after its last reference goes. Values written this way stay readable when the store is
+    /// opened without de-duplication.
+    pub fn value_dedup(mut self, dedup: bool) -> Options {
+        self.dedup_values = dedup;
+        self
+    }
+
+    /// Rejects writes of keys longer than `bytes` with `KvError::KeyTooLarge`
+    pub fn max_key_size(mut self, bytes: usize) -> Options {
+        self.max_key_size = Some(bytes);
+        self
+    }
+
+    /// Rejects writes of values longer than `bytes` with `KvError::ValueTooLarge`
+    pub fn max_value_size(mut self, bytes: usize) -> Options {
+        self.max_value_size = Some(bytes);
+        self
+    }
+
+    /// Limits the size of the log and value log to `bytes`
+    ///
+    /// A write that would exceed the quota first compacts the log, and fails with
+    /// `KvError::QuotaExceeded` if that does not free enough space.
+    pub fn quota(mut self, bytes: u64) -> Options {
+        self.quota = Some(bytes);
+        self
+    }
+
+    /// Limits the number of live keys to `keys`
+    ///
+    /// A write that would add a key beyond the limit fails with `KvError::QuotaExceeded`, or
+    /// evicts the least recently used keys if quota eviction is on; writes to existing keys are
+    /// always allowed.
+    pub fn max_keys(mut self, keys: usize) -> Options {
+        self.max_keys = Some(keys);
+        self
+    }
+
+    /// Evicts the least recently used keys instead of failing writes that exceed the quota or
+    /// the key limit
+    ///
+    /// Together with `max_keys` this makes the store a persistent cache that never grows past a
+    /// fixed number of keys.
+    pub fn quota_eviction(mut self, evict: bool) -> Options {
+        self.quota_eviction = evict;
+        self
+    }
+
+    /// Slows writes down once the log and value log hold more than `soft` bytes of stale
+    /// records, and refuses them with `KvError::CompactionBackpressure` at `hard` bytes
+    ///
+    /// Stale records pile up when compaction cannot reclaim them, such as when it fails or when
+    /// retained versions and removals hold on to them. Writes are delayed by up to 100ms, more
+    /// the closer stale bytes are to `hard`, and the log is compacted once more before a write
+    /// is refused.
+    pub fn write_stall(mut self, soft: u64, hard: u64) -> Options {
+        self.write_stall = Some((soft, hard.max(soft + 1)));
+        self
+    }
+
+    /// Keeps up to `versions` superseded values or removals of each key readable with
+    /// `KvStore::get_at`, including across compactions
+    pub fn retained_versions(mut self, versions: usize) -> Options {
+        self.retained_versions = versions;
+        self
+    }
+
+    /// Keeps the records of key removals through compactions until they are `retention` old
+    ///
+    /// Removals are otherwise dropped by the first compaction after them, and with them the
+    /// chance for `KvStore::journal` and other readers of the log to see them.
+    pub fn tombstone_retention(mut self, retention: Duration) -> Options {
+        self.tombstone_retention = retention;
+        self
+    }
+
+    /// Orders keys in scans and key listings with `compare` instead of by their bytes
+    ///
+    /// The comparator is not persisted and must be given again when reopening. `natural_order`
+    /// orders numbers embedded in keys by value.
+    pub fn comparator<F>(mut self, compare: F) -> Options
+    where
+        F: Fn(&[u8], &[u8]) -> Ordering + Send + Sync + 'static,
+    {
+        self.comparator = Some(Comparator::new(compare));
+        self
+    }
+
+    /// Keeps the log, value log and bucket logs in `storage` instead of the file system
+    ///
+    /// Files of a custom storage are not locked, so nothing stops two stores from writing the
+    /// same files. Backups are still read from and written to the file system.
+    pub fn storage<S: Storage + 'static>(mut self, storage: S) -> Options {
+        self.storage = Some(SharedStorage(Arc::new(storage)));
+        self
+    }
+
+    /// Sets the encoding of log records for a new store; the default is MessagePack
+    ///
+    /// The format is recorded in the header of the log, so an existing store keeps the format
+    /// it was created with, until a compaction rewrites its log in the format given here.
+    pub fn log_format(mut self, format: LogFormat) -> Options {
+        self.format = format;
+        self
+    }
+
+    /// Compresses values stored inline in the log with Snappy
+    ///
+    /// Records written without compression stay readable, so this can be turned on for an
+    /// existing store. Reading compressed values requires the `compression` feature.
+    #[cfg(feature = "compression")]
+    pub fn compression(mut self, compression: bool) -> Options {
+        self.compression = compression;
+        self
+    }
+
+    /// Encrypts every record written to the log and value log with AES-256-GCM under `key`
+    ///
+    /// A store with encrypted records cannot be opened without the same key. Records written
+    /// before the key was set stay readable and are encrypted by the next compaction.
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(mut self, key: &[u8; 32]) -> Options {
+        self.cipher = Some(Cipher::new(key));
+        self
+    }
+
+    /// Opens the store at the given path with these settings
+    pub fn open(&self, path: &Path) -> Result<KvStore> {
+        KvStore::open_until(path, None, self.clone())
+    }
+
+    /// Creates an empty store with these settings that is held in memory and never touches disk
+    pub fn open_in_memory(&self) -> Result<KvStore> {
+        KvStore::load(PathBuf::new(), Log::memory(), None, None, self.clone())
+    }
+}
+
+impl Options {
+    /// Checks a key and the length of the value about to be written for it against the limits
+    pub(crate) fn check_size(&self, key: &[u8], value_len: usize) -> Result<()> {
+        match (self.max_key_size, self.max_value_size) {
+            (Some(max), _) if key.len() > max => Err(KvError::KeyTooLarge),
+            (_, Some(max)) if value_len > max => Err(KvError::ValueTooLarge),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn new_value_cache(&self) -> ValueCache {
+        let capacity = match self.block_cache_bytes {
+            Some(_) => 0,
+            None => self.cache_capacity,
+        };
+        ValueCache::new(capacity, self.cache_bytes, self.eviction)
+    }
+
+    pub(crate) fn new_block_cache(&self) -> Option<RefCell<BlockCache>> {
+        self.block_cache_bytes
+            .map(|bytes| RefCell::new(BlockCache::new(bytes)))
+    }
+}
+
+impl KvStore {
+    /// Returns a builder for opening a store with non-default settings
+    pub fn options() -> Options {
+        Options::default()
+    }
+}
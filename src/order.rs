@@ -0,0 +1,88 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+type CompareFn = dyn Fn(&[u8], &[u8]) -> Ordering + Send + Sync;
+
+/// Ordering of keys used by scans in place of byte order
+#[derive(Clone)]
+pub(crate) struct Comparator(Arc<CompareFn>);
+
+impl Comparator {
+    pub(crate) fn new<F>(compare: F) -> Comparator
+    where
+        F: Fn(&[u8], &[u8]) -> Ordering + Send + Sync + 'static,
+    {
+        Comparator(Arc::new(compare))
+    }
+
+    pub(crate) fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        (self.0)(a, b)
+    }
+
+    pub(crate) fn sort<K: AsRef<[u8]>>(&self, keys: &mut [K]) {
+        keys.sort_by(|a, b| self.compare(a.as_ref(), b.as_ref()));
+    }
+
+    /// Returns whether a key falls within a range under this ordering
+    pub(crate) fn contains(&self, range: &impl RangeBounds<Vec<u8>>, key: &[u8]) -> bool {
+        let above = match range.start_bound() {
+            Bound::Included(start) => self.compare(key, start) != Ordering::Less,
+            Bound::Excluded(start) => self.compare(key, start) == Ordering::Greater,
+            Bound::Unbounded => true,
+        };
+        let below = match range.end_bound() {
+            Bound::Included(end) => self.compare(key, end) != Ordering::Greater,
+            Bound::Excluded(end) => self.compare(key, end) == Ordering::Less,
+            Bound::Unbounded => true,
+        };
+        above && below
+    }
+}
+
+impl fmt::Debug for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Comparator(..)")
+    }
+}
+
+/// Compares keys with runs of ASCII digits ordered by their numeric value, so that `item2`
+/// sorts before `item10`
+///
+/// Keys that differ only in leading zeros are ordered by their bytes.
+pub fn natural_order(a: &[u8], b: &[u8]) -> Ordering {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+            let (x, next_i) = digits(a, i);
+            let (y, next_j) = digits(b, j);
+            let order = x.len().cmp(&y.len()).then_with(|| x.cmp(y));
+            if order != Ordering::Equal {
+                return order;
+            }
+            i = next_i;
+            j = next_j;
+        } else {
+            if a[i] != b[j] {
+                return a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+    }
+    (a.len() - i).cmp(&(b.len() - j)).then_with(|| a.cmp(b))
+}
+
+/// Returns the run of digits starting at `start` without leading zeros, and the end of the run
+fn digits(key: &[u8], start: usize) -> (&[u8], usize) {
+    let end = key[start..]
+        .iter()
+        .position(|byte| !byte.is_ascii_digit())
+        .map_or(key.len(), |len| start + len);
+    let first = key[start..end]
+        .iter()
+        .position(|&byte| byte != b'0')
+        .map_or(end, |zeros| start + zeros);
+    (&key[first..end], end)
+}
@@ -0,0 +1,52 @@
+//! Reserving disk space for logs ahead of appends
+//!
+//! On Linux space is reserved with `fallocate` and `FALLOC_FL_KEEP_SIZE`, which allocates
+//! blocks past the end of the file without changing its length, so replay and the offsets of
+//! appended records are unaffected. Other platforms and file systems without support for it
+//! leave files to grow as they are appended to.
+use crate::log::Log;
+use std::fs::File;
+use std::io;
+
+/// Reserves space for a log up to the next multiple of `chunk` bytes after `end`
+pub(crate) fn reserve(log: &Log, end: u64, chunk: u64) -> io::Result<()> {
+    match log {
+        Log::File(file) if chunk > 0 => allocate(file, (end / chunk + 1) * chunk),
+        _ => Ok(()),
+    }
+}
+
+/// Reserves more space for a log if an append from `start` to `end` crosses into a new chunk
+pub(crate) fn extend(log: &Log, start: u64, end: u64, chunk: u64) -> io::Result<()> {
+    if chunk > 0 && start / chunk != end / chunk {
+        reserve(log, end, chunk)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn allocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            0,
+            len as libc::off_t,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) => Ok(()),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate(_file: &File, _len: u64) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,151 @@
+//! Python module exposing a store as a dict-like `KvStore` class
+//!
+//! Build the extension with `maturin build`, which enables the features set in `pyproject.toml`.
+//! Keys and values may be given as `bytes` or `str`, and are always returned as `bytes`.
+use crate::{KvError, KvStore};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyKeyError, PyOSError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyList};
+use std::path::PathBuf;
+
+create_exception!(kvs, KvsError, PyException, "Error raised by the store");
+
+impl From<KvError> for PyErr {
+    fn from(err: KvError) -> PyErr {
+        match err {
+            KvError::IoError(err) => PyOSError::new_err(err.to_string()),
+            err => KvsError::new_err(err.to_string()),
+        }
+    }
+}
+
+/// Key or value given from Python
+#[derive(FromPyObject)]
+enum Bytes {
+    Bytes(Vec<u8>),
+    Str(String),
+}
+
+impl Bytes {
+    fn into_vec(self) -> Vec<u8> {
+        match self {
+            Bytes::Bytes(bytes) => bytes,
+            Bytes::Str(s) => s.into_bytes(),
+        }
+    }
+}
+
+/// Store opened from Python, usable until `close` is called
+#[pyclass(name = "KvStore", module = "kvs", unsendable)]
+struct PyKvStore {
+    store: Option<KvStore>,
+}
+
+impl PyKvStore {
+    fn store(&mut self) -> PyResult<&mut KvStore> {
+        self.store
+            .as_mut()
+            .ok_or_else(|| PyValueError::new_err("store is closed"))
+    }
+}
+
+#[pymethods]
+impl PyKvStore {
+    #[new]
+    fn new(path: PathBuf) -> PyResult<PyKvStore> {
+        let store = KvStore::open(&path)?;
+        Ok(PyKvStore { store: Some(store) })
+    }
+
+    fn __getitem__<'py>(&mut self, py: Python<'py>, key: Bytes) -> PyResult<Bound<'py, PyBytes>> {
+        let key = key.into_vec();
+        match self.store()?.get_bytes(&key)? {
+            Some(value) => Ok(PyBytes::new(py, &value)),
+            None => Err(PyKeyError::new_err(PyBytes::new(py, &key).unbind())),
+        }
+    }
+
+    fn __setitem__(&mut self, key: Bytes, value: Bytes) -> PyResult<()> {
+        self.store()?.set_bytes(key.into_vec(), value.into_vec())?;
+        Ok(())
+    }
+
+    fn __delitem__(&mut self, py: Python<'_>, key: Bytes) -> PyResult<()> {
+        let key = key.into_vec();
+        match self.store()?.remove_bytes(&key) {
+            Ok(()) => Ok(()),
+            Err(KvError::KeyNotFound) => Err(PyKeyError::new_err(PyBytes::new(py, &key).unbind())),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn __contains__(&mut self, key: Bytes) -> PyResult<bool> {
+        Ok(self.store()?.get_bytes(&key.into_vec())?.is_some())
+    }
+
+    fn __len__(&mut self) -> PyResult<usize> {
+        Ok(self.store()?.len())
+    }
+
+    fn __iter__<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        Ok(self.keys(py)?.as_any().try_iter()?.into_any())
+    }
+
+    /// Returns the value of a key, or `default` if it is not in the store
+    #[pyo3(signature = (key, default = None))]
+    fn get(&mut self, py: Python<'_>, key: Bytes, default: Option<PyObject>) -> PyResult<PyObject> {
+        match self.store()?.get_bytes(&key.into_vec())? {
+            Some(value) => Ok(PyBytes::new(py, &value).into_any().unbind()),
+            None => Ok(default.unwrap_or_else(|| py.None())),
+        }
+    }
+
+    /// Returns a list of all keys
+    fn keys<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        let store = self.store()?;
+        let keys: Vec<_> = store
+            .keys_bytes()
+            .map(|key| PyBytes::new(py, key))
+            .collect();
+        PyList::new(py, keys)
+    }
+
+    /// Flushes writes to stable storage
+    fn sync(&mut self) -> PyResult<()> {
+        self.store()?.sync()?;
+        Ok(())
+    }
+
+    /// Compacts the log
+    fn compact(&mut self) -> PyResult<()> {
+        self.store()?.compact()?;
+        Ok(())
+    }
+
+    /// Closes the store, releasing its lock; further use raises `ValueError`
+    fn close(&mut self) {
+        self.store = None;
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    fn __exit__(
+        &mut self,
+        _exc_type: PyObject,
+        _exc_value: PyObject,
+        _traceback: PyObject,
+    ) -> bool {
+        self.close();
+        false
+    }
+}
+
+#[pymodule]
+fn kvs(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyKvStore>()?;
+    m.add("KvsError", m.py().get_type::<KvsError>())?;
+    Ok(())
+}
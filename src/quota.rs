@@ -0,0 +1,150 @@
+use crate::{now_millis, KvError, KvStore, Result};
+
+impl KvStore {
+    /// Makes room for a write of about `incoming` bytes under the size quota, if one is set
+    ///
+    /// Stale records are compacted away first. If that is not enough, the least recently used
+    /// keys are evicted when quota eviction is enabled, and the write is refused otherwise.
+    pub(crate) fn reserve(&mut self, incoming: u64) -> Result<()> {
+        self.stall()?;
+        let quota = match self.options.quota {
+            Some(quota) => quota,
+            None => return Ok(()),
+        };
+        let usage = self.own_size()?;
+        if usage + incoming <= quota {
+            return Ok(());
+        }
+        // Compaction can only reclaim the stale part of the log, and the log is never quite as
+        // small as its live records, so it is only worth a rewrite if that could be enough
+        if usage - self.live_size().min(usage) >= usage + incoming - quota {
+            self.compact()?;
+            if self.own_size()? + incoming <= quota {
+                return Ok(());
+            }
+        }
+        if !self.options.quota_eviction {
+            return Err(KvError::QuotaExceeded);
+        }
+
+        // Evicted keys are removed like any other, so watchers and indexes see them go, and
+        // the compaction that follows reclaims their space
+        let now = now_millis();
+        let mut live = self.live_size();
+        for key in self.least_recently_used() {
+            if live + incoming <= quota {
+                break;
+            }
+            let entry = match self.index.get(&key) {
+                Some(entry) if !entry.is_expired(now) => *entry,
+                _ => continue,
+            };
+            self.delete_entry(key, None)?;
+            live = live.saturating_sub(entry.len + entry.separated);
+            self.evictions += 1;
+        }
+        self.compact()?;
+        if self.own_size()? + incoming > quota {
+            return Err(KvError::QuotaExceeded);
+        }
+        Ok(())
+    }
+
+    /// Refuses a write that would add `new_keys` keys beyond the key quota, if one is set
+    pub(crate) fn reserve_keys(&mut self, new_keys: usize) -> Result<()> {
+        let max_keys = match self.options.max_keys {
+            Some(max_keys) => max_keys,
+            None => return Ok(()),
+        };
+        // The index also holds expired keys, so live keys are only counted when it is full
+        if new_keys == 0 || self.index.len() + new_keys <= max_keys {
+            return Ok(());
+        }
+        let live = self.len();
+        if live + new_keys <= max_keys {
+            return Ok(());
+        }
+        if !self.options.quota_eviction || new_keys > max_keys {
+            return Err(KvError::QuotaExceeded);
+        }
+        // Evicted keys are removed like any other, so the removals are durable at once and
+        // compaction reclaims their space later
+        let now = now_millis();
+        let victims: Vec<Vec<u8>> = self
+            .least_recently_used()
+            .into_iter()
+            .filter(|key| {
+                self.index
+                    .get(key)
+                    .is_some_and(|entry| !entry.is_expired(now))
+            })
+            .take(live + new_keys - max_keys)
+            .collect();
+        for key in victims {
+            self.delete_entry(key, None)?;
+            self.evictions += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns the keys of the index ordered from least to most recently used
+    fn least_recently_used(&self) -> Vec<Vec<u8>> {
+        let mut candidates: Vec<(u64, &Vec<u8>)> = self
+            .index
+            .iter()
+            .map(|(key, entry)| {
+                let used = self
+                    .accessed
+                    .get(key)
+                    .map_or(entry.time, |&t| t.max(entry.time));
+                (used, key)
+            })
+            .collect();
+        candidates.sort();
+        candidates.into_iter().map(|(_, key)| key.clone()).collect()
+    }
+
+    /// Returns whether a key has no live value, so writing it adds a key
+    pub(crate) fn is_new_key(&self, key: &[u8]) -> bool {
+        self.index
+            .get(key)
+            .is_none_or(|entry| entry.is_expired(now_millis()))
+    }
+
+    /// Sets or lifts the byte and key quotas of this store, replacing those it was opened with
+    ///
+    /// Quotas are not persisted. Each bucket has its own quotas, so giving every tenant a
+    /// bucket and setting its quotas with `store.bucket(name)?.set_quota(..)` keeps tenants
+    /// sharing a store from crowding each other out.
+    pub fn set_quota(&mut self, bytes: Option<u64>, keys: Option<usize>) {
+        self.options.quota = bytes;
+        self.options.max_keys = keys;
+    }
+
+    /// Records a use of a key for choosing eviction victims
+    pub(crate) fn touch(&mut self, key: &[u8]) {
+        if self.options.quota_eviction {
+            self.accessed.insert(key.to_vec(), now_millis());
+        }
+    }
+
+    /// Returns the bytes of the log and value log of this store, excluding buckets
+    pub(crate) fn own_size(&self) -> Result<u64> {
+        let mut size = self.log.len()?;
+        if let Some(values) = &self.values {
+            size += values.len()?;
+        }
+        Ok(size)
+    }
+
+    /// Returns the approximate bytes a compacted log would take
+    pub(crate) fn live_size(&self) -> u64 {
+        let now = now_millis();
+        self.index
+            .values()
+            .filter(|entry| !entry.is_expired(now))
+            .map(|entry| entry.len + entry.separated)
+            .sum::<u64>()
+            + self.live_blob_bytes()
+    }
+}
@@ -0,0 +1,236 @@
+use crate::{now_millis, KvError, KvStore, Result};
+use std::io::Read;
+
+const OPCODE_FUNCTION: u8 = 0xf5;
+const OPCODE_MODULE_AUX: u8 = 0xf7;
+const OPCODE_IDLE: u8 = 0xf8;
+const OPCODE_FREQ: u8 = 0xf9;
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+const TYPE_STRING: u8 = 0;
+
+/// Reads the primitives of the Redis RDB dump format
+struct RdbReader<R> {
+    reader: R,
+}
+
+/// A length prefix, or the marker for a specially encoded string
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+impl<R: Read> RdbReader<R> {
+    fn byte(&mut self) -> Result<u8> {
+        let mut buf = [0; 1];
+        self.reader.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn bytes(&mut self, len: u64) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        (&mut self.reader).take(len).read_to_end(&mut buf)?;
+        if buf.len() as u64 != len {
+            return Err(KvError::InvalidImport);
+        }
+        Ok(buf)
+    }
+
+    fn u64_le(&mut self, len: u64) -> Result<u64> {
+        let buf = self.bytes(len)?;
+        Ok(buf.iter().rev().fold(0, |n, &b| n << 8 | u64::from(b)))
+    }
+
+    fn length_or_encoding(&mut self) -> Result<Length> {
+        let first = self.byte()?;
+        match first >> 6 {
+            0 => Ok(Length::Len(u64::from(first & 0x3f))),
+            1 => Ok(Length::Len(
+                u64::from(first & 0x3f) << 8 | u64::from(self.byte()?),
+            )),
+            2 if first == 0x80 => self
+                .u64_le(4)
+                .map(|n| Length::Len(u64::from((n as u32).swap_bytes()))),
+            2 if first == 0x81 => self.u64_le(8).map(|n| Length::Len(n.swap_bytes())),
+            3 => Ok(Length::Encoded(first & 0x3f)),
+            _ => Err(KvError::InvalidImport),
+        }
+    }
+
+    fn length(&mut self) -> Result<u64> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(KvError::InvalidImport),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => self.bytes(len),
+            Length::Encoded(0) => Ok((self.byte()? as i8).to_string().into_bytes()),
+            Length::Encoded(1) => Ok((self.u64_le(2)? as i16).to_string().into_bytes()),
+            Length::Encoded(2) => Ok((self.u64_le(4)? as i32).to_string().into_bytes()),
+            Length::Encoded(3) => {
+                let compressed_len = self.length()?;
+                let len = self.length()?;
+                let compressed = self.bytes(compressed_len)?;
+                lzf_decompress(&compressed, len as usize).ok_or(KvError::InvalidImport)
+            }
+            Length::Encoded(_) => Err(KvError::InvalidImport),
+        }
+    }
+
+    /// Skips over a value of a type other than a plain string
+    fn skip_value(&mut self, kind: u8) -> Result<()> {
+        let strings_per_item = match kind {
+            // Lists, sets and quicklists hold one string per item, hashes two
+            1 | 2 | 14 => 1,
+            4 => 2,
+            // Sorted sets store a member followed by its score
+            3 => {
+                for _ in 0..self.length()? {
+                    self.string()?;
+                    let score_len = self.byte()?;
+                    if score_len < 253 {
+                        self.bytes(u64::from(score_len))?;
+                    }
+                }
+                return Ok(());
+            }
+            5 => {
+                for _ in 0..self.length()? {
+                    self.string()?;
+                    self.bytes(8)?;
+                }
+                return Ok(());
+            }
+            // Quicklists of listpacks store a container kind before each node
+            18 => {
+                for _ in 0..self.length()? {
+                    self.length()?;
+                    self.string()?;
+                }
+                return Ok(());
+            }
+            // Compact encodings are stored as a single string
+            9..=13 | 16 | 17 | 20 => {
+                self.string()?;
+                return Ok(());
+            }
+            _ => return Err(KvError::InvalidImport),
+        };
+        for _ in 0..self.length()? * strings_per_item {
+            self.string()?;
+        }
+        Ok(())
+    }
+}
+
+/// Decompresses an LZF compressed string of the given uncompressed length
+fn lzf_decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = usize::from(input[i]);
+        i += 1;
+        if ctrl < 32 {
+            let literal = input.get(i..i + ctrl + 1)?;
+            output.extend_from_slice(literal);
+            i += ctrl + 1;
+        } else {
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += usize::from(*input.get(i)?);
+                i += 1;
+            }
+            let back = ((ctrl & 0x1f) << 8) + usize::from(*input.get(i)?) + 1;
+            i += 1;
+            let start = output.len().checked_sub(back)?;
+            for k in start..start + run + 2 {
+                let byte = output[k];
+                output.push(byte);
+            }
+        }
+    }
+    if output.len() == len {
+        Some(output)
+    } else {
+        None
+    }
+}
+
+impl KvStore {
+    /// Stores the string keys of a Redis RDB dump, keeping their expiry times
+    ///
+    /// Keys of other types and keys that have already expired are skipped. Returns the number
+    /// of keys stored.
+    pub fn import_rdb<R: Read>(&mut self, reader: R) -> Result<usize> {
+        let mut rdb = RdbReader { reader };
+        if &rdb.bytes(5)?[..] != b"REDIS" {
+            return Err(KvError::InvalidImport);
+        }
+        rdb.bytes(4)?;
+
+        let now = now_millis();
+        let mut count = 0;
+        let mut expires_at = None;
+        loop {
+            let kind = match rdb.byte()? {
+                OPCODE_EOF => break,
+                OPCODE_AUX => {
+                    rdb.string()?;
+                    rdb.string()?;
+                    continue;
+                }
+                OPCODE_SELECTDB => {
+                    rdb.length()?;
+                    continue;
+                }
+                OPCODE_RESIZEDB => {
+                    rdb.length()?;
+                    rdb.length()?;
+                    continue;
+                }
+                OPCODE_EXPIRETIME_MS => {
+                    expires_at = Some(rdb.u64_le(8)?);
+                    continue;
+                }
+                OPCODE_EXPIRETIME => {
+                    expires_at = Some(rdb.u64_le(4)? * 1000);
+                    continue;
+                }
+                OPCODE_IDLE => {
+                    rdb.length()?;
+                    continue;
+                }
+                OPCODE_FREQ => {
+                    rdb.byte()?;
+                    continue;
+                }
+                OPCODE_FUNCTION | OPCODE_MODULE_AUX => return Err(KvError::InvalidImport),
+                kind => kind,
+            };
+
+            let key = rdb.string()?;
+            let expires_at = expires_at.take();
+            if kind != TYPE_STRING {
+                rdb.skip_value(kind)?;
+                continue;
+            }
+            let value = rdb.string()?;
+            match expires_at {
+                Some(t) if t <= now => {}
+                _ => {
+                    self.write_value(key, value, expires_at, None)?;
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+}
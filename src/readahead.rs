@@ -0,0 +1,62 @@
+use crate::crypto::unseal_record;
+use crate::{codec, now_millis, KvStore, LogEntry};
+use std::io::{self, Seek, SeekFrom};
+
+impl KvStore {
+    /// Caches the values of live keys stored just after a record read from disk, up to the
+    /// read-ahead limit
+    ///
+    /// Keys written together are often read together, so this turns the reads that follow into
+    /// cache hits. Reading ahead is best effort: it stops at the first record that cannot be
+    /// read, such as a torn record at the end of the log.
+    pub(crate) fn read_ahead(&mut self, pointer: u64) {
+        let limit = self.options.read_ahead;
+        if limit == 0 || self.block_cache.is_some() {
+            return;
+        }
+        let log_len = match self.log.len() {
+            Ok(len) => len,
+            Err(_) => return,
+        };
+        let mut reader = io::BufReader::new(self.log.reader());
+        if reader.seek(SeekFrom::Start(pointer)).is_err()
+            || codec::decode(self.options.format, &mut reader).is_err()
+        {
+            return;
+        }
+        let now = now_millis();
+        let mut values = Vec::new();
+        while values.len() < limit {
+            let pos = match reader.stream_position() {
+                Ok(pos) if pos < log_len => pos,
+                _ => break,
+            };
+            let record = match codec::decode(self.options.format, &mut reader)
+                .and_then(|record| unseal_record(&self.options, record))
+            {
+                Ok(record) => record,
+                Err(_) => break,
+            };
+            let key = match &record {
+                LogEntry::Set { key, .. } => key.clone(),
+                _ => continue,
+            };
+            // Only the latest record of a key holds its value
+            let live = self
+                .index
+                .get(&key)
+                .is_some_and(|entry| entry.pointer == pos && !entry.is_expired(now));
+            if !live || self.cache.peek(&key).is_some() {
+                continue;
+            }
+            match self.entry_value(&key, record) {
+                Ok(Some(value)) => values.push((key, value)),
+                Ok(None) => {}
+                Err(_) => break,
+            }
+        }
+        for (key, value) in values {
+            self.cache_value(key, value);
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use crate::clock::now_millis;
+use crate::KvStore;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+
+/// xorshift64* generator; sampling needs no more than a fast, roughly uniform source
+struct Rng(u64);
+
+impl Rng {
+    /// Seeds from the randomly keyed hasher of the standard library
+    fn new() -> Rng {
+        Rng(RandomState::new().hash_one(now_millis()) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Returns a number below `bound`
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+impl KvStore {
+    /// Returns up to `n` live keys chosen uniformly at random, in no particular order
+    ///
+    /// The index is walked once with reservoir sampling, so only the sample is held in memory.
+    /// Keys that are not valid UTF-8 are converted lossily.
+    pub fn sample_keys(&self, n: usize) -> Vec<String> {
+        if n == 0 {
+            return Vec::new();
+        }
+        let mut rng = Rng::new();
+        let mut sample = Vec::with_capacity(n.min(self.index.len()));
+        for (seen, key) in self.keys_bytes().enumerate() {
+            if seen < n {
+                sample.push(key);
+            } else {
+                let slot = rng.below(seen as u64 + 1) as usize;
+                if slot < n {
+                    sample[slot] = key;
+                }
+            }
+        }
+        sample
+            .into_iter()
+            .map(|key| String::from_utf8_lossy(key).into_owned())
+            .collect()
+    }
+}
@@ -0,0 +1,113 @@
+use crate::{now_millis, KvError, KvStore, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::mem;
+use std::sync::Arc;
+
+/// Derives the index terms of a value
+type Extractor = Arc<dyn Fn(&[u8]) -> Vec<Vec<u8>> + Send + Sync>;
+
+/// Index from terms derived from values to the keys holding them
+pub(crate) struct SecondaryIndex {
+    extractor: Extractor,
+    keys: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>>,
+    terms: HashMap<Vec<u8>, Vec<Vec<u8>>>,
+}
+
+impl SecondaryIndex {
+    /// Indexes every live value of a store, replacing whatever the index held
+    fn build(&mut self, store: &KvStore) -> Result<()> {
+        self.keys.clear();
+        self.terms.clear();
+        for entry in store.export() {
+            let entry = entry?;
+            self.update(&entry.key, Some(&entry.value));
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, key: &[u8], value: Option<&[u8]>) {
+        if let Some(terms) = self.terms.remove(key) {
+            for term in terms {
+                if let Some(keys) = self.keys.get_mut(&term) {
+                    keys.remove(key);
+                    if keys.is_empty() {
+                        self.keys.remove(&term);
+                    }
+                }
+            }
+        }
+        if let Some(value) = value {
+            let terms = (self.extractor)(value);
+            for term in &terms {
+                self.keys
+                    .entry(term.clone())
+                    .or_default()
+                    .insert(key.to_vec());
+            }
+            if !terms.is_empty() {
+                self.terms.insert(key.to_vec(), terms);
+            }
+        }
+    }
+}
+
+impl KvStore {
+    /// Registers an index named `name` over the terms `extractor` derives from each value
+    ///
+    /// The index is built from the current contents and then kept up to date by every write.
+    /// Like merge operators, indexes are not persisted and must be registered after opening.
+    pub fn create_index<F>(&mut self, name: &str, extractor: F) -> Result<()>
+    where
+        F: Fn(&[u8]) -> Vec<Vec<u8>> + Send + Sync + 'static,
+    {
+        let mut index = SecondaryIndex {
+            extractor: Arc::new(extractor),
+            keys: BTreeMap::new(),
+            terms: HashMap::new(),
+        };
+        index.build(self)?;
+        self.secondary.insert(name.to_string(), index);
+        Ok(())
+    }
+
+    /// Drops a registered index
+    pub fn drop_index(&mut self, name: &str) {
+        self.secondary.remove(name);
+    }
+
+    /// Returns the live keys whose values have the given term in the named index, in key order
+    pub fn find_by_index(&self, name: &str, term: &str) -> Result<Vec<String>> {
+        let index = self.secondary.get(name).ok_or(KvError::IndexNotFound)?;
+        let now = now_millis();
+        let keys = match index.keys.get(term.as_bytes()) {
+            Some(keys) => keys,
+            None => return Ok(Vec::new()),
+        };
+        // Keys that expired or were evicted are not reported as writes, so check the index
+        Ok(keys
+            .iter()
+            .filter(|key| match self.index.get(*key) {
+                Some(entry) => !entry.is_expired(now),
+                None => false,
+            })
+            .map(|key| String::from_utf8_lossy(key).into_owned())
+            .collect())
+    }
+
+    /// Rebuilds every secondary index from the current contents, after they were replaced
+    pub(crate) fn rebuild_indexes(&mut self) -> Result<()> {
+        let mut secondary = mem::take(&mut self.secondary);
+        for index in secondary.values_mut() {
+            index.build(self)?;
+        }
+        self.secondary = secondary;
+        Ok(())
+    }
+
+    /// Updates every secondary index for a committed change to a key
+    pub(crate) fn update_indexes(&mut self, key: &[u8], value: Option<&[u8]>) {
+        for index in self.secondary.values_mut() {
+            index.update(key, value);
+        }
+    }
+}
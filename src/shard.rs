@@ -0,0 +1,118 @@
+use crate::{fnv1a, KvError, KvStore, Options, Result, FNV_OFFSET};
+use std::fs;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+/// Store split into independent shards by key hash, each with its own log, index and lock
+///
+/// Every method takes `&self` and locks only the shard owning the key, so a sharded store
+/// shared between threads serves writes to different shards in parallel, and compaction of
+/// one shard does not block the others.
+pub struct ShardedStore {
+    shards: Vec<Mutex<KvStore>>,
+}
+
+impl ShardedStore {
+    /// Opens a store in a directory with the given number of shards
+    ///
+    /// The number of shards is fixed when the store is created; opening it with a different
+    /// number fails with `KvError::ShardCountMismatch`.
+    pub fn open(path: &Path, shards: usize) -> Result<ShardedStore> {
+        Options::default().open_sharded(path, shards)
+    }
+
+    /// Returns the index of the shard holding a key
+    ///
+    /// Shard placement is persisted, so it uses a hash that does not change between builds.
+    pub fn shard_of(&self, key: &[u8]) -> usize {
+        (fnv1a(FNV_OFFSET, key) % self.shards.len() as u64) as usize
+    }
+
+    /// Returns the number of shards
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Locks and returns one shard
+    pub fn shard(&self, index: usize) -> MutexGuard<'_, KvStore> {
+        self.shards[index]
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn shard_for(&self, key: &str) -> MutexGuard<'_, KvStore> {
+        self.shard(self.shard_of(key.as_bytes()))
+    }
+
+    /// Retrieve the value for a key
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard_for(&key).get(key)
+    }
+
+    /// Set the value for a key
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard_for(&key).set(key, value)
+    }
+
+    /// Remove a given key
+    pub fn remove(&self, key: String) -> Result<()> {
+        self.shard_for(&key).remove(key)
+    }
+
+    /// Returns the number of keys across all shards
+    pub fn len(&self) -> usize {
+        (0..self.shards.len())
+            .map(|index| self.shard(index).len())
+            .sum()
+    }
+
+    /// Returns whether every shard is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Compacts each shard in turn, holding the lock of one shard at a time
+    pub fn compact(&self) -> Result<()> {
+        for index in 0..self.shards.len() {
+            self.shard(index).compact()?;
+        }
+        Ok(())
+    }
+
+    /// Syncs each shard in turn, holding the lock of one shard at a time
+    pub fn sync(&self) -> Result<()> {
+        for index in 0..self.shards.len() {
+            self.shard(index).sync()?;
+        }
+        Ok(())
+    }
+}
+
+impl Options {
+    /// Opens a store in a directory split into `shards` shards with these settings
+    pub fn open_sharded(&self, path: &Path, shards: usize) -> Result<ShardedStore> {
+        if shards == 0 {
+            return Err(KvError::ShardCountMismatch);
+        }
+        fs::create_dir_all(path)?;
+        let existing = fs::read_dir(path)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                name.starts_with("shard-") && name.ends_with(".log")
+            })
+            .count();
+        if existing != 0 && existing != shards {
+            return Err(KvError::ShardCountMismatch);
+        }
+
+        let shards = (0..shards)
+            .map(|index| {
+                let store = self.open(&path.join(format!("shard-{}.log", index)))?;
+                Ok(Mutex::new(store))
+            })
+            .collect::<Result<_>>()?;
+        Ok(ShardedStore { shards })
+    }
+}
@@ -0,0 +1,86 @@
+use crate::clock::Instant;
+use crate::KvStore;
+use std::cell::Cell;
+use std::time::Duration;
+
+/// Part of an operation whose time is tracked to explain slow operations
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Phase {
+    DiskRead,
+    Append,
+    Fsync,
+    Compaction,
+}
+
+/// Operation whose count and latency are recorded in the stats
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Op {
+    Read,
+    Write,
+    Remove,
+}
+
+impl Op {
+    fn name(self) -> &'static str {
+        match self {
+            Op::Read => "read",
+            Op::Write => "set",
+            Op::Remove => "remove",
+        }
+    }
+}
+
+/// Time spent in each phase of the operation in progress
+#[derive(Debug, Default)]
+pub(crate) struct Phases {
+    times: [Cell<Duration>; 4],
+}
+
+impl Phases {
+    /// Adds the time since `start` to a phase
+    pub(crate) fn add(&self, phase: Phase, start: Instant) {
+        let time = &self.times[phase as usize];
+        time.set(time.get() + start.elapsed());
+    }
+
+    fn take(&self) -> [Duration; 4] {
+        let mut times = [Duration::default(); 4];
+        for (time, phase) in times.iter_mut().zip(&self.times) {
+            *time = phase.take();
+        }
+        times
+    }
+}
+
+impl KvStore {
+    /// Marks the start of a timed operation
+    pub(crate) fn start_op(&self) -> Instant {
+        self.phases.take();
+        Instant::now()
+    }
+
+    /// Records the latency of an operation and logs it as a warning if it took at least as long
+    /// as the slow operation threshold, along with the time spent in each phase
+    pub(crate) fn finish_op(&mut self, op: Op, start: Instant) {
+        let elapsed = start.elapsed();
+        let stats = match op {
+            Op::Read => &mut self.op_counters.reads,
+            Op::Write => &mut self.op_counters.writes,
+            Op::Remove => &mut self.op_counters.removes,
+        };
+        stats.record(elapsed);
+        let [disk_read, append, fsync, compaction] = self.phases.take();
+        match self.options.slow_op_threshold {
+            Some(threshold) if elapsed >= threshold => ::log::warn!(
+                "slow {} took {:?}: disk read {:?}, append {:?}, fsync {:?}, compaction {:?}",
+                op.name(),
+                elapsed,
+                disk_read,
+                append,
+                fsync,
+                compaction
+            ),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{Iter, KvStore, RecentTokens, Result};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::RangeBounds;
+
+/// Read-only view of a store as it was when the snapshot was taken
+///
+/// The snapshot keeps its own handle on the log, so later writes and compactions of the store
+/// do not affect it. Clearing the store replaces the log rather than truncating it, so that
+/// leaves the snapshot intact as well.
+pub struct Snapshot {
+    store: KvStore,
+}
+
+impl Snapshot {
+    /// Retrieve the value for a key
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+
+    /// Retrieve the value for a binary key
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.store.get_bytes(key)
+    }
+
+    /// Returns whether a live value is stored for a key
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.store.contains_key(key)
+    }
+
+    /// Returns all live keys in key order
+    pub fn keys(&self) -> impl Iterator<Item = String> + '_ {
+        self.store.keys()
+    }
+
+    /// Returns the number of live keys
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    /// Returns whether the snapshot has no live keys
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    /// Returns an iterator over all live key-value pairs in key order
+    pub fn iter(&self) -> Iter<'_> {
+        self.store.iter()
+    }
+
+    /// Retrieve all key-value pairs within a range of keys, in key order
+    pub fn scan(&mut self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        self.store.scan(range)
+    }
+
+    /// Retrieve all key-value pairs whose key starts with the given prefix, in key order
+    pub fn scan_prefix(&mut self, prefix: &str) -> Result<impl Iterator<Item = (String, String)>> {
+        self.store.scan_prefix(prefix)
+    }
+}
+
+impl<'a> IntoIterator for &'a Snapshot {
+    type Item = Result<(String, String)>;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl KvStore {
+    /// Takes a read-only snapshot of the current contents of the store
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let store = KvStore {
+            path: self.path.clone(),
+            log: self.log.read_handle(&self.path)?,
+            values: match &self.values {
+                Some(values) => Some(values.read_handle(&self.path.with_extension("vlog"))?),
+                None => None,
+            },
+            index: self.index.clone(),
+            cache: self.options.new_value_cache(),
+            block_cache: self.options.new_block_cache(),
+            mapping: RefCell::new(None),
+            prepared: self.prepared.clone(),
+            last_token: self.last_token,
+            tokens: RecentTokens::new(0),
+            seq: self.seq,
+            blobs: self.blobs.clone(),
+            blob_hashes: self.blob_hashes.clone(),
+            last_blob: self.last_blob,
+            buckets: HashMap::new(),
+            merge_operator: self.merge_operator.clone(),
+            cache_hits: 0,
+            cache_misses: 0,
+            compactions: 0,
+            op_counters: Default::default(),
+            phases: Default::default(),
+            compaction_counter: 0,
+            stale_bytes: (0, 0),
+            stalls: 0,
+            evictions: 0,
+            accessed: HashMap::new(),
+            watchers: Vec::new(),
+            sinks: Vec::new(),
+            hooks: Vec::new(),
+            secondary: HashMap::new(),
+            versions: self.versions.clone(),
+            tombstones: self.tombstones.clone(),
+            options: self.options.clone().read_only(true),
+            temp_dir: None,
+        };
+        Ok(Snapshot { store })
+    }
+}
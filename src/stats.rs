@@ -0,0 +1,310 @@
+use crate::{now_millis, KvStore, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Distribution of sizes in power-of-two buckets
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SizeHistogram {
+    counts: Vec<usize>,
+    sum: u64,
+}
+
+impl SizeHistogram {
+    fn record(&mut self, size: u64) {
+        let bucket = (64 - size.leading_zeros()) as usize;
+        if self.counts.len() <= bucket {
+            self.counts.resize(bucket + 1, 0);
+        }
+        self.counts[bucket] += 1;
+        self.sum = self.sum.saturating_add(size);
+    }
+
+    /// Returns the exclusive upper bound of each non-empty bucket with its count, smallest first
+    pub fn buckets(&self) -> impl Iterator<Item = (u64, usize)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(bucket, &count)| (1u64.checked_shl(bucket as u32).unwrap_or(u64::MAX), count))
+    }
+
+    /// Returns the number of sizes recorded
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    /// Returns the sum of the sizes recorded
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+}
+
+/// Number of operations of one kind served since the store was opened, with their latencies
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OpStats {
+    /// Number of operations, including those that failed
+    pub count: u64,
+    /// Latencies of the operations in microseconds
+    pub latency_micros: SizeHistogram,
+}
+
+impl OpStats {
+    pub(crate) fn record(&mut self, latency: Duration) {
+        self.count += 1;
+        self.latency_micros.record(latency.as_micros() as u64);
+    }
+}
+
+/// Counters for the operations served by a store
+#[derive(Debug, Clone, Default)]
+pub(crate) struct OpCounters {
+    pub(crate) reads: OpStats,
+    pub(crate) writes: OpStats,
+    pub(crate) removes: OpStats,
+}
+
+/// Usage of a bucket against its quotas
+#[derive(Debug, Clone, PartialEq)]
+pub struct BucketUsage {
+    /// Number of live keys
+    pub keys: usize,
+    /// Bytes of the log and value log of the bucket
+    pub bytes: u64,
+    /// Most keys the bucket may hold, if limited
+    pub max_keys: Option<usize>,
+    /// Most bytes the bucket may use, if limited
+    pub quota: Option<u64>,
+}
+
+/// Statistics about the contents and usage of a store
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    /// Number of live keys
+    pub keys: usize,
+    /// Number of removed keys whose removal is still recorded in the log
+    pub tombstones: usize,
+    /// Approximate bytes of the log holding live values
+    pub live_bytes: u64,
+    /// Approximate bytes of the log holding stale records that compaction would reclaim
+    pub dead_bytes: u64,
+    /// Size of the log file in bytes
+    pub log_size: u64,
+    /// Number of reads served from the cache since the store was opened
+    pub cache_hits: u64,
+    /// Number of reads that went to the log since the store was opened
+    pub cache_misses: u64,
+    /// Total size of the keys and values held in the read cache
+    pub cache_bytes: u64,
+    /// Number of compactions since the store was opened
+    pub compactions: u64,
+    /// Number of writes delayed by write stalls since the store was opened
+    pub write_stalls: u64,
+    /// Number of keys evicted to stay within the quotas since the store was opened
+    pub evictions: u64,
+    /// Sizes of live keys in bytes
+    pub key_sizes: SizeHistogram,
+    /// Approximate sizes of live values in bytes, including the encoding overhead of their records
+    pub value_sizes: SizeHistogram,
+    /// Reads of single keys
+    pub reads: OpStats,
+    /// Sets of single keys, including those with an expiry or an idempotency token
+    pub writes: OpStats,
+    /// Removals of single keys
+    pub removes: OpStats,
+    /// Usage of each bucket opened through the store, by name
+    pub buckets: BTreeMap<String, BucketUsage>,
+}
+
+impl Stats {
+    /// Returns the fraction of reads served from the cache, or zero if nothing was read
+    pub fn cache_hit_rate(&self) -> f64 {
+        let reads = self.cache_hits + self.cache_misses;
+        if reads == 0 {
+            0.0
+        } else {
+            self.cache_hits as f64 / reads as f64
+        }
+    }
+
+    /// Renders the statistics in the Prometheus text exposition format, with every metric name
+    /// prefixed by `kvs_`
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let gauges = [
+            ("keys", "Number of live keys", self.keys as u64),
+            (
+                "tombstones",
+                "Removed keys still recorded in the log",
+                self.tombstones as u64,
+            ),
+            (
+                "live_bytes",
+                "Bytes of log holding live values",
+                self.live_bytes,
+            ),
+            (
+                "dead_bytes",
+                "Bytes of log that compaction would reclaim",
+                self.dead_bytes,
+            ),
+            ("log_size_bytes", "Size of the log file", self.log_size),
+            (
+                "cache_bytes",
+                "Bytes held in the read cache",
+                self.cache_bytes,
+            ),
+        ];
+        for (name, help, value) in &gauges {
+            metric(&mut out, name, help, "gauge");
+            let _ = writeln!(out, "kvs_{} {}", name, value);
+        }
+        let counters = [
+            (
+                "cache_hits_total",
+                "Reads served from the cache",
+                self.cache_hits,
+            ),
+            (
+                "cache_misses_total",
+                "Reads that went to the log",
+                self.cache_misses,
+            ),
+            (
+                "compactions_total",
+                "Compactions of the log",
+                self.compactions,
+            ),
+            (
+                "write_stalls_total",
+                "Writes delayed by stale records awaiting compaction",
+                self.write_stalls,
+            ),
+            (
+                "evictions_total",
+                "Keys evicted to stay within the quotas",
+                self.evictions,
+            ),
+        ];
+        for (name, help, value) in &counters {
+            metric(&mut out, name, help, "counter");
+            let _ = writeln!(out, "kvs_{} {}", name, value);
+        }
+        if !self.buckets.is_empty() {
+            metric(
+                &mut out,
+                "bucket_keys",
+                "Number of live keys in a bucket",
+                "gauge",
+            );
+            for (name, usage) in &self.buckets {
+                let _ = writeln!(out, "kvs_bucket_keys{{bucket=\"{}\"}} {}", name, usage.keys);
+            }
+            metric(&mut out, "bucket_bytes", "Bytes used by a bucket", "gauge");
+            for (name, usage) in &self.buckets {
+                let _ = writeln!(
+                    out,
+                    "kvs_bucket_bytes{{bucket=\"{}\"}} {}",
+                    name, usage.bytes
+                );
+            }
+        }
+        for (op, stats) in &[
+            ("read", &self.reads),
+            ("write", &self.writes),
+            ("remove", &self.removes),
+        ] {
+            let name = format!("{}_duration_seconds", op);
+            metric(&mut out, &name, &format!("Latency of {}s", op), "histogram");
+            let mut cumulative = 0;
+            for (bound, count) in stats.latency_micros.buckets() {
+                cumulative += count;
+                let le = bound as f64 / 1e6;
+                let _ = writeln!(out, "kvs_{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+            }
+            let _ = writeln!(out, "kvs_{}_bucket{{le=\"+Inf\"}} {}", name, stats.count);
+            let sum = stats.latency_micros.sum() as f64 / 1e6;
+            let _ = writeln!(out, "kvs_{}_sum {}", name, sum);
+            let _ = writeln!(out, "kvs_{}_count {}", name, stats.count);
+        }
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP kvs_{} {}", name, help);
+    let _ = writeln!(out, "# TYPE kvs_{} {}", name, kind);
+}
+
+impl KvStore {
+    /// Returns the bytes used on disk by the log and those of any buckets opened through this store
+    pub fn size_on_disk(&self) -> Result<u64> {
+        let mut size = self.log.len()?;
+        if let Some(values) = &self.values {
+            size += values.len()?;
+        }
+        for bucket in self.buckets.values() {
+            size += bucket.size_on_disk()?;
+        }
+        Ok(size)
+    }
+
+    /// Returns an estimate of the bytes of log needed to hold the live values, which is roughly
+    /// what the log would shrink to if it were compacted
+    pub fn estimated_live_size(&self) -> u64 {
+        let now = now_millis();
+        self.index
+            .values()
+            .filter(|entry| !entry.is_expired(now))
+            .map(|entry| entry.len)
+            .sum()
+    }
+
+    /// Collects statistics about the store from the in-memory index
+    pub fn stats(&self) -> Result<Stats> {
+        let now = now_millis();
+        let mut keys = 0;
+        let mut live_bytes = 0;
+        let mut key_sizes = SizeHistogram::default();
+        let mut value_sizes = SizeHistogram::default();
+        for (key, entry) in &self.index {
+            if entry.is_expired(now) {
+                continue;
+            }
+            keys += 1;
+            live_bytes += entry.len;
+            key_sizes.record(key.len() as u64);
+            value_sizes.record(entry.len.saturating_sub(key.len() as u64));
+        }
+        let log_size = self.log.len()?;
+        let mut buckets = BTreeMap::new();
+        for (name, bucket) in &self.buckets {
+            let usage = BucketUsage {
+                keys: bucket.len(),
+                bytes: bucket.own_size()?,
+                max_keys: bucket.options.max_keys,
+                quota: bucket.options.quota,
+            };
+            buckets.insert(name.clone(), usage);
+        }
+        Ok(Stats {
+            keys,
+            tombstones: self.tombstones.live(&self.index).count(),
+            live_bytes,
+            dead_bytes: log_size.saturating_sub(live_bytes),
+            log_size,
+            cache_hits: self.cache_hits,
+            cache_misses: self.cache_misses,
+            cache_bytes: self.cache.size() as u64,
+            compactions: self.compactions,
+            write_stalls: self.stalls,
+            evictions: self.evictions,
+            key_sizes,
+            value_sizes,
+            reads: self.op_counters.reads.clone(),
+            writes: self.op_counters.writes.clone(),
+            removes: self.op_counters.removes.clone(),
+            buckets,
+        })
+    }
+}
@@ -0,0 +1,257 @@
+use crate::log::Log;
+use crate::{direct, open_append, open_locked, prealloc, Options, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// File operations a store performs on its log and value log, set with `Options::storage`
+///
+/// Handles returned by `open` and `create` must keep reading the same contents after the file
+/// is renamed, replaced or removed, as snapshots rely on it.
+pub trait Storage: Send + Sync {
+    /// Opens the file at `path`, creating an empty one if it is missing and `create` is set,
+    /// or failing with `io::ErrorKind::NotFound` otherwise
+    fn open(&self, path: &Path, create: bool) -> io::Result<Arc<dyn StorageFile>>;
+
+    /// Creates an empty file at `path`, replacing any file there
+    fn create(&self, path: &Path) -> io::Result<Arc<dyn StorageFile>>;
+
+    /// Moves a file to a new path, replacing any file there
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Deletes the file at `path`
+    fn remove(&self, path: &Path) -> io::Result<()>;
+}
+
+/// A file opened through a `Storage`, which is only ever appended to
+pub trait StorageFile: Send + Sync {
+    /// Reads bytes starting at `offset` into `buf`, returning how many were read, or zero at
+    /// the end of the file
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Appends all of `data` to the end of the file
+    fn append(&self, data: &[u8]) -> io::Result<()>;
+
+    /// Returns the length of the file in bytes
+    fn len(&self) -> io::Result<u64>;
+
+    /// Returns whether the file is empty
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Flushes the file to stable storage
+    fn sync(&self) -> io::Result<()>;
+}
+
+/// Storage keeping files in memory, shared between clones so a store can be reopened from it
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    files: Arc<Mutex<HashMap<PathBuf, Arc<MemoryFile>>>>,
+}
+
+impl MemoryStorage {
+    /// Creates storage holding no files
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    /// Returns the contents of the file at `path`, if there is one
+    pub fn contents(&self, path: &Path) -> Option<Vec<u8>> {
+        let files = lock(&self.files);
+        files.get(path).map(|file| lock(&file.data).clone())
+    }
+
+    /// Lists the paths of all files, in no particular order
+    pub fn paths(&self) -> Vec<PathBuf> {
+        lock(&self.files).keys().cloned().collect()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Arc<dyn StorageFile>> {
+        let mut files = lock(&self.files);
+        match files.get(path) {
+            Some(file) => Ok(file.clone()),
+            None if create => {
+                let file = Arc::new(MemoryFile::default());
+                files.insert(path.to_path_buf(), file.clone());
+                Ok(file)
+            }
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Arc<dyn StorageFile>> {
+        let file = Arc::new(MemoryFile::default());
+        lock(&self.files).insert(path.to_path_buf(), file.clone());
+        Ok(file)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = lock(&self.files);
+        let file = files.remove(from).ok_or_else(|| not_found(from))?;
+        files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        lock(&self.files)
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+}
+
+impl fmt::Debug for MemoryStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryStorage")
+            .field("files", &lock(&self.files).len())
+            .finish()
+    }
+}
+
+#[derive(Default)]
+struct MemoryFile {
+    data: Mutex<Vec<u8>>,
+}
+
+impl StorageFile for MemoryFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let data = lock(&self.data);
+        let start = (offset as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn append(&self, data: &[u8]) -> io::Result<()> {
+        lock(&self.data).extend_from_slice(data);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(lock(&self.data).len() as u64)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+/// Storage set on the options of a store
+#[derive(Clone)]
+pub(crate) struct SharedStorage(pub(crate) Arc<dyn Storage>);
+
+impl fmt::Debug for SharedStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Storage(..)")
+    }
+}
+
+impl Options {
+    /// Opens a log for appending, creating it if needed, and locks it if `lock` is set
+    ///
+    /// Files of a custom storage are never locked.
+    pub(crate) fn open_log_file(&self, path: &Path, lock: bool) -> Result<Log> {
+        let log = match &self.storage {
+            Some(storage) => Log::Storage(storage.0.open(path, true)?),
+            None if lock => Log::File(open_locked(path, self.direct_io)?),
+            None => Log::File(open_append(path, self.direct_io)?),
+        };
+        if let Some(chunk) = self.preallocate {
+            prealloc::reserve(&log, log.len()?, chunk)?;
+        }
+        Ok(log)
+    }
+
+    /// Opens an existing log for reading only
+    pub(crate) fn open_existing(&self, path: &Path) -> io::Result<Log> {
+        match &self.storage {
+            Some(storage) => storage.0.open(path, false).map(Log::Storage),
+            None => File::open(path).map(Log::File),
+        }
+    }
+
+    /// Creates an empty log, replacing any file at the path
+    pub(crate) fn create_file(&self, path: &Path) -> io::Result<Log> {
+        match &self.storage {
+            Some(storage) => storage.0.create(path).map(Log::Storage),
+            None => {
+                let mut options = OpenOptions::new();
+                options.write(true).create(true).truncate(true);
+                let log = Log::File(direct::open(&mut options, path, self.direct_io)?);
+                if let Some(chunk) = self.preallocate {
+                    prealloc::reserve(&log, 0, chunk)?;
+                }
+                Ok(log)
+            }
+        }
+    }
+
+    pub(crate) fn file_exists(&self, path: &Path) -> bool {
+        match &self.storage {
+            Some(storage) => storage.0.open(path, false).is_ok(),
+            None => path.exists(),
+        }
+    }
+
+    pub(crate) fn rename_file(&self, from: &Path, to: &Path) -> io::Result<()> {
+        match &self.storage {
+            Some(storage) => storage.0.rename(from, to),
+            None => fs::rename(from, to),
+        }
+    }
+
+    /// Flushes the directory holding `path`, so that renames into it survive a crash
+    ///
+    /// Only Unix can sync a directory; custom storage makes its own renames durable.
+    pub(crate) fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        match &self.storage {
+            None if cfg!(unix) => File::open(dir)?.sync_all(),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match &self.storage {
+            Some(storage) => storage.0.remove(path),
+            None => fs::remove_file(path),
+        }
+    }
+
+    /// Creates a directory for files of the store; custom storage has no directories
+    pub(crate) fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        match &self.storage {
+            Some(_) => Ok(()),
+            None => fs::create_dir_all(path),
+        }
+    }
+
+    /// Copies a file from the file system, such as one from a backup, into the storage
+    pub(crate) fn import_file(&self, src: &Path, path: &Path) -> io::Result<()> {
+        match &self.storage {
+            Some(storage) => storage.0.create(path)?.append(&fs::read(src)?),
+            None => fs::copy(src, path).map(|_| ()),
+        }
+    }
+}
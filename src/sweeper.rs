@@ -0,0 +1,58 @@
+use crate::KvStore;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Background task that periodically purges expired keys from a shared store
+pub struct ExpirationSweeper {
+    stop: Sender<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ExpirationSweeper {
+    /// Starts sweeping the store at the given interval
+    pub fn start(store: Arc<Mutex<KvStore>>, interval: Duration) -> ExpirationSweeper {
+        let (stop, rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(interval) {
+                if let Ok(mut store) = store.lock() {
+                    // A failed sweep is retried on the next tick
+                    let _ = store.purge_expired();
+                }
+            }
+        });
+
+        ExpirationSweeper {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    /// Stops the sweeper and waits for it to finish
+    ///
+    /// Returns the panic of the sweeping thread as an error if it panicked.
+    pub fn stop(mut self) -> thread::Result<()> {
+        self.shutdown()
+    }
+
+    /// Tells the thread to stop, then joins it; a sweeper that was already shut down is left
+    /// as it is
+    fn shutdown(&mut self) -> thread::Result<()> {
+        let handle = match self.handle.take() {
+            Some(handle) => handle,
+            None => return Ok(()),
+        };
+        // The thread also stops when the channel is closed, so a failed send is harmless
+        let _ = self.stop.send(());
+        handle.join()
+    }
+}
+
+impl Drop for ExpirationSweeper {
+    fn drop(&mut self) {
+        if self.shutdown().is_err() {
+            ::log::warn!("expiration sweeper thread panicked");
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use crate::clock::{sleep, Instant};
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// Paces compaction to a number of bytes per second by sleeping whenever it gets ahead
+pub(crate) struct Throttle {
+    rate: Option<u64>,
+    start: Instant,
+    bytes: Cell<u64>,
+}
+
+impl Throttle {
+    /// Creates a throttle allowing `rate` bytes per second, or any rate if it is `None`
+    pub(crate) fn new(rate: Option<u64>) -> Throttle {
+        Throttle {
+            rate: rate.filter(|&rate| rate > 0),
+            start: Instant::now(),
+            bytes: Cell::new(0),
+        }
+    }
+
+    /// Accounts for `len` bytes of I/O, sleeping until the rate allows them
+    pub(crate) fn consume(&self, len: usize) {
+        let rate = match self.rate {
+            Some(rate) => rate,
+            None => return,
+        };
+        let bytes = self.bytes.get() + len as u64;
+        self.bytes.set(bytes);
+        let due = Duration::from_secs_f64(bytes as f64 / rate as f64);
+        let elapsed = self.start.elapsed();
+        if due > elapsed {
+            sleep(due - elapsed);
+        }
+    }
+
+    /// Wraps a writer so that everything written through it is paced by this throttle
+    pub(crate) fn writer<W: Write>(&self, inner: W) -> Throttled<'_, W> {
+        Throttled {
+            inner,
+            throttle: self,
+        }
+    }
+}
+
+pub(crate) struct Throttled<'a, W> {
+    inner: W,
+    throttle: &'a Throttle,
+}
+
+impl<'a, W: Write> Write for Throttled<'a, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(data)?;
+        self.throttle.consume(len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,44 @@
+use crate::IndexEntry;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Latest removal of each removed key, kept so compaction can carry recent removals over to the
+/// new log
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Tombstones {
+    /// Sequence number and time of the removal of each key
+    removed: BTreeMap<Vec<u8>, (u64, u64)>,
+}
+
+impl Tombstones {
+    pub(crate) fn record(&mut self, key: Vec<u8>, seq: u64, time: u64) {
+        self.removed.insert(key, (seq, time));
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.removed.clear();
+    }
+
+    /// Returns the removals of keys that have not been written again since, with their sequence
+    /// numbers and times
+    pub(crate) fn live<'a>(
+        &'a self,
+        index: &'a BTreeMap<Vec<u8>, IndexEntry>,
+    ) -> impl Iterator<Item = (&'a [u8], u64, u64)> {
+        self.removed
+            .iter()
+            .filter(move |(key, _)| !index.contains_key(*key))
+            .map(|(key, &(seq, time))| (key.as_slice(), seq, time))
+    }
+
+    /// Returns the live removals made less than `retention` before `now`
+    pub(crate) fn retained<'a>(
+        &'a self,
+        index: &'a BTreeMap<Vec<u8>, IndexEntry>,
+        now: u64,
+        retention: Duration,
+    ) -> impl Iterator<Item = (&'a [u8], u64, u64)> {
+        let cutoff = now.saturating_sub(retention.as_millis() as u64);
+        self.live(index).filter(move |&(_, _, time)| time > cutoff)
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{keys_with_prefix, BatchOp, IndexEntry, KvStore, LogEntry, PreparedBatch, Result};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// A superseded state of a key: a value it held, or its removal
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Version {
+    pub(crate) seq: u64,
+    pub(crate) time: u64,
+    /// Index entry of the value, or `None` if the key was removed at `seq`
+    pub(crate) entry: Option<IndexEntry>,
+}
+
+/// Superseded versions of each key, up to a configured number per key
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Versions {
+    pub(crate) keys: BTreeMap<Vec<u8>, VecDeque<Version>>,
+    limit: usize,
+}
+
+impl Versions {
+    pub(crate) fn new(limit: usize) -> Versions {
+        Versions {
+            keys: BTreeMap::new(),
+            limit,
+        }
+    }
+
+    /// Returns the current index entries of keys about to be changed by a write
+    ///
+    /// Nothing is captured when no versions are retained.
+    pub(crate) fn capture<I, K>(
+        &self,
+        index: &BTreeMap<Vec<u8>, IndexEntry>,
+        keys: I,
+    ) -> Vec<(Vec<u8>, Option<IndexEntry>)>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<[u8]>,
+    {
+        if self.limit == 0 {
+            return Vec::new();
+        }
+        keys.into_iter()
+            .map(|key| {
+                let key = key.as_ref();
+                (key.to_vec(), index.get(key).cloned())
+            })
+            .collect()
+    }
+
+    /// Keeps the states of captured keys that the write at `seq` superseded
+    pub(crate) fn record(
+        &mut self,
+        index: &BTreeMap<Vec<u8>, IndexEntry>,
+        captured: Vec<(Vec<u8>, Option<IndexEntry>)>,
+        seq: u64,
+        time: u64,
+    ) {
+        for (key, previous) in captured {
+            let current = index.get(&key);
+            if previous.map(|entry| entry.seq) == current.map(|entry| entry.seq) {
+                continue;
+            }
+            if let Some(entry) = previous {
+                self.push(
+                    &key,
+                    Version {
+                        seq: entry.seq,
+                        time: entry.time,
+                        entry: Some(entry),
+                    },
+                );
+            }
+            if current.is_none() {
+                self.push(
+                    &key,
+                    Version {
+                        seq,
+                        time,
+                        entry: None,
+                    },
+                );
+            }
+        }
+    }
+
+    pub(crate) fn push(&mut self, key: &[u8], version: Version) {
+        let versions = self.keys.entry(key.to_vec()).or_default();
+        versions.push_back(version);
+        while versions.len() > self.limit {
+            versions.pop_front();
+        }
+    }
+
+    /// Returns the newest retained version of a key written at or before `seq`
+    fn find(&self, key: &[u8], seq: u64) -> Option<&Version> {
+        self.keys
+            .get(key)?
+            .iter()
+            .rev()
+            .find(|version| version.seq <= seq)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.keys.clear();
+    }
+}
+
+/// Returns the keys a record changes when it is replayed, with its sequence number and time
+pub(crate) fn changed_keys(
+    entry: &LogEntry,
+    index: &BTreeMap<Vec<u8>, IndexEntry>,
+    prepared: &HashMap<u64, PreparedBatch>,
+) -> (Vec<Vec<u8>>, u64, u64) {
+    match entry {
+        LogEntry::Set { key, seq, time, .. }
+        | LogEntry::Remove { key, seq, time, .. }
+        | LogEntry::Merge { key, seq, time, .. }
+        | LogEntry::Append { key, seq, time, .. } => (vec![key.clone()], *seq, *time),
+        LogEntry::Rename {
+            from,
+            to,
+            seq,
+            time,
+            ..
+        } => (vec![from.clone(), to.clone()], *seq, *time),
+        LogEntry::RemovePrefix { prefix, seq, time } => {
+            (keys_with_prefix(index, prefix), *seq, *time)
+        }
+        LogEntry::Commit { token, seq, time } => {
+            let keys = match prepared.get(token) {
+                Some(batch) => batch_keys(&batch.ops),
+                None => Vec::new(),
+            };
+            (keys, *seq, *time)
+        }
+        _ => (Vec::new(), 0, 0),
+    }
+}
+
+pub(crate) fn batch_keys(ops: &[BatchOp]) -> Vec<Vec<u8>> {
+    let mut keys: Vec<Vec<u8>> = ops
+        .iter()
+        .map(|op| match op {
+            BatchOp::Set { key, .. } | BatchOp::Remove { key } => key.clone(),
+        })
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+impl KvStore {
+    /// Returns the sequence number of the latest write, to read the store as of now later
+    pub fn latest_seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Retrieve the value a key had after the write with sequence number `seq`
+    ///
+    /// Older values are only available while they are among the versions retained by
+    /// `Options::retained_versions`; beyond that the key reads as missing.
+    pub fn get_at(&mut self, key: String, seq: u64) -> Result<Option<String>> {
+        let key = key.into_bytes();
+        if let Some(entry) = self.live_entry(&key) {
+            if entry.seq <= seq {
+                return Ok(self.get_bytes(&key)?.map(String::from_utf8).transpose()?);
+            }
+        }
+        let pointer = match self.versions.find(&key, seq) {
+            Some(Version {
+                entry: Some(entry), ..
+            }) => entry.pointer,
+            _ => return Ok(None),
+        };
+        let value = self.read_log_entry(&key, pointer)?;
+        Ok(value.map(String::from_utf8).transpose()?)
+    }
+}
@@ -0,0 +1,70 @@
+//! Warming the read cache on open with the keys it held when the store was last closed
+//!
+//! The keys are kept in a MessagePack list next to the log, with the extension `hot`. The list
+//! only names keys, so values are always read from the log and a stale list is harmless.
+use crate::{KvStore, Result};
+use serde_bytes::ByteBuf;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+impl KvStore {
+    /// Saves the keys held in the read cache, for a store opened with `Options::warm_cache`
+    ///
+    /// Stores with cache warming save their keys when they are compacted and closed; calling
+    /// this periodically also keeps the list fresh if the process dies instead.
+    pub fn save_hot_keys(&self) -> Result<()> {
+        if self.log.is_memory() || self.options.read_only {
+            return Ok(());
+        }
+        let keys: Vec<ByteBuf> = self.cache.keys().cloned().map(ByteBuf::from).collect();
+        let buf = rmp_serde::to_vec(&keys)?;
+        let tmp_path = self.hot_keys_path().with_extension("hot.tmp");
+        {
+            let file = self.options.create_file(&tmp_path)?;
+            file.writer().write_all(&buf)?;
+            file.sync()?;
+        }
+        self.options.rename_file(&tmp_path, &self.hot_keys_path())?;
+        Ok(())
+    }
+
+    /// Reads the values of the keys saved by `save_hot_keys` into the read cache
+    ///
+    /// Keys that have since been removed are skipped, and a missing or unreadable list leaves
+    /// the cache cold.
+    pub(crate) fn warm_cache(&mut self) {
+        let path = self.hot_keys_path();
+        if !self.options.warm_cache || self.log.is_memory() || !self.options.file_exists(&path) {
+            return;
+        }
+        let keys = match self.read_hot_keys(&path) {
+            Ok(keys) => keys,
+            Err(err) => {
+                ::log::warn!("ignoring hot keys in {}: {}", path.display(), err);
+                return;
+            }
+        };
+        for key in keys {
+            let pointer = match self.live_entry(&key) {
+                Some(entry) => entry.pointer,
+                None => continue,
+            };
+            if let Ok(Some(value)) = self.read_log_entry(&key, pointer) {
+                self.cache_value(key.into_vec(), value);
+            }
+        }
+    }
+
+    fn read_hot_keys(&self, path: &Path) -> Result<Vec<ByteBuf>> {
+        let file = self.options.open_existing(path)?;
+        let mut reader = file.reader();
+        reader.seek(SeekFrom::Start(0))?;
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        Ok(rmp_serde::from_slice(&buf)?)
+    }
+
+    fn hot_keys_path(&self) -> PathBuf {
+        self.path.with_extension("hot")
+    }
+}
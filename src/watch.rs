@@ -0,0 +1,116 @@
+use crate::{KvStore, Result, StoreEvent};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+/// A committed change to a watched key
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchEvent {
+    /// Key that changed
+    pub key: Vec<u8>,
+    /// Value before the change, if the key existed
+    pub old: Option<Vec<u8>>,
+    /// Value after the change, or `None` if the key was removed
+    pub new: Option<Vec<u8>>,
+}
+
+impl WatchEvent {
+    /// Returns whether the key was removed
+    pub fn is_remove(&self) -> bool {
+        self.new.is_none()
+    }
+}
+
+/// Stream of changes to the keys under a prefix, created with `KvStore::watch`
+///
+/// Iterating blocks until the next change and ends once the store is dropped.
+pub struct Watch {
+    events: Receiver<WatchEvent>,
+}
+
+impl Watch {
+    /// Returns the next change if one is already waiting
+    pub fn try_next(&self) -> Option<WatchEvent> {
+        self.events.try_recv().ok()
+    }
+
+    /// Waits up to `timeout` for the next change
+    pub fn next_timeout(&self, timeout: Duration) -> Option<WatchEvent> {
+        self.events.recv_timeout(timeout).ok()
+    }
+}
+
+impl Iterator for Watch {
+    type Item = WatchEvent;
+
+    fn next(&mut self) -> Option<WatchEvent> {
+        self.events.recv().ok()
+    }
+}
+
+pub(crate) struct Watcher {
+    prefix: Vec<u8>,
+    events: Sender<WatchEvent>,
+}
+
+impl KvStore {
+    /// Subscribes to every committed change of a key starting with `prefix`
+    pub fn watch(&mut self, prefix: &str) -> Watch {
+        let (events, rx) = mpsc::channel();
+        self.watchers.push(Watcher {
+            prefix: prefix.as_bytes().to_vec(),
+            events,
+        });
+        Watch { events: rx }
+    }
+
+    /// Returns whether changes to a key are watched, indexed or sent to a change sink
+    pub(crate) fn is_observed(&self, key: &[u8]) -> bool {
+        !self.sinks.is_empty()
+            || !self.secondary.is_empty()
+            || !self.hooks.is_empty()
+            || self.is_watched(key)
+    }
+
+    fn is_watched(&self, key: &[u8]) -> bool {
+        self.watchers
+            .iter()
+            .any(|watcher| key.starts_with(&watcher.prefix))
+    }
+
+    /// Returns the current value of a key if anyone is watching it, to report as the old value
+    pub(crate) fn watched_value(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.is_watched(key) {
+            self.read_value(key)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Applies a change to the secondary indexes and sends it to the event hooks, the change
+    /// sinks and the watchers of the key, dropping watchers that have gone away
+    pub(crate) fn notify(
+        &mut self,
+        key: &[u8],
+        old: Option<Vec<u8>>,
+        new: Option<&[u8]>,
+    ) -> Result<()> {
+        self.update_indexes(key, new);
+        self.emit(match new {
+            Some(value) => StoreEvent::Write { key, value },
+            None => StoreEvent::Remove { key },
+        });
+        self.publish(key, new)?;
+        if !self.is_watched(key) {
+            return Ok(());
+        }
+        let event = WatchEvent {
+            key: key.to_vec(),
+            old,
+            new: new.map(<[u8]>::to_vec),
+        };
+        self.watchers.retain(|watcher| {
+            !key.starts_with(&watcher.prefix) || watcher.events.send(event.clone()).is_ok()
+        });
+        Ok(())
+    }
+}
@@ -1,8 +1,20 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{
+    natural_order, Change, Conditional, Eviction, ExpirationSweeper, JournalOp, JsonLinesSink,
+    KeyVersion, KvError, KvStore, KvStoreManager, LogFormat, MemoryStorage, RestorePoint, Result,
+    ShardedStore, Storage, StorageFile, StoreEvent, SyncPolicy, WatchEvent, WriteBatch,
+};
 use predicates::ord::eq;
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -17,7 +29,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,7 +40,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -41,7 +53,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -54,7 +66,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -72,7 +84,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +92,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -100,7 +112,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,7 +120,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -121,13 +133,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +148,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +169,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,7 +184,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -254,6 +266,55 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Keys set with a TTL should disappear once it elapses, also after reopening
+#[test]
+fn expired_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A plain set should clear an existing TTL
+#[test]
+fn set_clears_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -299,3 +360,3729 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Prepared batches should only become visible once committed, even across restarts
+#[test]
+fn prepare_and_commit_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .remove("key1".to_owned());
+    let token = store.prepare_batch(batch)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.commit(token)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.commit(token).is_err());
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Aborted batches should never be applied
+#[test]
+fn prepare_and_abort_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key1".to_owned(), "value1".to_owned());
+    let token = store.prepare_batch(batch)?;
+    store.abort(token)?;
+    assert!(store.commit(token).is_err());
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.commit(token).is_err());
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// The sweeper should purge expired keys without them being read
+#[test]
+fn expiration_sweeper() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(20),
+    )?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let store = Arc::new(Mutex::new(store));
+    let sweeper = ExpirationSweeper::start(store.clone(), Duration::from_millis(10));
+    thread::sleep(Duration::from_millis(100));
+    sweeper.stop().expect("sweeper thread panicked");
+
+    let mut store = store.lock().unwrap();
+    assert_eq!(store.purge_expired()?, 0);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Writes retried with the same idempotency token should only be applied once
+#[test]
+fn idempotent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set_idempotent("req1".to_owned(), "key1".to_owned(), "value1".to_owned())?);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(!store.set_idempotent("req1".to_owned(), "key1".to_owned(), "value1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    assert!(store.remove_idempotent("req2".to_owned(), "key1".to_owned())?);
+    assert!(!store.remove_idempotent("req2".to_owned(), "key1".to_owned())?);
+
+    assert_eq!(
+        store.incr_idempotent("req3".to_owned(), "count".to_owned(), 5)?,
+        Some(5)
+    );
+    assert_eq!(
+        store.incr_idempotent("req3".to_owned(), "count".to_owned(), 5)?,
+        None
+    );
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.set_idempotent("req1".to_owned(), "key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.remove_idempotent("req2".to_owned(), "key1".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(
+        store.incr_idempotent("req3".to_owned(), "count".to_owned(), 5)?,
+        None
+    );
+    assert_eq!(store.get("count".to_owned())?, Some("5".to_owned()));
+
+    Ok(())
+}
+
+// Locks should be held by one token at a time until released or their lease runs out
+#[test]
+fn lease_locks() -> Result<()> {
+    let mut store = KvStore::in_memory()?;
+    let token = store
+        .acquire_lock("job", Duration::from_secs(60))?
+        .expect("lock is free");
+    assert_eq!(store.acquire_lock("job", Duration::from_secs(60))?, None);
+    assert!(!store.release_lock("job", token + 1)?);
+    assert!(store.renew_lock("job", token, Duration::from_secs(60))?);
+    assert!(store.release_lock("job", token)?);
+    assert!(!store.release_lock("job", token)?);
+
+    let token = store
+        .acquire_lock("job", Duration::from_millis(10))?
+        .expect("lock is free");
+    thread::sleep(Duration::from_millis(20));
+    assert!(!store.renew_lock("job", token, Duration::from_secs(60))?);
+    let next = store
+        .acquire_lock("job", Duration::from_secs(60))?
+        .expect("lease has run out");
+    assert_ne!(next, token);
+    assert!(!store.release_lock("job", token)?);
+    Ok(())
+}
+
+// Paging through a scan with cursors should return every key once, in order
+#[test]
+fn scan_pages() -> Result<()> {
+    let mut store = KvStore::in_memory()?;
+    for i in 0..25 {
+        store.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+
+    let mut pages = Vec::new();
+    let mut cursor = String::new();
+    loop {
+        let (page, next) = store.scan_page(&cursor, 10)?;
+        pages.push(page);
+        if next.is_empty() {
+            break;
+        }
+        cursor = next;
+    }
+    assert_eq!(
+        pages.iter().map(Vec::len).collect::<Vec<_>>(),
+        vec![10, 10, 5]
+    );
+    assert_eq!(pages.concat(), store.scan(..)?);
+    assert!(matches!(
+        store.scan_page("not a cursor", 10),
+        Err(KvError::InvalidCursor)
+    ));
+    Ok(())
+}
+
+// Sampling should return distinct live keys, and every key should turn up eventually
+#[test]
+fn sample_keys() -> Result<()> {
+    let mut store = KvStore::in_memory()?;
+    assert!(store.sample_keys(3).is_empty());
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let mut seen = std::collections::HashSet::new();
+    for _ in 0..200 {
+        let mut sample = store.sample_keys(3);
+        assert_eq!(sample.len(), 3);
+        sample.sort();
+        sample.dedup();
+        assert_eq!(sample.len(), 3);
+        seen.extend(sample);
+    }
+    assert_eq!(seen.len(), 9);
+    assert!(!seen.contains("key0"));
+    assert_eq!(store.sample_keys(20).len(), 9);
+    Ok(())
+}
+
+// Scans should return live entries within the range in key order
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in &["key3", "key1", "key4", "key2", "key5"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("key3".to_owned())?;
+
+    let entries = store.scan("key2".to_owned().."key5".to_owned())?;
+    assert_eq!(
+        entries,
+        vec![
+            ("key2".to_owned(), "value-key2".to_owned()),
+            ("key4".to_owned(), "value-key4".to_owned()),
+        ]
+    );
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    let keys: Vec<String> = store.scan(..)?.into_iter().map(|(k, _)| k).collect();
+    assert_eq!(keys, vec!["key1", "key2", "key4", "key5"]);
+
+    Ok(())
+}
+
+// The journal should list writes in the order they were applied
+#[test]
+fn journal() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    let journal = store.journal(..)?;
+    let ops: Vec<(u64, JournalOp, &str, Option<usize>)> = journal
+        .iter()
+        .map(|e| (e.seq, e.op, e.key.as_str(), e.value_size))
+        .collect();
+    assert_eq!(
+        ops,
+        vec![
+            (1, JournalOp::Set, "key1", Some(6)),
+            (2, JournalOp::Set, "key2", Some(6)),
+            (3, JournalOp::Remove, "key1", None),
+        ]
+    );
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let seqs: Vec<u64> = store.journal(2..)?.iter().map(|e| e.seq).collect();
+    assert_eq!(seqs, vec![2, 3]);
+
+    Ok(())
+}
+
+// `kvs journal` should print one line per write within the requested range
+#[test]
+fn cli_journal() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["journal", "--since", "2", "--until", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\tset\tkey2\t6").and(contains("key1").not()));
+
+    Ok(())
+}
+
+// Creation time should survive overwrites and compaction while modification time advances
+#[test]
+fn key_metadata() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_with_meta("key1".to_owned())?, None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let (_, first) = store.get_with_meta("key1".to_owned())?.unwrap();
+    assert_eq!(first.created, first.modified);
+
+    thread::sleep(Duration::from_millis(10));
+    for iter in 0..1001 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    let (value, meta) = store.get_with_meta("key1".to_owned())?.unwrap();
+    assert_eq!(value, "value1000");
+    assert_eq!(meta.created, first.created);
+    assert!(meta.modified > first.modified);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_with_meta("key1".to_owned())?, Some((value, meta)));
+
+    Ok(())
+}
+
+// `kvs get --meta <KEY>` should print the value followed by its timestamps
+#[test]
+fn cli_get_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "--meta", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("value1\n")
+                .and(contains("created: "))
+                .and(contains("modified: ")),
+        );
+
+    Ok(())
+}
+
+// Prefix scans should only return keys under the prefix
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in &["user:4", "user:42:b", "user:42:a", "user:420", "user:43:a"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+
+    let entries: Vec<(String, String)> = store.scan_prefix("user:42:")?.collect();
+    assert_eq!(
+        entries,
+        vec![
+            ("user:42:a".to_owned(), "value-user:42:a".to_owned()),
+            ("user:42:b".to_owned(), "value-user:42:b".to_owned()),
+        ]
+    );
+    assert_eq!(store.scan_prefix("group:")?.count(), 0);
+
+    Ok(())
+}
+
+// Iteration should visit every live entry exactly once in key order
+#[test]
+fn iterate_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.iter().count(), 0);
+
+    for key_id in (0..10).rev() {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key3".to_owned())?;
+    store.set("key5".to_owned(), "updated".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let entries = store.iter().collect::<Result<Vec<(String, String)>>>()?;
+    assert_eq!(entries.len(), 9);
+    assert_eq!(entries[0], ("key0".to_owned(), "value0".to_owned()));
+    assert_eq!(entries[4], ("key5".to_owned(), "updated".to_owned()));
+    assert!(entries.iter().all(|(k, _)| k != "key3"));
+
+    let mut count = 0;
+    for entry in &store {
+        entry?;
+        count += 1;
+    }
+    assert_eq!(count, 9);
+
+    Ok(())
+}
+
+// Only keys written after the given time should be reported, oldest first
+#[test]
+fn modified_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let (_, meta) = store.get_with_meta("key2".to_owned())?.unwrap();
+
+    thread::sleep(Duration::from_millis(10));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    thread::sleep(Duration::from_millis(10));
+    store.set("key1".to_owned(), "updated".to_owned())?;
+
+    assert_eq!(store.modified_since(meta.modified), vec!["key3", "key1"]);
+    assert_eq!(store.modified_since(0).len(), 3);
+    assert_eq!(store.modified_before(meta.modified + 1), vec!["key2"]);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.modified_since(meta.modified), vec!["key3", "key1"]);
+    drop(store);
+
+    let cutoff = humantime::format_rfc3339_millis(
+        std::time::UNIX_EPOCH + Duration::from_millis(meta.modified + 1),
+    )
+    .to_string();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "--modified-before", &cutoff])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("key2\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "key2", "--meta"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("key2\t").and(contains("Z\t")));
+
+    Ok(())
+}
+
+// Conditional gets should only return values that changed since the caller's copy
+#[test]
+fn conditional_get() -> Result<()> {
+    let mut store = KvStore::in_memory()?;
+    assert_eq!(
+        store.get_if_version_differs("key1".to_owned(), 0)?,
+        Conditional::NotFound
+    );
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let meta = match store.get_if_version_differs("key1".to_owned(), 0)? {
+        Conditional::Modified(value, meta) => {
+            assert_eq!(value, "value1");
+            meta
+        }
+        other => panic!("expected a value, got {:?}", other),
+    };
+    assert_eq!(
+        store.get_if_version_differs("key1".to_owned(), meta.seq)?,
+        Conditional::NotModified
+    );
+    assert_eq!(
+        store.get_if_modified_since("key1".to_owned(), meta.modified)?,
+        Conditional::NotModified
+    );
+
+    thread::sleep(Duration::from_millis(10));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(matches!(
+        store.get_if_version_differs("key1".to_owned(), meta.seq)?,
+        Conditional::Modified(ref value, _) if value == "value2"
+    ));
+    assert!(matches!(
+        store.get_if_modified_since("key1".to_owned(), meta.modified)?,
+        Conditional::Modified(ref value, _) if value == "value2"
+    ));
+    Ok(())
+}
+
+// Output flags should make keys and values with control characters safe to print
+#[test]
+fn cli_get_output_encoding() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "line1\nline2\u{1b}".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "--escape", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("line1\\nline2\\u{1b}").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "--base64", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("bGluZTEKbGluZTIb").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "--strict", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(b"bin\xff".to_vec(), b"\x00".to_vec())?;
+    store.set_bytes(b"key2".to_vec(), b"\xff".to_vec())?;
+    drop(store);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "--meta", "--base64", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("/w==\ncreated: "));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "--base64", "bin"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Ymlu/w==").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--escape"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(r#"{"key":"bin\\xff","value":"\\0"}"#));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--strict"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let exported = temp_dir.path().join("export.jsonl");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--base64", exported.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(other_dir.path())?;
+    store.import_jsonl(io::BufReader::new(std::fs::File::open(&exported)?))?;
+    assert_eq!(store.get_bytes(b"bin\xff")?, Some(b"\x00".to_vec()));
+
+    Ok(())
+}
+
+// Introspection methods should only account for live keys
+#[test]
+fn keys_and_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    assert_eq!(store.len(), 0);
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key3".to_owned())?;
+    store.set_with_ttl(
+        "key4".to_owned(),
+        "value4".to_owned(),
+        Duration::from_millis(20),
+    )?;
+    assert!(store.contains_key("key4"));
+    thread::sleep(Duration::from_millis(50));
+
+    assert!(!store.is_empty());
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key1", "key2"]);
+    assert!(store.contains_key("key1"));
+    assert!(!store.contains_key("key3"));
+    assert!(!store.contains_key("key4"));
+
+    Ok(())
+}
+
+// Identical values should be stored once in the value log when de-duplication is enabled
+#[test]
+fn value_dedup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file_size = |name: &str| {
+        std::fs::metadata(temp_dir.path().join(name))
+            .map(|m| m.len())
+            .unwrap()
+    };
+    let mut store = KvStore::options().value_dedup(true).open(temp_dir.path())?;
+
+    let blob = "x".repeat(10_000);
+    store.set("key1".to_owned(), blob.clone())?;
+    store.set("key2".to_owned(), blob.clone())?;
+    store.set("key3".to_owned(), blob.clone())?;
+    assert!(file_size("data.log") < 1000);
+    assert!(file_size("data.vlog") < 2 * blob.len() as u64);
+    store.set("key3".to_owned(), "other".to_owned())?;
+
+    drop(store);
+    let mut store = KvStore::options().value_dedup(true).open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(blob.clone()));
+    assert_eq!(store.get("key2".to_owned())?, Some(blob.clone()));
+    assert_eq!(store.get("key3".to_owned())?, Some("other".to_owned()));
+
+    // Compaction should keep the shared value while it is referenced
+    store.remove("key1".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key2".to_owned())?, Some(blob.clone()));
+    assert!(file_size("data.log") < 1000);
+    assert!(file_size("data.vlog") >= blob.len() as u64);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some(blob));
+
+    // and drop it once the last key referring to it is gone
+    store.remove("key2".to_owned())?;
+    store.compact()?;
+    assert!(file_size("data.vlog") < 1000);
+
+    Ok(())
+}
+
+// multi_get should return values in the order the keys were requested
+#[test]
+fn multi_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    let keys: Vec<String> = vec!["key4", "missing", "key0", "key2", "key4"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    assert_eq!(
+        store.multi_get(&keys)?,
+        vec![
+            Some("value4".to_owned()),
+            None,
+            Some("value0".to_owned()),
+            Some("value2".to_owned()),
+            Some("value4".to_owned()),
+        ]
+    );
+    assert_eq!(store.multi_get(&[])?, vec![]);
+
+    Ok(())
+}
+
+// Values larger than the chunk size should survive reopening and compaction intact
+#[test]
+fn chunked_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    // Multi-byte characters make sure chunk boundaries never split a character
+    let large: String = "aé€".repeat(700_000);
+    store.set("large".to_owned(), large.clone())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large.clone()));
+    assert_eq!(store.journal(..)?[0].value_size, Some(large.len()));
+
+    for iter in 0..1001 {
+        store.set("counter".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some(large));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Removing a prefix should delete exactly the keys under it, persistently
+#[test]
+fn remove_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in &["user:1:a", "user:1:b", "user:10:a", "user:2:a"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+
+    assert_eq!(store.remove_prefix("user:1:")?, 2);
+    assert_eq!(store.remove_prefix("user:1:")?, 0);
+    assert_eq!(
+        store.keys().collect::<Vec<_>>(),
+        vec!["user:10:a", "user:2:a"]
+    );
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.keys().collect::<Vec<_>>(),
+        vec!["user:10:a", "user:2:a"]
+    );
+    assert_eq!(store.get("user:1:a".to_owned())?, None);
+    store.set("user:1:a".to_owned(), "again".to_owned())?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("user:1:a".to_owned())?, Some("again".to_owned()));
+
+    Ok(())
+}
+
+// Range reads should return the requested slice of both small and chunked values
+#[test]
+fn get_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("small".to_owned(), "hello world".to_owned())?;
+    let large: String = (0..500_000).map(|i| format!("{:06}", i)).collect();
+    store.set("large".to_owned(), large.clone())?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_range("small".to_owned(), 6, 5)?,
+        Some("world".to_owned())
+    );
+    assert_eq!(
+        store.get_range("small".to_owned(), 6, 100)?,
+        Some("world".to_owned())
+    );
+    assert_eq!(
+        store.get_range("small".to_owned(), 100, 5)?,
+        Some(String::new())
+    );
+    assert_eq!(store.get_range("missing".to_owned(), 0, 5)?, None);
+
+    let offset = 1024 * 1024 - 3;
+    assert_eq!(
+        store.get_range("large".to_owned(), offset, 12)?,
+        Some(large[offset..offset + 12].to_string())
+    );
+    assert_eq!(
+        store.get_range("large".to_owned(), large.len() - 6, 100)?,
+        Some("499999".to_owned())
+    );
+
+    store.set("utf8".to_owned(), "é".to_owned())?;
+    assert!(store.get_range("utf8".to_owned(), 1, 1).is_err());
+
+    Ok(())
+}
+
+// Clearing should leave an empty store that can still be written to
+#[test]
+fn clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    store.clear()?;
+    assert!(store.is_empty());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key3"]);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.journal(..)?[0].seq, 3);
+
+    Ok(())
+}
+
+// `kvs clear` should require confirmation
+#[test]
+fn cli_clear() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["clear"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["clear", "--yes"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Session {
+    user: String,
+    roles: Vec<String>,
+    expires: Option<u64>,
+}
+
+// Typed values should round-trip through the store
+#[test]
+fn typed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let session = Session {
+        user: "alice".to_owned(),
+        roles: vec!["admin".to_owned()],
+        expires: None,
+    };
+    store.set_as("session".to_owned(), &session)?;
+    store.set_as("count".to_owned(), &42u32)?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_as::<Session>("session".to_owned())?,
+        Some(session)
+    );
+    assert_eq!(store.get_as::<u32>("count".to_owned())?, Some(42));
+    assert_eq!(store.get_as::<u32>("missing".to_owned())?, None);
+    assert!(store.get_as::<u32>("session".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Arbitrary bytes should be stored as keys and values and survive reopening
+#[test]
+fn binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let key = vec![0xff, 0x00, 0x01];
+    let value = vec![0x08, 0x96, 0x01, 0xc3, 0x28];
+    store.set_bytes(key.clone(), value.clone())?;
+    store.set("text".to_owned(), "value".to_owned())?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value));
+    assert_eq!(store.get_bytes(b"text")?, Some(b"value".to_vec()));
+    assert_eq!(
+        store.keys_bytes().collect::<Vec<_>>(),
+        vec![b"text", &key[..]]
+    );
+    store.set_bytes(b"bad".to_vec(), vec![0xc3, 0x28])?;
+    assert!(store.get("bad".to_owned()).is_err());
+    store.remove_bytes(&key)?;
+    assert_eq!(store.get_bytes(&key)?, None);
+
+    Ok(())
+}
+
+// `kvs doctor` should report on the store and leave no trace of its self-test
+#[test]
+fn cli_doctor() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    std::fs::write(temp_dir.path().join("doctor.log"), "mine")?;
+    let files = std::fs::read_dir(temp_dir.path())?.count();
+
+    let work_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--db", temp_dir.path().to_str().unwrap(), "doctor"])
+        .current_dir(&work_dir)
+        .assert()
+        .success()
+        .stdout(contains("1 keys").and(contains("compact round trip succeeded")));
+    assert_eq!(
+        std::fs::read_to_string(temp_dir.path().join("doctor.log"))?,
+        "mine"
+    );
+    assert_eq!(std::fs::read_dir(temp_dir.path())?.count(), files);
+    assert_eq!(std::fs::read_dir(work_dir.path())?.count(), 0);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("doctor".to_owned())?, None);
+    drop(store);
+
+    let mut store = KvStore::options()
+        .compaction_threshold(1000)
+        .open(temp_dir.path())?;
+    for i in 0..30 {
+        store.set("big".to_owned(), format!("{}{}", "x".repeat(100_000), i))?;
+    }
+    drop(store);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["doctor"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("info: large key big")
+                .and(contains("recommend: compact now"))
+                .and(contains("tombstone").and(contains("migrate").not())),
+        );
+
+    Ok(())
+}
+
+// Buckets should have their own key space that survives reopening
+#[test]
+fn buckets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "root".to_owned())?;
+    store
+        .bucket("users")?
+        .set("key1".to_owned(), "alice".to_owned())?;
+    store
+        .bucket("orders")?
+        .set("key2".to_owned(), "42".to_owned())?;
+
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key1"]);
+    assert_eq!(store.get("key1".to_owned())?, Some("root".to_owned()));
+    assert!(store.bucket("../etc").is_err());
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    let users = store.bucket("users")?;
+    assert_eq!(users.get("key1".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(users.get("key2".to_owned())?, None);
+    assert_eq!(store.bucket("orders")?.len(), 1);
+
+    Ok(())
+}
+
+// Merge operands should be combined with the stored value on read and survive compaction
+#[test]
+fn merge_operator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.merge("list".to_owned(), "a".to_owned()).is_err());
+
+    let append = |_: &[u8], existing: Option<&[u8]>, operand: &[u8]| {
+        let mut value = existing.map(|v| v.to_vec()).unwrap_or_default();
+        if !value.is_empty() {
+            value.push(b',');
+        }
+        value.extend_from_slice(operand);
+        value
+    };
+    store.set_merge_operator(append);
+    store.set("list".to_owned(), "a".to_owned())?;
+    store.merge("list".to_owned(), "b".to_owned())?;
+    store.merge("list".to_owned(), "c".to_owned())?;
+    store.merge("new".to_owned(), "x".to_owned())?;
+    assert_eq!(store.get("list".to_owned())?, Some("a,b,c".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.get("list".to_owned()).is_err());
+    store.set_merge_operator(append);
+    assert_eq!(store.get("list".to_owned())?, Some("a,b,c".to_owned()));
+    assert_eq!(store.get("new".to_owned())?, Some("x".to_owned()));
+
+    for i in 0..1000 {
+        store.merge("count".to_owned(), i.to_string())?;
+    }
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_merge_operator(append);
+    assert_eq!(store.get("list".to_owned())?, Some("a,b,c".to_owned()));
+    let count = store.get("count".to_owned())?.unwrap_or_default();
+    assert_eq!(count.split(',').count(), 1000);
+
+    Ok(())
+}
+
+// Incrementing should treat values as integers and reject anything else
+#[test]
+fn incr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.incr("hits".to_owned(), 5)?, 5);
+    assert_eq!(store.incr("hits".to_owned(), -7)?, -2);
+    store.set("max".to_owned(), "9223372036854775807".to_owned())?;
+    assert!(store.incr("max".to_owned(), 1).is_err());
+    store.set("name".to_owned(), "alice".to_owned())?;
+    assert!(store.incr("name".to_owned(), 1).is_err());
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("hits".to_owned())?, Some("-2".to_owned()));
+    assert_eq!(store.get("name".to_owned())?, Some("alice".to_owned()));
+
+    Ok(())
+}
+
+// Appends should build up a value without rewriting it
+#[test]
+fn append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.append("events".to_owned(), "start;".to_owned())?, 6);
+    assert_eq!(store.append("events".to_owned(), "stop;".to_owned())?, 11);
+    store.set("name".to_owned(), "al".to_owned())?;
+    assert_eq!(store.append("name".to_owned(), "ice".to_owned())?, 5);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("events".to_owned())?,
+        Some("start;stop;".to_owned())
+    );
+    assert_eq!(store.get("name".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(
+        store.journal(..)?.last().map(|e| e.op),
+        Some(JournalOp::Append)
+    );
+
+    Ok(())
+}
+
+// The value should only be computed when the key is missing
+#[test]
+fn get_or_insert_with() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let value = store.get_or_insert_with("key1".to_owned(), || panic!("computed"))?;
+    assert_eq!(value, "value1");
+    let value = store.get_or_insert_with("key2".to_owned(), || "value2".to_owned())?;
+    assert_eq!(value, "value2");
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Entries should support insert-or-modify in a single write
+#[test]
+fn entry_api() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let value = store
+        .entry("key1".to_owned())?
+        .and_modify(|v| v.push('!'))?
+        .or_insert("value1".to_owned())?;
+    assert_eq!(value, "value1");
+    let value = store
+        .entry("key1".to_owned())?
+        .and_modify(|v| v.push('!'))?
+        .or_insert("unused".to_owned())?;
+    assert_eq!(value, "value1!");
+    assert_eq!(store.journal(..)?.len(), 2);
+
+    assert_eq!(
+        store.entry("key1".to_owned())?.remove()?,
+        Some("value1!".to_owned())
+    );
+    assert_eq!(store.entry("key1".to_owned())?.remove()?, None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Replacing and taking values should hand back what was stored before
+#[test]
+fn previous_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.replace("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(
+        store.replace("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.take("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.take("key1".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Renaming should move the value in one record and fail for missing keys
+#[test]
+fn rename() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.rename("key1".to_owned(), "key2".to_owned())?;
+    assert!(store.rename("key1".to_owned(), "key3".to_owned()).is_err());
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    let last = store.journal(..)?.pop().unwrap();
+    assert_eq!(last.op, JournalOp::Rename);
+    assert_eq!(last.new_key, Some("key2".to_owned()));
+
+    Ok(())
+}
+
+// Snapshots should keep seeing the old state across writes and compaction
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let mut snapshot = store.snapshot()?;
+
+    store.remove("key1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    for i in 0..1100 {
+        store.set("key2".to_owned(), format!("value{}", i))?;
+    }
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3".to_owned())?, None);
+    assert_eq!(snapshot.keys().collect::<Vec<_>>(), vec!["key1", "key2"]);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1099".to_owned()));
+
+    Ok(())
+}
+
+// Restoring a backup should bring back the state at the time of the backup
+#[test]
+fn backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.backup(backup_dir.path())?;
+
+    store.remove("key1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let mut backup = KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(backup);
+
+    store.restore(backup_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Restoring should keep the watchers, indexes and buckets registered on the store
+#[test]
+fn restore_keeps_registrations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "red".to_owned())?;
+    let seq = store.journal(..)?.last().map(|e| e.seq).unwrap();
+    store.backup(backup_dir.path())?;
+    store.set("key1".to_owned(), "blue".to_owned())?;
+    store.create_index("color", |value| vec![value.to_vec()])?;
+    let watch = store.watch("key");
+    store
+        .bucket("users")?
+        .set("user1".to_owned(), "name1".to_owned())?;
+
+    store.restore(backup_dir.path())?;
+    assert_eq!(
+        store.find_by_index("color", "red")?,
+        vec!["key1".to_owned()]
+    );
+    assert!(store.find_by_index("color", "blue")?.is_empty());
+    store.set("key2".to_owned(), "blue".to_owned())?;
+    assert!(watch.try_next().is_some_and(|event| event.key == b"key2"));
+    assert_eq!(
+        store.bucket("users")?.get("user1".to_owned())?,
+        Some("name1".to_owned())
+    );
+
+    store.restore_to(RestorePoint::Seq(seq))?;
+    assert!(store.find_by_index("color", "blue")?.is_empty());
+    store.set("key3".to_owned(), "blue".to_owned())?;
+    assert!(watch.try_next().is_some_and(|event| event.key == b"key3"));
+    Ok(())
+}
+
+// `kvs backup` and `kvs restore` should round trip the data
+#[test]
+fn cli_backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_path = backup_dir.path().to_str().unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["backup", backup_path])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["restore", backup_path])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
+}
+
+// Incremental backups should only copy what was appended since the last backup
+#[test]
+fn incremental_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let full = store.backup_incremental(backup_dir.path())?;
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let incremental = store.backup_incremental(backup_dir.path())?;
+    let log_len = std::fs::metadata(temp_dir.path().join("data.log"))?.len();
+    assert!(incremental > 0);
+    assert_eq!(full + incremental, log_len);
+    assert_eq!(store.backup_incremental(backup_dir.path())?, 0);
+
+    let mut backup = KvStore::open(backup_dir.path())?;
+    assert_eq!(backup.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(backup.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Backups should record checksums that catch a damaged file before it is restored
+#[test]
+fn verified_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.backup_incremental(backup_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.backup_incremental(backup_dir.path())?;
+    KvStore::verify_backup(backup_dir.path())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let backup_log = backup_dir.path().join("data.log");
+    let mut data = std::fs::read(&backup_log)?;
+    let last = data.len() - 2;
+    data[last] ^= 0xff;
+    std::fs::write(&backup_log, data)?;
+    assert!(matches!(
+        KvStore::verify_backup(backup_dir.path()),
+        Err(KvError::CorruptBackup(ref file)) if file == "data.log"
+    ));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["restore", "--verify", backup_dir.path().to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Rewinding should undo writes made after the restore point
+#[test]
+fn restore_to_point() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let seq = store.journal(..)?.last().map(|e| e.seq).unwrap();
+    store.remove("key1".to_owned())?;
+    store.set("key2".to_owned(), "changed".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    store.restore_to(RestorePoint::Seq(seq))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert!(store.journal(..)?.last().map(|e| e.seq).unwrap() > seq + 3);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Exported data should import into another store unchanged
+#[test]
+fn export_import_jsonl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_bytes(vec![0xff], vec![0xc3, 0x28])?;
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(60),
+    )?;
+
+    let mut buf = Vec::new();
+    assert_eq!(store.export_jsonl(&mut buf)?, 3);
+    let text = String::from_utf8(buf.clone()).unwrap();
+    assert!(text.starts_with(r#"{"key":"key1","value":"value1"}"#));
+
+    let mut other = KvStore::open(other_dir.path())?;
+    assert_eq!(other.import_jsonl(&buf[..])?, 3);
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(other.get_bytes(&[0xff])?, Some(vec![0xc3, 0x28]));
+    assert!(other
+        .get_with_meta("key2".to_owned())?
+        .unwrap()
+        .1
+        .expires_at
+        .is_some());
+    assert!(other.import_jsonl(&b"not json\n"[..]).is_err());
+
+    Ok(())
+}
+
+// `kvs export` output should be accepted by `kvs import`
+#[test]
+fn cli_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "jsonl"])
+        .current_dir(&temp_dir)
+        .output()
+        .expect("unable to run kvs export");
+    assert!(output.status.success());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import"])
+        .current_dir(&other_dir)
+        .with_stdin()
+        .buffer(output.stdout)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&other_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
+}
+
+// CSV rows should be imported using the chosen columns
+#[test]
+fn import_csv() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let csv = "id,name,email\n1,alice,alice@example.com\n2,bob,\"bob,jr@example.com\"\n";
+    assert_eq!(store.import_csv(csv.as_bytes(), "name", "email")?, 2);
+    assert_eq!(
+        store.get("bob".to_owned())?,
+        Some("bob,jr@example.com".to_owned())
+    );
+    assert_eq!(store.import_csv(csv.as_bytes(), "0", "1")?, 2);
+    assert_eq!(store.get("1".to_owned())?, Some("alice".to_owned()));
+    assert!(store.import_csv(csv.as_bytes(), "missing", "name").is_err());
+
+    Ok(())
+}
+
+// `kvs import --format csv` should load a CSV file
+#[test]
+fn cli_import_csv() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("users.csv"),
+        "user,role\nalice,admin\n",
+    )?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "import",
+            "--format",
+            "csv",
+            "--key-column",
+            "user",
+            "--value-column",
+            "role",
+            "users.csv",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "alice"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("admin").trim());
+
+    Ok(())
+}
+
+// String keys should be imported from a Redis dump, skipping other types and expired keys
+#[test]
+fn import_rdb() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut rdb = b"REDIS0009".to_vec();
+    rdb.extend(b"\xfa\x09redis-ver\x056.0.0");
+    rdb.extend(b"\xfe\x00\xfb\x04\x01");
+    rdb.extend(b"\x00\x04name\x05alice");
+    rdb.extend(b"\x00\x05count\xc0\x7b");
+    rdb.extend(b"\x00\x03lzf\xc3\x04\x03\x02abc");
+    rdb.extend(b"\x01\x04list\x02\x01a\x01b");
+    rdb.extend(b"\xfc\xe8\x03\x00\x00\x00\x00\x00\x00\x00\x03old\x01x");
+    rdb.extend(b"\xff\x00\x00\x00\x00\x00\x00\x00\x00");
+
+    assert_eq!(store.import_rdb(&rdb[..])?, 3);
+    assert_eq!(store.get("name".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(store.get("count".to_owned())?, Some("123".to_owned()));
+    assert_eq!(store.get("lzf".to_owned())?, Some("abc".to_owned()));
+    assert_eq!(store.get("list".to_owned())?, None);
+    assert_eq!(store.get("old".to_owned())?, None);
+    assert!(store.import_rdb(&b"NOTREDIS"[..]).is_err());
+
+    Ok(())
+}
+
+// `kvs export --format sqlite` should write a queryable table
+#[cfg(feature = "sqlite")]
+#[test]
+fn cli_export_sqlite() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "sqlite", "out.db"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let conn = rusqlite::Connection::open(temp_dir.path().join("out.db")).unwrap();
+    let (value, kind): (Vec<u8>, String) = conn
+        .query_row(
+            "SELECT value, typeof(value) FROM kv WHERE key = 'key2'",
+            rusqlite::NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!((value.as_slice(), kind.as_str()), (&b"value2"[..], "blob"));
+
+    Ok(())
+}
+
+// Bulk loaded pairs should be readable before and after reopening
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("old".to_owned()));
+
+    let entries = (0..1000).map(|i| (format!("key{}", i), format!("value{}", i)));
+    assert_eq!(store.bulk_load(entries)?, 1000);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    store.bulk_load(vec![("large", "x".repeat(3 * 1024 * 1024))])?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1001);
+    assert_eq!(store.get("key999".to_owned())?, Some("value999".to_owned()));
+    assert_eq!(
+        store.get("large".to_owned())?.map(|v| v.len()),
+        Some(3 * 1024 * 1024)
+    );
+
+    Ok(())
+}
+
+// Bulk loads should be refused whole when a pair is too large or the quotas are exceeded,
+// and store values as the store's options say
+#[test]
+fn bulk_load_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStore::options()
+        .max_value_size(100)
+        .max_keys(3)
+        .value_log_threshold(50);
+    let mut store = options.open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    let log_size = store.size_on_disk()?;
+    let entries = vec![("key1", "value1".to_owned()), ("key2", "x".repeat(200))];
+    assert!(matches!(
+        store.bulk_load(entries),
+        Err(KvError::ValueTooLarge)
+    ));
+    let entries = (1..4).map(|i| (format!("key{}", i), format!("value{}", i)));
+    assert!(matches!(
+        store.bulk_load(entries),
+        Err(KvError::QuotaExceeded)
+    ));
+    assert_eq!(store.size_on_disk()?, log_size);
+
+    let entries = vec![("key1", "value1".to_owned()), ("key2", "y".repeat(80))];
+    assert_eq!(store.bulk_load(entries)?, 2);
+    assert!(temp_dir.path().join("data.vlog").exists());
+
+    drop(store);
+    let mut store = options.open(temp_dir.path())?;
+    assert_eq!(store.len(), 3);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("y".repeat(80)));
+
+    // Identical values should be shared, and overwrites should lead to compaction
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .value_dedup(true)
+        .compaction_threshold(1)
+        .open(temp_dir.path())?;
+    let blob = "z".repeat(10_000);
+    store.bulk_load(vec![("a", blob.clone()), ("b", blob.clone())])?;
+    assert!(store.size_on_disk()? < 2 * blob.len() as u64);
+    store.bulk_load(vec![("a", "1"), ("b", "2")])?;
+    assert_eq!(store.stats()?.compactions, 1);
+    assert_eq!(store.get("a".to_owned())?, Some("1".to_owned()));
+    Ok(())
+}
+
+// The export iterator should yield live pairs in key order
+#[test]
+fn export_iterator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "gone".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    thread::sleep(Duration::from_millis(5));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let entries = store.export().collect::<Result<Vec<_>>>()?;
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].key, b"key1");
+    assert_eq!(entries[1].value, b"value2");
+
+    Ok(())
+}
+
+// Compacting on demand should shrink the log and keep the data
+#[test]
+fn compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let log = temp_dir.path().join("data.log");
+    let before = std::fs::metadata(&log)?.len();
+    store.compact()?;
+    assert!(std::fs::metadata(&log)?.len() < before / 10);
+    assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+// Stats should account for live and dead bytes in the log
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key2".to_owned())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 2);
+    assert_eq!(stats.live_bytes + stats.dead_bytes, stats.log_size);
+    assert!(stats.dead_bytes > stats.live_bytes);
+    assert!(stats.cache_hit_rate() > 0.0);
+
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let reopened = store.stats()?;
+    assert_eq!(reopened.live_bytes, stats.live_bytes);
+    assert!(reopened.dead_bytes < 64);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 2").and(contains("compactions: 0")));
+
+    Ok(())
+}
+
+// Size histograms should bucket keys and values by powers of two
+#[test]
+fn size_histograms() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "small".to_owned())?;
+    store.set("bb".to_owned(), "x".repeat(5000))?;
+    store.set("cc".to_owned(), "y".repeat(5000))?;
+
+    let stats = store.stats()?;
+    let key_sizes: Vec<(u64, usize)> = stats.key_sizes.buckets().collect();
+    assert_eq!(key_sizes, vec![(2, 1), (4, 2)]);
+    assert_eq!(stats.value_sizes.total(), 3);
+    assert_eq!(stats.value_sizes.buckets().last(), Some((8192, 2)));
+
+    Ok(())
+}
+
+// Disk usage should include buckets and the live size should track compaction
+#[test]
+fn disk_usage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..50 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let log_size = std::fs::metadata(temp_dir.path().join("data.log"))?.len();
+    assert_eq!(store.size_on_disk()?, log_size);
+    assert!(store.estimated_live_size() < log_size / 10);
+
+    store
+        .bucket("users")?
+        .set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.size_on_disk()? > log_size);
+
+    let live = store.estimated_live_size();
+    store.compact()?;
+    let compacted = std::fs::metadata(temp_dir.path().join("data.log"))?.len();
+    assert!(compacted >= live && compacted < live + 64);
+
+    Ok(())
+}
+
+// Options should tune compaction and a read-only store should reject writes
+#[test]
+fn open_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .cache_capacity(10)
+        .compaction_threshold(10)
+        .sync_policy(SyncPolicy::Always)
+        .open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    assert_eq!(store.stats()?.compactions, 1);
+    drop(store);
+
+    let mut store = KvStore::options().read_only(true).open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value19".to_owned()));
+    match store.set("key1".to_owned(), "value".to_owned()) {
+        Err(KvError::ReadOnly) => {}
+        other => panic!("expected read-only error, got {:?}", other),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value19".to_owned()));
+
+    Ok(())
+}
+
+// The read cache should stay within its byte budget
+#[test]
+fn cache_byte_budget() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .cache_capacity(1000)
+        .cache_bytes(1000)
+        .open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), "x".repeat(100))?;
+    }
+    let cached = store.stats()?.cache_bytes;
+    assert!(cached > 0 && cached <= 1000);
+
+    store.set("big".to_owned(), "y".repeat(5000))?;
+    assert!(store.stats()?.cache_bytes <= 1000);
+    assert_eq!(store.get("big".to_owned())?, Some("y".repeat(5000)));
+    assert_eq!(store.get("key0".to_owned())?, Some("x".repeat(100)));
+
+    Ok(())
+}
+
+// A scan-resistant cache should keep values read repeatedly through a burst of one-off writes
+#[test]
+fn cache_eviction_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .cache_capacity(10)
+        .eviction(Eviction::ScanResistant)
+        .open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(format!("hot{}", i), "value".to_owned())?;
+        store.get(format!("hot{}", i))?;
+    }
+    for i in 0..100 {
+        store.set(format!("cold{}", i), "value".to_owned())?;
+    }
+
+    let hits = store.stats()?.cache_hits;
+    for i in 0..5 {
+        assert_eq!(store.get(format!("hot{}", i))?, Some("value".to_owned()));
+    }
+    assert_eq!(store.stats()?.cache_hits, hits + 5);
+
+    Ok(())
+}
+
+// Reads through the block cache should stay correct across overwrites, compaction and clear
+#[test]
+fn block_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .block_cache(64 * 1024)
+        .open(temp_dir.path())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("big".to_owned(), "x".repeat(20_000))?;
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("big".to_owned())?, Some("x".repeat(20_000)));
+
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    store.clear()?;
+    store.set("key2".to_owned(), "again".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("again".to_owned()));
+
+    Ok(())
+}
+
+// A second writer on the same log should be refused while the first is open
+#[test]
+fn exclusive_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::AlreadyLocked) => {}
+        other => panic!("expected lock error, got {:?}", other.map(|_| ())),
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    store.compact()?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A read-only store should open a locked log and keep seeing the state as of opening
+#[test]
+fn read_only_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut reader = KvStore::open_read_only(temp_dir.path())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(reader.compact().is_err());
+    assert!(reader.remove("key1".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// An in-memory store should behave like a file-backed one without creating any files
+#[test]
+fn in_memory_store() -> Result<()> {
+    let mut store = KvStore::in_memory()?;
+    for i in 0..10 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let mut snapshot = store.snapshot()?;
+    store.remove("key2".to_owned())?;
+    store.compact()?;
+    store
+        .bucket("users")?
+        .set("key1".to_owned(), "user1".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value9".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.bucket("users")?.get("key1".to_owned())?,
+        Some("user1".to_owned())
+    );
+    assert!(store.size_on_disk()? < 200);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.backup(temp_dir.path())?;
+    let mut restored = KvStore::in_memory()?;
+    restored.restore(temp_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value9".to_owned()));
+
+    Ok(())
+}
+
+// A temporary store should delete its files when dropped
+#[test]
+fn temporary_store() -> Result<()> {
+    let mut store = KvStore::temporary()?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store
+        .bucket("users")?
+        .set("key1".to_owned(), "user1".to_owned())?;
+    store.compact()?;
+    store.restore_to(RestorePoint::Seq(1))?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let dir = store
+        .path()
+        .parent()
+        .expect("log has a directory")
+        .to_path_buf();
+    assert!(dir.join("data.log").exists());
+    drop(store);
+    assert!(!dir.exists());
+
+    Ok(())
+}
+
+// Reads through a memory mapping should see records written after the log was mapped
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .cache_capacity(0)
+        .mmap(true)
+        .open(temp_dir.path())?;
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    store.set("big".to_owned(), "x".repeat(3 * 1024 * 1024))?;
+    assert_eq!(
+        store.get("big".to_owned())?,
+        Some("x".repeat(3 * 1024 * 1024))
+    );
+
+    let mut snapshot = store.snapshot()?;
+    store.clear()?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Large values should live in the value log, survive compaction and reopening, and be backed up
+#[test]
+fn value_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .value_log_threshold(100)
+        .open(temp_dir.path())?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    for i in 0..5 {
+        store.set("big".to_owned(), format!("{}", i).repeat(1000))?;
+    }
+    store.set("other".to_owned(), "y".repeat(1000))?;
+    let log_size = std::fs::metadata(temp_dir.path().join("data.log"))?.len();
+    assert!(log_size < 1000);
+    assert_eq!(
+        store.get_range("big".to_owned(), 10, 5)?,
+        Some("44444".to_owned())
+    );
+
+    store.compact()?;
+    let values_size = std::fs::metadata(temp_dir.path().join("data.vlog"))?.len();
+    assert_eq!(values_size, 2000);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("big".to_owned())?, Some("4".repeat(1000)));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.backup(backup_dir.path())?;
+    let mut restored = KvStore::in_memory()?;
+    restored.restore(backup_dir.path())?;
+    assert_eq!(restored.get("other".to_owned())?, Some("y".repeat(1000)));
+
+    Ok(())
+}
+
+// Compressed values should take less space and mix with uncompressed ones
+#[cfg(feature = "compression")]
+#[test]
+fn compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = r#"{"name": "value", "tags": ["a", "b"]}"#.repeat(100);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("plain".to_owned(), value.clone())?;
+    drop(store);
+
+    let mut store = KvStore::options().compression(true).open(temp_dir.path())?;
+    let before = std::fs::metadata(temp_dir.path().join("data.log"))?.len();
+    store.set("packed".to_owned(), value.clone())?;
+    let after = std::fs::metadata(temp_dir.path().join("data.log"))?.len();
+    assert!(after - before < before / 4);
+    assert_eq!(store.journal(..)?[1].value_size, Some(value.len()));
+
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::options()
+        .compression(true)
+        .cache_capacity(0)
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("plain".to_owned())?, Some(value.clone()));
+    assert_eq!(store.get("packed".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Encrypted records should not be readable without the key
+#[cfg(feature = "encryption")]
+#[test]
+fn encryption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = [7; 32];
+    let value = "secret value ".repeat(100);
+    let mut store = KvStore::options()
+        .encryption_key(&key)
+        .value_log_threshold(1000)
+        .open(temp_dir.path())?;
+    store.set("small".to_owned(), "secret value".to_owned())?;
+    store.set("large".to_owned(), value.clone())?;
+    drop(store);
+
+    for file in &["data.log", "data.vlog"] {
+        let contents = std::fs::read(temp_dir.path().join(file))?;
+        let text = String::from_utf8_lossy(&contents);
+        assert!(!text.contains("secret value"));
+    }
+    match KvStore::open(temp_dir.path()) {
+        Err(KvError::Encryption) => {}
+        other => panic!("expected encryption error, got {:?}", other.map(|_| ())),
+    }
+    match KvStore::options()
+        .encryption_key(&[8; 32])
+        .open(temp_dir.path())
+    {
+        Err(KvError::Encryption) => {}
+        other => panic!("expected encryption error, got {:?}", other.map(|_| ())),
+    }
+
+    let mut store = KvStore::options()
+        .encryption_key(&key)
+        .cache_capacity(0)
+        .open(temp_dir.path())?;
+    assert_eq!(
+        store.get("small".to_owned())?,
+        Some("secret value".to_owned())
+    );
+    assert_eq!(store.get("large".to_owned())?, Some(value.clone()));
+    assert_eq!(store.journal(..)?[1].value_size, Some(value.len()));
+    Ok(())
+}
+
+// Writes over the configured key and value sizes should be rejected
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .max_key_size(8)
+        .max_value_size(16)
+        .open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    match store.set("a long key name".to_owned(), "value".to_owned()) {
+        Err(KvError::KeyTooLarge) => {}
+        other => panic!("expected KeyTooLarge, got {:?}", other),
+    }
+    match store.set("key".to_owned(), "x".repeat(17)) {
+        Err(KvError::ValueTooLarge) => {}
+        other => panic!("expected ValueTooLarge, got {:?}", other),
+    }
+    match store.append("key".to_owned(), "x".repeat(12)) {
+        Err(KvError::ValueTooLarge) => {}
+        other => panic!("expected ValueTooLarge, got {:?}", other),
+    }
+    let mut batch = WriteBatch::new();
+    batch.set("other".to_owned(), "x".repeat(17));
+    assert!(store.prepare_batch(batch).is_err());
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.len(), 1);
+    Ok(())
+}
+
+// Writes past the quota should fail, or evict the least recently used keys if enabled
+#[test]
+fn quota() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = "x".repeat(100);
+    let mut store = KvStore::options().quota(1000).open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set("key".to_owned(), format!("{}{}", value, i))?;
+    }
+    let mut result = Ok(());
+    for i in 0..20 {
+        result = store.set(format!("key{}", i), value.clone());
+        if result.is_err() {
+            break;
+        }
+    }
+    match result {
+        Err(KvError::QuotaExceeded) => {}
+        other => panic!("expected QuotaExceeded, got {:?}", other),
+    }
+    assert!(store.size_on_disk()? <= 1000);
+    let compactions = store.stats()?.compactions;
+    assert!(store.set("another".to_owned(), value.clone()).is_err());
+    assert_eq!(store.stats()?.compactions, compactions);
+    drop(store);
+
+    let mut store = KvStore::options()
+        .quota(1000)
+        .quota_eviction(true)
+        .open(temp_dir.path())?;
+    let watch = store.watch("key0");
+    for i in 0..20 {
+        std::thread::sleep(Duration::from_millis(2));
+        store.get("key".to_owned())?;
+        store.set(format!("new{}", i), value.clone())?;
+    }
+    assert!(store.size_on_disk()? <= 1000);
+    assert!(store.get("key".to_owned())?.is_some());
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert!(watch.try_next().is_some_and(|event| event.is_remove()));
+    assert_eq!(store.get("new19".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Appending should build on the value a key holds after the quota compacted the log
+#[test]
+fn quota_append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStore::options().quota(1000);
+    let mut store = options.open(temp_dir.path())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.set("k".to_owned(), "a".to_owned())?;
+    let mut i = 0;
+    while store.size_on_disk()? < 950 {
+        store.set("other".to_owned(), format!("value-number-{}", i))?;
+        i += 1;
+    }
+    let suffix = "b".repeat(100);
+    let compactions = store.stats()?.compactions;
+    assert_eq!(store.append("k".to_owned(), suffix.clone())?, 101);
+    assert!(store.stats()?.compactions > compactions);
+    assert_eq!(store.get("k".to_owned())?, Some(format!("a{}", suffix)));
+
+    drop(store);
+    let mut store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("k".to_owned())?, Some(format!("a{}", suffix)));
+    Ok(())
+}
+
+// Reading a value from disk should cache the values written after it
+#[test]
+fn read_ahead() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key2".to_owned(), "updated".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::options().read_ahead(3).open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    for i in &[1, 3, 4] {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.get("key2".to_owned())?, Some("updated".to_owned()));
+    let stats = store.stats()?;
+    assert_eq!((stats.cache_hits, stats.cache_misses), (3, 2));
+    Ok(())
+}
+
+// A store reopened with cache warming should serve the keys cached before it was closed
+#[test]
+fn warm_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStore::options().cache_capacity(2).warm_cache(true);
+    let mut store = options.open(temp_dir.path())?;
+    for i in 0..5 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.get("key0".to_owned())?;
+    store.get("key1".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("data.hot").exists());
+
+    let mut store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.stats()?;
+    assert_eq!((stats.cache_hits, stats.cache_misses), (2, 0));
+    Ok(())
+}
+
+// A store bounded to a number of keys should evict the least recently used ones, durably
+#[test]
+fn bounded_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStore::options().max_keys(3).quota_eviction(true);
+    let mut store = options.open(temp_dir.path())?;
+    for i in 0..3 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+        thread::sleep(Duration::from_millis(2));
+    }
+    store.get("key0".to_owned())?;
+    thread::sleep(Duration::from_millis(2));
+    store.set("key3".to_owned(), "value".to_owned())?;
+    store.set("key4".to_owned(), "value".to_owned())?;
+
+    assert_eq!(store.len(), 3);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.stats()?.evictions, 2);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 3);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Each bucket should be held to its own quotas and report its usage in the stats
+#[test]
+fn bucket_quotas() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.bucket("small")?.set_quota(None, Some(2));
+    store.bucket("large")?.set_quota(Some(1 << 20), None);
+
+    let small = store.bucket("small")?;
+    small.set("key1".to_owned(), "value".to_owned())?;
+    small.set("key2".to_owned(), "value".to_owned())?;
+    small.set("key1".to_owned(), "updated".to_owned())?;
+    assert!(matches!(
+        small.set("key3".to_owned(), "value".to_owned()),
+        Err(KvError::QuotaExceeded)
+    ));
+    let mut batch = WriteBatch::new();
+    batch.set("key3".to_owned(), "value".to_owned());
+    assert!(matches!(
+        small.prepare_batch(batch),
+        Err(KvError::QuotaExceeded)
+    ));
+    small.remove("key2".to_owned())?;
+    small.set("key3".to_owned(), "value".to_owned())?;
+    for i in 0..10 {
+        store
+            .bucket("large")?
+            .set(format!("key{}", i), "value".to_owned())?;
+    }
+
+    let stats = store.stats()?;
+    let small = &stats.buckets["small"];
+    assert_eq!(
+        (small.keys, small.max_keys, small.quota),
+        (2, Some(2), None)
+    );
+    let large = &stats.buckets["large"];
+    assert_eq!(large.keys, 10);
+    assert!(large.bytes > 0 && large.quota == Some(1 << 20));
+    assert!(stats
+        .to_prometheus()
+        .contains("kvs_bucket_keys{bucket=\"large\"} 10\n"));
+    Ok(())
+}
+
+// Watchers should receive every change to keys under their prefix
+#[test]
+fn watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("config/a".to_owned(), "1".to_owned())?;
+    let watch = store.watch("config/");
+
+    store.set("config/a".to_owned(), "2".to_owned())?;
+    store.set("other".to_owned(), "x".to_owned())?;
+    store.rename("config/a".to_owned(), "config/b".to_owned())?;
+    store.remove("config/b".to_owned())?;
+
+    let event = |key: &str, old: Option<&str>, new: Option<&str>| WatchEvent {
+        key: key.as_bytes().to_vec(),
+        old: old.map(|value| value.as_bytes().to_vec()),
+        new: new.map(|value| value.as_bytes().to_vec()),
+    };
+    assert_eq!(
+        watch.try_next(),
+        Some(event("config/a", Some("1"), Some("2")))
+    );
+    assert_eq!(watch.try_next(), Some(event("config/a", Some("2"), None)));
+    assert_eq!(watch.try_next(), Some(event("config/b", None, Some("2"))));
+    let removed = watch.try_next().expect("missing remove event");
+    assert!(removed.is_remove());
+    assert_eq!(watch.try_next(), None);
+
+    drop(store);
+    assert_eq!(watch.count(), 0);
+    Ok(())
+}
+
+// Change sinks should receive every committed change in sequence order
+#[test]
+fn change_sink() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let changes = Arc::new(Mutex::new(Vec::new()));
+    let received = changes.clone();
+    store.add_change_sink(move |change: &Change| {
+        received.lock().unwrap().push(change.clone());
+        Ok(())
+    });
+    let path = temp_dir.path().join("changes.jsonl");
+    store.add_change_sink(JsonLinesSink::new(std::fs::File::create(&path)?));
+
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.append("a".to_owned(), "2".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("b".to_owned(), "3".to_owned());
+    batch.remove("a".to_owned());
+    let token = store.prepare_batch(batch)?;
+    store.commit(token)?;
+
+    let changes = changes.lock().unwrap();
+    let summary: Vec<(&[u8], Option<&[u8]>)> = changes
+        .iter()
+        .map(|change| (&change.key[..], change.value.as_deref()))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (&b"a"[..], Some(&b"1"[..])),
+            (&b"a"[..], Some(&b"12"[..])),
+            (&b"b"[..], Some(&b"3"[..])),
+            (&b"a"[..], None),
+        ]
+    );
+    assert!(changes.windows(2).all(|pair| pair[0].seq <= pair[1].seq));
+
+    let lines = std::fs::read_to_string(&path)?;
+    assert_eq!(lines.lines().count(), 4);
+    assert!(lines.lines().nth(1).unwrap().contains(r#""value":"12""#));
+    Ok(())
+}
+
+// Sharded stores should spread keys over separate logs that can be written in parallel
+#[test]
+fn sharded_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = Arc::new(ShardedStore::open(temp_dir.path(), 4)?);
+    let writers: Vec<_> = (0..4)
+        .map(|thread| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    store.set(format!("key{}-{}", thread, i), i.to_string())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    assert_eq!(store.len(), 200);
+    assert!((0..4).all(|shard| !store.shard(shard).is_empty()));
+    assert!(temp_dir.path().join("shard-3.log").exists());
+    drop(store);
+
+    match ShardedStore::open(temp_dir.path(), 2) {
+        Err(KvError::ShardCountMismatch) => {}
+        other => panic!("expected ShardCountMismatch, got {:?}", other.map(|_| ())),
+    }
+    let store = ShardedStore::open(temp_dir.path(), 4)?;
+    assert_eq!(store.get("key2-7".to_owned())?, Some("7".to_owned()));
+    store.remove("key2-7".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.len(), 199);
+    Ok(())
+}
+
+// Reads at a sequence number should see retained versions, also after compaction and reopening
+#[test]
+fn get_at() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStore::options().retained_versions(3);
+    let mut store = options.open(temp_dir.path())?;
+    let mut seqs = Vec::new();
+    store.set("key".to_owned(), "1".to_owned())?;
+    seqs.push(store.latest_seq());
+    store.set("key".to_owned(), "2".to_owned())?;
+    seqs.push(store.latest_seq());
+    store.remove("key".to_owned())?;
+    seqs.push(store.latest_seq());
+    store.set("key".to_owned(), "3".to_owned())?;
+    seqs.push(store.latest_seq());
+    store.set("key".to_owned(), "4".to_owned())?;
+
+    let expected = vec![None, Some("2".to_owned()), None, Some("3".to_owned())];
+    for _ in 0..2 {
+        let values = seqs
+            .iter()
+            .map(|&seq| store.get_at("key".to_owned(), seq))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(values, expected);
+        assert_eq!(store.get("key".to_owned())?, Some("4".to_owned()));
+        store.compact()?;
+        drop(store);
+        store = options.open(temp_dir.path())?;
+    }
+    Ok(())
+}
+
+// History should list the values of a key newest first, including removals
+#[test]
+fn history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "1".to_owned())?;
+    store.append("key".to_owned(), "2".to_owned())?;
+    store.set("other".to_owned(), "x".to_owned())?;
+    store.remove("key".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key".to_owned(), "3".to_owned());
+    let token = store.prepare_batch(batch)?;
+    store.commit(token)?;
+
+    let values = |history: Vec<KeyVersion>| -> Vec<Option<String>> {
+        history.into_iter().map(|version| version.value).collect()
+    };
+    assert_eq!(
+        values(store.history("key", 10)?),
+        vec![
+            Some("3".to_owned()),
+            None,
+            Some("12".to_owned()),
+            Some("1".to_owned())
+        ]
+    );
+    assert_eq!(store.history("key", 1)?.len(), 1);
+
+    store.compact()?;
+    assert_eq!(
+        values(store.history("key", 10)?),
+        vec![Some("3".to_owned())]
+    );
+    Ok(())
+}
+
+// Secondary indexes should follow every write to the values they index
+#[test]
+fn secondary_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "alice@example.com".to_owned())?;
+    store.set("user:2".to_owned(), "bob@example.com".to_owned())?;
+    store.create_index("domain", |value| {
+        match value.iter().position(|&byte| byte == b'@') {
+            Some(at) => vec![value[at + 1..].to_vec()],
+            None => Vec::new(),
+        }
+    })?;
+    assert_eq!(
+        store.find_by_index("domain", "example.com")?,
+        vec!["user:1".to_owned(), "user:2".to_owned()]
+    );
+
+    store.set("user:2".to_owned(), "bob@example.org".to_owned())?;
+    store.set("user:3".to_owned(), "carol@example.com".to_owned())?;
+    store.remove("user:1".to_owned())?;
+    assert_eq!(
+        store.find_by_index("domain", "example.com")?,
+        vec!["user:3".to_owned()]
+    );
+    assert_eq!(
+        store.find_by_index("domain", "example.org")?,
+        vec!["user:2".to_owned()]
+    );
+    assert!(store.find_by_index("email", "x@y.com").is_err());
+    Ok(())
+}
+
+// `kvs grep` should print the pairs whose key or value contains the pattern
+#[test]
+fn cli_grep() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "alice@example.com".to_owned())?;
+    store.set("user:2".to_owned(), "bob@example.org".to_owned())?;
+    store.set("example".to_owned(), "none".to_owned())?;
+    let found: Vec<Vec<u8>> = store
+        .scan_filter(|_, value| value.ends_with(b".org"))
+        .map(|entry| entry.map(|entry| entry.key))
+        .collect::<Result<_>>()?;
+    assert_eq!(found, vec![b"user:2".to_vec()]);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["grep", "example.com"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("user:1\talice@example.com").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["grep", "--keys", "example"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("example\tnone").trim());
+    Ok(())
+}
+
+// Scans should follow the comparator the store was opened with
+#[test]
+fn key_comparator() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .comparator(natural_order)
+        .open(temp_dir.path())?;
+    for key in &["item10", "item2", "item1", "other"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    let keys: Vec<String> = store.keys().collect();
+    assert_eq!(keys, vec!["item1", "item2", "item10", "other"]);
+    let scanned: Vec<String> = store
+        .scan("item2".to_owned().."item11".to_owned())?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(scanned, vec!["item2", "item10"]);
+    let prefixed: Vec<String> = store.scan_prefix("item")?.map(|(key, _)| key).collect();
+    assert_eq!(prefixed, vec!["item1", "item2", "item10"]);
+    Ok(())
+}
+
+// `--db` and `KVS_DIR` should select the store the CLI works on
+#[test]
+fn cli_db_path() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let first = temp_dir.path().join("first");
+    let second = temp_dir.path().join("second");
+    std::fs::create_dir(&first).unwrap();
+    std::fs::create_dir(&second).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--db"])
+        .arg(&first)
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .env("KVS_DIR", &first)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("--db")
+        .arg(&second)
+        .args(["get", "key1"])
+        .env("KVS_DIR", &first)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    assert!(!temp_dir.path().join("data.log").exists());
+}
+
+// `kvs shell` should run commands from standard input against a single open store
+#[test]
+fn cli_shell() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["shell"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set user:1 Alice Smith\nset user:2 Bob\nget user:1\nrm user:2\nrm user:2\nscan user:\nhistory\n")
+        .assert()
+        .success()
+        .stdout(eq("OK\nOK\nAlice Smith\nOK\nerror: Key not found\nuser:1\tAlice Smith\n   1  set user:1 Alice Smith\n   2  set user:2 Bob\n   3  get user:1\n   4  rm user:2\n   5  rm user:2\n   6  scan user:\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "user:1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Alice Smith").trim());
+}
+
+// `kvs batch` should apply every command and report the ones that failed
+#[test]
+fn cli_batch() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["batch"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("# load users\nset user:1 Alice\nrm user:2\nset user:2 Bob\nget user:2\n")
+        .assert()
+        .failure()
+        .stdout(eq(
+            "OK\nerror: line 3: Key not found\nOK\nBob\n1 commands failed\n",
+        ));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["-"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("get user:1\n")
+        .assert()
+        .success()
+        .stdout(eq("Alice\n"));
+}
+
+// `--output` should print JSON, or report a missing key only through the exit status
+#[test]
+fn cli_output_modes() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "Key not found"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "json", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"key":"key1","value":"Key not found"}"#).trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2", "--output", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"key":"key2","value":null}"#).trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "quiet", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "quiet", "get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "quiet", "rm", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout(is_empty());
+}
+
+// `kvs scan` should list keys with a prefix, up to a limit
+#[test]
+fn cli_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key in &["user:1", "user:2", "user:3", "group:1"] {
+        store.set(key.to_string(), format!("{} value", key))?;
+    }
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("group:1\nuser:1\nuser:2\nuser:3\n"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["scan", "user:", "--limit", "2", "--values"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("user:1\tuser:1 value\nuser:2\tuser:2 value\n"));
+    Ok(())
+}
+
+// The log dump should list every record and flag bytes that cannot be decoded
+#[test]
+fn dump_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let records = store.dump_log()?;
+    drop(store);
+    let kinds: Vec<_> = records.iter().map(|record| record.kind).collect();
+    assert_eq!(kinds, vec![Some("set"), Some("set"), Some("rm")]);
+    assert_eq!(records[1].key.as_deref(), Some("key2"));
+    assert_eq!(records[1].value_size, Some(6));
+    // Records follow the 8-byte header of the log
+    assert_eq!(records[0].offset, 8);
+    assert_eq!(records[1].offset, records[0].offset + records[0].len);
+
+    let path = temp_dir.path().join("data.log");
+    let mut data = std::fs::read(&path)?;
+    let end = (records[0].offset + records[0].len) as usize;
+    data.splice(end..end, vec![0xc1; 3]);
+    std::fs::write(&path, data)?;
+
+    let store = KvStore::options().read_only(true).open(temp_dir.path())?;
+    let records = store.dump_log()?;
+    let kinds: Vec<_> = records.iter().map(|record| record.kind).collect();
+    assert_eq!(kinds, vec![Some("set"), None, Some("set"), Some("rm")]);
+    assert_eq!((records[1].offset, records[1].len), (end as u64, 3));
+    Ok(())
+}
+
+// Metadata should report where the live entry of a key is in the log
+#[test]
+fn key_location() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.metadata("key3").is_none());
+    let records = store.dump_log()?;
+    let location = store.metadata("key2").expect("key2 is stored");
+    assert_eq!(location.path, temp_dir.path().join("data.log"));
+    assert_eq!(
+        (location.offset, location.len),
+        (records[1].offset, records[1].len)
+    );
+    assert_eq!(location.meta.seq, store.latest_seq());
+    store.get("key2".to_owned())?;
+    assert!(store.metadata("key2").unwrap().cached);
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["inspect", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("offset: 8\n"));
+    Ok(())
+}
+
+// `kvs set --ttl` should expire keys and `kvs ttl` should report their remaining lifetime
+#[test]
+fn cli_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "session", "abc", "--ttl", "1h 30s"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "user", "alice"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let store = KvStore::open(temp_dir.path())?;
+    let ttl = store.ttl("session")?.expect("session expires");
+    assert!(ttl <= Duration::from_secs(3630) && ttl > Duration::from_secs(3600));
+    assert_eq!(store.ttl("user")?, None);
+    assert!(matches!(store.ttl("missing"), Err(KvError::KeyNotFound)));
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ttl", "session"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("1h"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["ttl", "user"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("No expiry").trim());
+    Ok(())
+}
+
+// `kvs getset` and `kvs getdel` should print the value a key held before the write
+#[test]
+fn cli_getset_getdel() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["getset", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["getset", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["getdel", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// `kvs exists` should report presence through its exit status and `kvs strlen` the value size
+#[test]
+fn cli_exists_strlen() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "héllo"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["exists", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("true").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["exists", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout(eq("false").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["strlen", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("6").trim());
+}
+
+// `kvs watch` should print changes made by another process to keys with the prefix
+#[test]
+fn cli_watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user:0".to_owned(), "before".to_owned())?;
+
+    let since = store.latest_seq().to_string();
+    let watcher = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["watch", "user:", "--count", "2", "--since", &since])
+        .current_dir(&temp_dir)
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+    thread::sleep(Duration::from_millis(200));
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("group:1".to_owned(), "admins".to_owned())?;
+    store.remove("user:0".to_owned())?;
+
+    let output = watcher.wait_with_output()?;
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "set\tuser:1\talice\nrm\tuser:0\n"
+    );
+    Ok(())
+}
+
+// `kvs bench` should run the requested operations and report their latencies
+#[test]
+fn cli_bench() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "bench",
+            "--writes",
+            "200",
+            "--reads",
+            "100",
+            "--threads",
+            "2",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains("set: 200 ops")
+                .and(predicate::str::contains("get: 100 ops"))
+                .and(predicate::str::contains("p99")),
+        );
+    assert!(!temp_dir.path().join("data.log").exists());
+}
+
+// Stats should count operations and render in the Prometheus text format
+#[test]
+fn prometheus_metrics() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert!(store.remove("key2".to_owned()).is_err());
+
+    let stats = store.stats()?;
+    assert_eq!(stats.writes.count, 2);
+    assert_eq!(stats.reads.count, 1);
+    assert_eq!(stats.removes.count, 2);
+    assert_eq!(stats.writes.latency_micros.total(), 2);
+
+    let metrics = stats.to_prometheus();
+    assert!(metrics.contains("# TYPE kvs_keys gauge\nkvs_keys 1\n"));
+    assert!(metrics.contains("kvs_write_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+    assert!(metrics.contains("kvs_remove_duration_seconds_count 2\n"));
+    Ok(())
+}
+
+static SLOW_OPS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct SlowOpLogger;
+
+impl log::Log for SlowOpLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        SLOW_OPS.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+// Operations slower than the threshold should be logged with the time spent in each phase
+#[test]
+fn slow_op_logging() -> Result<()> {
+    log::set_logger(&SlowOpLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Warn);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .sync_policy(SyncPolicy::Always)
+        .slow_op_threshold(Duration::from_secs(0))
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let logged = SLOW_OPS.lock().unwrap().clone();
+    assert_eq!(logged.len(), 1);
+    assert!(logged[0].starts_with("slow set took"));
+    assert!(logged[0].contains("fsync"));
+    Ok(())
+}
+
+/// Subscriber recording the names of the spans created while it is the default
+#[cfg(feature = "tracing")]
+struct SpanRecorder {
+    names: Arc<Mutex<Vec<&'static str>>>,
+}
+
+#[cfg(feature = "tracing")]
+impl tracing::Subscriber for SpanRecorder {
+    fn enabled(&self, _: &tracing::Metadata) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &tracing::span::Attributes) -> tracing::span::Id {
+        let mut names = self.names.lock().unwrap();
+        names.push(span.metadata().name());
+        tracing::span::Id::from_u64(names.len() as u64)
+    }
+
+    fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record) {}
+
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+    fn event(&self, _: &tracing::Event) {}
+
+    fn enter(&self, _: &tracing::span::Id) {}
+
+    fn exit(&self, _: &tracing::span::Id) {}
+}
+
+// Operations, log I/O and compaction should open tracing spans
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_spans() -> Result<()> {
+    let names = Arc::new(Mutex::new(Vec::new()));
+    let recorder = SpanRecorder {
+        names: Arc::clone(&names),
+    };
+    tracing::subscriber::with_default(recorder, || -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::options()
+            .cache_capacity(0)
+            .sync_policy(SyncPolicy::Always)
+            .open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        store.remove("key1".to_owned())?;
+        store.compact()?;
+        Ok(())
+    })?;
+    let names = names.lock().unwrap();
+    for name in &[
+        "kvs.set",
+        "kvs.append",
+        "kvs.fsync",
+        "kvs.get",
+        "kvs.disk_read",
+        "kvs.remove",
+        "kvs.compact",
+    ] {
+        assert!(names.contains(name), "no {} span in {:?}", name, names);
+    }
+    Ok(())
+}
+
+// Event hooks should see writes, removals, compactions and cache evictions
+#[test]
+fn event_hooks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options().cache_capacity(1).open(temp_dir.path())?;
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&events);
+    store.add_event_hook(move |event| {
+        let event = match event {
+            StoreEvent::Write { key, value } => format!(
+                "write {} {}",
+                String::from_utf8_lossy(key),
+                String::from_utf8_lossy(value)
+            ),
+            StoreEvent::Remove { key } => format!("remove {}", String::from_utf8_lossy(key)),
+            StoreEvent::CompactionStarted => "compaction started".to_owned(),
+            StoreEvent::CompactionFinished { .. } => "compaction finished".to_owned(),
+            StoreEvent::CacheEviction { key } => format!("evict {}", String::from_utf8_lossy(key)),
+        };
+        seen.lock().unwrap().push(event);
+    });
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.compact()?;
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            "write key1 value1",
+            "write key2 value2",
+            "evict key1",
+            "remove key1",
+            "compaction started",
+            "compaction finished",
+        ]
+    );
+    Ok(())
+}
+
+// Errors reading the store should say which operation, key and file they happened in
+#[test]
+fn error_context() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .value_log_threshold(10)
+        .open(temp_dir.path())?;
+    store.set("large".to_owned(), "x".repeat(100))?;
+    drop(store);
+    let values = std::fs::OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("data.vlog"))?;
+    values.set_len(50)?;
+
+    let mut store = KvStore::options()
+        .value_log_threshold(10)
+        .open(temp_dir.path())?;
+    let err = store.get("large".to_owned()).unwrap_err();
+    assert!(matches!(err.root(), KvError::Corruption { offset: 0 }));
+    let message = err.to_string();
+    assert!(message.starts_with("Corrupt record at offset 0 (during get of key \"large\""));
+    assert!(message.contains("data.log"));
+    assert!(matches!(
+        store.remove("missing".to_owned()),
+        Err(KvError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+// Compaction and restores should replace files the store has open or mapped, as Windows requires
+// them to be closed first, and leave no temporary files behind
+#[test]
+fn replace_open_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().join("my store");
+    std::fs::create_dir(&dir)?;
+    let path = dir.join("values.db");
+    let mut store = KvStore::options()
+        .mmap(true)
+        .value_log_threshold(100)
+        .open(&path)?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    for i in 0..3 {
+        store.set("big".to_owned(), format!("{}", i).repeat(1000))?;
+    }
+    let mut snapshot = store.snapshot()?;
+    store.compact()?;
+    store.set("after".to_owned(), "compaction".to_owned())?;
+    assert_eq!(store.get("big".to_owned())?, Some("2".repeat(1000)));
+    assert_eq!(snapshot.get("small".to_owned())?, Some("value".to_owned()));
+
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.backup(backup_dir.path())?;
+    store.remove("small".to_owned())?;
+    store.restore(backup_dir.path())?;
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    store.compact()?;
+    drop(store);
+
+    let mut files: Vec<String> = std::fs::read_dir(&dir)?
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    files.sort();
+    assert_eq!(files, vec!["values.db", "values.vlog"]);
+    let mut store = KvStore::open(&path)?;
+    assert_eq!(
+        store.get("after".to_owned())?,
+        Some("compaction".to_owned())
+    );
+    Ok(())
+}
+
+// Stores should be flushable and syncable at points chosen by the application
+#[test]
+fn explicit_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .sync_policy(SyncPolicy::Never)
+        .value_log_threshold(10)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("large".to_owned(), "x".repeat(100))?;
+    store
+        .bucket("users")?
+        .set("key2".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+    store.sync()?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large".to_owned())?, Some("x".repeat(100)));
+    assert_eq!(
+        store.bucket("users")?.get("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    KvStore::in_memory()?.sync()?;
+    Ok(())
+}
+
+// Removals should survive compactions within the retention period and be purged after it
+#[test]
+fn tombstone_retention() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .tombstone_retention(Duration::from_secs(3600))
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.stats()?.tombstones, 1);
+    store.compact()?;
+    drop(store);
+
+    let mut store = KvStore::options()
+        .tombstone_retention(Duration::from_secs(3600))
+        .open(temp_dir.path())?;
+    assert_eq!(store.stats()?.tombstones, 1);
+    let removals: Vec<String> = store
+        .journal(..)?
+        .into_iter()
+        .filter(|entry| entry.op == JournalOp::Remove)
+        .map(|entry| entry.key)
+        .collect();
+    assert_eq!(removals, vec!["key1"]);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    assert_eq!(store.stats()?.tombstones, 0);
+    assert!(store
+        .journal(..)?
+        .iter()
+        .all(|entry| entry.op != JournalOp::Remove));
+    Ok(())
+}
+
+// Paranoid checks should refuse to return the value of a record that belongs to another key
+#[test]
+fn paranoid_checks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("data.log");
+    let mut store = KvStore::options()
+        .paranoid_checks(true)
+        .cache_capacity(0)
+        .open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key5".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(
+        store.multi_get(&["key1".to_owned(), "key5".to_owned()])?,
+        vec![Some("value1".to_owned()), None]
+    );
+
+    // Rename key3 on disk behind the back of the index
+    let log = std::fs::read(&path)?;
+    let at = log.windows(4).position(|w| w == b"key3").unwrap();
+    let mut tampered = log.clone();
+    tampered[at..at + 4].copy_from_slice(b"keyX");
+    std::fs::write(&path, tampered)?;
+    let err = store.get("key3".to_owned()).unwrap_err();
+    assert!(matches!(err.root(), KvError::Corruption { .. }));
+    assert!(store.multi_get(&["key3".to_owned()]).is_err());
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+/// Storage in memory that can be made to fail renames or to tear appends in half
+#[derive(Clone, Default)]
+struct FaultyStorage {
+    inner: MemoryStorage,
+    fail_renames: Arc<AtomicBool>,
+    tear_appends: Arc<AtomicBool>,
+}
+
+struct FaultyFile {
+    inner: Arc<dyn StorageFile>,
+    tear_appends: Arc<AtomicBool>,
+}
+
+impl FaultyStorage {
+    fn wrap(&self, inner: Arc<dyn StorageFile>) -> Arc<dyn StorageFile> {
+        Arc::new(FaultyFile {
+            inner,
+            tear_appends: self.tear_appends.clone(),
+        })
+    }
+}
+
+impl Storage for FaultyStorage {
+    fn open(&self, path: &Path, create: bool) -> io::Result<Arc<dyn StorageFile>> {
+        Ok(self.wrap(self.inner.open(path, create)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Arc<dyn StorageFile>> {
+        Ok(self.wrap(self.inner.create(path)?))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if self.fail_renames.load(Ordering::SeqCst) {
+            return Err(io::Error::other("rename failed"));
+        }
+        self.inner.rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+}
+
+impl StorageFile for FaultyFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.inner.read_at(buf, offset)
+    }
+
+    fn append(&self, data: &[u8]) -> io::Result<()> {
+        if self.tear_appends.load(Ordering::SeqCst) {
+            self.inner.append(&data[..data.len() / 2])?;
+            return Err(io::Error::other("torn write"));
+        }
+        self.inner.append(data)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.inner.sync()
+    }
+}
+
+// Stores should run on custom storage and survive failed renames and torn writes
+#[test]
+fn custom_storage() -> Result<()> {
+    let storage = FaultyStorage::default();
+    let path = Path::new("db/data.log");
+    let options = KvStore::options().storage(storage.clone());
+    let mut store = options.open(path)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    store
+        .bucket("users")?
+        .set("user1".to_owned(), "name1".to_owned())?;
+    let mut snapshot = store.snapshot()?;
+
+    storage.fail_renames.store(true, Ordering::SeqCst);
+    assert!(store.compact().is_err());
+    storage.fail_renames.store(false, Ordering::SeqCst);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key10".to_owned(), "value10".to_owned())?;
+    store.compact()?;
+    assert_eq!(snapshot.get("key0".to_owned())?, None);
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    storage.tear_appends.store(true, Ordering::SeqCst);
+    assert!(store.set("torn".to_owned(), "value".to_owned()).is_err());
+    storage.tear_appends.store(false, Ordering::SeqCst);
+    drop(store);
+
+    let mut store = options.open(path)?;
+    assert_eq!(store.len(), 10);
+    assert_eq!(store.get("torn".to_owned())?, None);
+    assert_eq!(store.get("key10".to_owned())?, Some("value10".to_owned()));
+    assert_eq!(
+        store.bucket("users")?.get("user1".to_owned())?,
+        Some("name1".to_owned())
+    );
+    Ok(())
+}
+
+// A restore that fails to replace the files should leave the store writable
+#[test]
+fn failed_restore() -> Result<()> {
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let storage = FaultyStorage::default();
+    let path = Path::new("db/data.log");
+    let options = KvStore::options().storage(storage.clone());
+    let mut store = options.open(path)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.backup(backup_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    storage.fail_renames.store(true, Ordering::SeqCst);
+    assert!(store.restore(backup_dir.path()).is_err());
+    storage.fail_renames.store(false, Ordering::SeqCst);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let mut store = options.open(path)?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Managers should create, share, list and drop named stores kept in their own directories
+#[test]
+fn store_manager() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let manager = KvStoreManager::open(temp_dir.path())?;
+    let users = manager.create("users")?;
+    manager.create("orders")?;
+    assert!(matches!(manager.create("users"), Err(KvError::StoreExists)));
+    assert!(matches!(
+        manager.get("missing"),
+        Err(KvError::StoreNotFound)
+    ));
+    assert!(matches!(
+        manager.create("../etc"),
+        Err(KvError::InvalidBucketName)
+    ));
+
+    users
+        .lock()
+        .unwrap()
+        .set("key".to_owned(), "value".to_owned())?;
+    let again = manager.get("users")?;
+    assert!(Arc::ptr_eq(&users, &again));
+    assert_eq!(
+        manager.list()?,
+        vec!["orders".to_owned(), "users".to_owned()]
+    );
+
+    assert!(matches!(
+        manager.drop_store("users"),
+        Err(KvError::StoreInUse)
+    ));
+    drop(again);
+    drop(users);
+    manager.drop_store("users")?;
+    assert_eq!(manager.list()?, vec!["orders".to_owned()]);
+    assert!(!temp_dir.path().join("users").exists());
+    drop(manager);
+
+    // A store the manager never opened should not be dropped while open elsewhere
+    let manager = KvStoreManager::open(temp_dir.path())?;
+    let orders = KvStore::open(&temp_dir.path().join("orders"))?;
+    assert!(matches!(
+        manager.drop_store("orders"),
+        Err(KvError::StoreInUse)
+    ));
+    assert!(temp_dir.path().join("orders").exists());
+    drop(orders);
+    drop(manager);
+
+    let manager = KvStoreManager::open(temp_dir.path())?;
+    let orders = manager.get_or_create("orders")?;
+    orders
+        .lock()
+        .unwrap()
+        .set("id".to_owned(), "1".to_owned())?;
+    assert!(manager.get_or_create("users")?.lock().unwrap().is_empty());
+    Ok(())
+}
+
+// Stores writing through to the disk should read, compact and reopen like any other
+#[test]
+fn direct_io() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStore::options().direct_io(true).value_log_threshold(64);
+    let mut store = options.open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), i.to_string())?;
+    }
+    store.set("large".to_owned(), "x".repeat(100))?;
+    store.set("key0".to_owned(), "new".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    drop(store);
+
+    let mut store = options.open(temp_dir.path())?;
+    assert_eq!(store.len(), 21);
+    assert_eq!(store.get("large".to_owned())?, Some("x".repeat(100)));
+    Ok(())
+}
+
+// Preallocation should reserve disk space past the end of the log without changing its length
+#[test]
+fn preallocate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStore::options().preallocate(1 << 20);
+    let mut store = options.open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    let log = temp_dir.path().join("data.log");
+    let len = std::fs::metadata(&log)?.len();
+    assert!(len < 100);
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert!(std::fs::metadata(&log)?.blocks() * 512 >= 1 << 20);
+    }
+
+    store.set("value".to_owned(), "x".repeat(1 << 20))?;
+    store.compact()?;
+    drop(store);
+    let mut store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("value".to_owned())?, Some("x".repeat(1 << 20)));
+    Ok(())
+}
+
+// Writes should be delayed and then refused while compaction cannot reclaim stale records
+#[test]
+fn write_stall() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .retained_versions(1000)
+        .write_stall(2000, 3000)
+        .open(temp_dir.path())?;
+    let mut refused = false;
+    for i in 0..100 {
+        match store.set("key".to_owned(), format!("{:0200}", i)) {
+            Ok(()) => {}
+            Err(KvError::CompactionBackpressure) => {
+                refused = true;
+                break;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    assert!(refused);
+    let stats = store.stats()?;
+    assert!(stats.write_stalls > 0);
+    assert!(stats.compactions > 0);
+    assert!(matches!(
+        store.set("other".to_owned(), "value".to_owned()),
+        Err(KvError::CompactionBackpressure)
+    ));
+
+    // Lifting the retention lets compaction reclaim the stale records
+    drop(store);
+    let mut store = KvStore::options()
+        .write_stall(2000, 3000)
+        .open(temp_dir.path())?;
+    store.compact()?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    Ok(())
+}
+
+// Appending should build on the value a key holds after a write stall compacted the log
+#[test]
+fn write_stall_append() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStore::options().write_stall(500, 1000);
+    let mut store = options.open(temp_dir.path())?;
+    store.set("other".to_owned(), format!("{:0100}", 0))?;
+    store.set("other".to_owned(), format!("{:0100}", 1))?;
+    store.set("k".to_owned(), "a".to_owned())?;
+    let mut i = 2;
+    while store.stats()?.dead_bytes < 1000 {
+        store.set("other".to_owned(), format!("{:0100}", i))?;
+        i += 1;
+    }
+    assert_eq!(store.stats()?.compactions, 0);
+    let suffix = "b".repeat(100);
+    assert_eq!(store.append("k".to_owned(), suffix.clone())?, 101);
+    assert_eq!(store.stats()?.compactions, 1);
+    assert_eq!(store.get("k".to_owned())?, Some(format!("a{}", suffix)));
+
+    drop(store);
+    let mut store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("k".to_owned())?, Some(format!("a{}", suffix)));
+    Ok(())
+}
+
+// Compaction should be paced to the rate limit
+#[test]
+fn compaction_rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .compaction_rate_limit(100_000)
+        .open(temp_dir.path())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), "x".repeat(1000))?;
+    }
+    let start = std::time::Instant::now();
+    store.compact()?;
+    assert!(start.elapsed() >= Duration::from_millis(150));
+    assert_eq!(store.get("key7".to_owned())?, Some("x".repeat(1000)));
+    Ok(())
+}
+
+// Opening should fail on a missing store or an existing one when asked to
+#[test]
+fn open_flags() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let strict = KvStore::options().create_if_missing(false);
+    assert!(matches!(
+        strict.open(temp_dir.path()),
+        Err(KvError::StoreNotFound)
+    ));
+    assert!(!temp_dir.path().join("data.log").exists());
+
+    let fresh = KvStore::options().error_if_exists(true);
+    let mut store = fresh.open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    assert!(matches!(
+        fresh.open(temp_dir.path()),
+        Err(KvError::StoreExists)
+    ));
+
+    let mut store = strict.open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Strict opens should report a damaged record instead of dropping the rest of the log
+#[test]
+fn strict_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let offset = std::fs::metadata(temp_dir.path().join("data.log"))?.len();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("data.log");
+    let mut data = std::fs::read(&log)?;
+    data[offset as usize] = 0xc1;
+    std::fs::write(&log, data)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+    match KvStore::options().strict_open(true).open(temp_dir.path()) {
+        Err(err) => assert!(
+            matches!(err.root(), KvError::Corruption { offset: at } if *at == offset),
+            "unexpected error {:?}",
+            err
+        ),
+        Ok(_) => panic!("expected corruption to be reported"),
+    }
+    Ok(())
+}
+
+// Stores should keep the record format they were created with, and still read older logs
+// written without a header
+#[test]
+fn log_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::options()
+        .log_format(LogFormat::JsonLines)
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("data.log");
+    let text = std::fs::read(&log)?;
+    let lines: Vec<_> = text[8..].split(|&b| b == b'\n').collect();
+    assert_eq!(lines.len(), 4);
+    for line in &lines[..3] {
+        serde_json::from_slice::<serde_json::Value>(line).expect("record is not JSON");
+    }
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+    assert_eq!(&std::fs::read(&log)?[..6], b"KVSL\x01j");
+
+    let legacy = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(legacy.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    let log = legacy.path().join("data.log");
+    let data = std::fs::read(&log)?;
+    std::fs::write(&log, &data[8..])?;
+    let mut store = KvStore::options()
+        .log_format(LogFormat::JsonLines)
+        .open(legacy.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    store.set("other".to_owned(), "value".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(legacy.path())?;
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// `kvs migrate` should upgrade logs written before the header, and unknown versions should be
+// refused
+#[test]
+fn migrate_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    drop(store);
+    let log = temp_dir.path().join("data.log");
+    let data = std::fs::read(&log)?;
+    std::fs::write(&log, &data[8..])?;
+    assert_eq!(KvStore::open(temp_dir.path())?.log_version()?, 0);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--format", "jsonl"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(
+            "migrated from version 0 (msgpack) to version 1 (jsonl)\n",
+        ));
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.log_version()?, 1);
+    assert_eq!(store.log_format(), LogFormat::JsonLines);
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    drop(store);
+
+    let mut data = std::fs::read(&log)?;
+    data[4] = 9;
+    std::fs::write(&log, data)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvError::UnsupportedLogVersion(9))
+    ));
+    Ok(())
+}